@@ -0,0 +1,170 @@
+use ::aml::{AmlContext, DebugVerbosity, Handler};
+use alloc::boxed::Box;
+use common::{
+    kprintln,
+    util::{in16, in32, in8, out16, out32, out8},
+};
+
+use super::{get_dsdt, get_xsdt, phys_to_virt, Signature};
+use crate::{
+    drivers::{pci, pit},
+    time,
+};
+
+/// The namespace from the DSDT and SSDTs, None until `init`
+pub static mut GLOBAL_AML: Option<AmlContext> = None;
+
+/// What the interpreter uses to reach memory, ports and PCI configuration space
+struct KernelHandler;
+
+impl KernelHandler {
+    fn read<T>(address: usize) -> T {
+        unsafe { core::ptr::read_volatile(phys_to_virt::<T>(address as u64)) }
+    }
+
+    fn write<T>(address: usize, value: T) {
+        unsafe { core::ptr::write_volatile(phys_to_virt::<T>(address as u64) as *mut T, value) }
+    }
+}
+
+impl Handler for KernelHandler {
+    fn read_u8(&self, address: usize) -> u8 {
+        Self::read(address)
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        Self::read(address)
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        Self::read(address)
+    }
+
+    fn read_u64(&self, address: usize) -> u64 {
+        Self::read(address)
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) {
+        Self::write(address, value)
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) {
+        Self::write(address, value)
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) {
+        Self::write(address, value)
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) {
+        Self::write(address, value)
+    }
+
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { in8(port) }
+    }
+
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { in16(port) }
+    }
+
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { in32(port) }
+    }
+
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { out8(port, value) }
+    }
+
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { out16(port, value) }
+    }
+
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { out32(port, value) }
+    }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        pci::get_pci().read_u8(segment, bus, device, function, offset)
+    }
+
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        pci::get_pci().read_u16(segment, bus, device, function, offset)
+    }
+
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        pci::get_pci().read_u32(segment, bus, device, function, offset)
+    }
+
+    fn write_pci_u8(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u8,
+    ) {
+        pci::get_pci_mut().write_u8(segment, bus, device, function, offset, value)
+    }
+
+    fn write_pci_u16(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u16,
+    ) {
+        pci::get_pci_mut().write_u16(segment, bus, device, function, offset, value)
+    }
+
+    fn write_pci_u32(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
+    ) {
+        pci::get_pci_mut().write_u32(segment, bus, device, function, offset, value)
+    }
+
+    // Stall is at most 100us, the PIT can't wait less than a millisecond
+    fn stall(&self, _microseconds: u64) {
+        pit::wait_ms(1);
+    }
+
+    fn sleep(&self, milliseconds: u64) {
+        time::sleep_ms(milliseconds);
+    }
+}
+
+/// Loads the DSDT and every SSDT into the namespace. Needs `pci::init` first since AML can touch
+/// configuration space. Tables that fail to parse are skipped.
+pub fn init() {
+    let mut context = AmlContext::new(Box::new(KernelHandler), DebugVerbosity::None);
+
+    let ssdts = get_xsdt()
+        .iter()
+        .filter(|table| table.signature == Signature::SSDT.as_bytes());
+    for table in get_dsdt().into_iter().chain(ssdts) {
+        if let Err(e) = context.parse_table(table.data()) {
+            kprintln!(
+                "Unable to parse {:?}: {:?}",
+                core::str::from_utf8(&table.signature),
+                e
+            );
+        }
+    }
+
+    if let Err(e) = context.initialize_objects() {
+        kprintln!("Unable to initialize AML objects: {:?}", e);
+    }
+
+    unsafe {
+        GLOBAL_AML = Some(context);
+    }
+}
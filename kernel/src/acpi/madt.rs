@@ -0,0 +1,163 @@
+use alloc::vec::Vec;
+
+use super::SdtHeader;
+
+// Interrupt controller structure types
+const PROCESSOR_LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+
+// Processor local APIC flags, a cpu with neither set can't be used
+const PROCESSOR_ENABLED: u32 = 1;
+const ONLINE_CAPABLE: u32 = 1 << 1;
+
+// Interrupt source override flags, 0 in either field means the bus default (active high and edge
+// triggered for isa)
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b11 << 2;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+const ISA_BUS: u8 = 0;
+
+/// Multiple APIC Description Table, the interrupt controller structures follow the fixed fields
+#[repr(C, packed)]
+pub struct MADT {
+    pub header: SdtHeader,
+    pub local_apic_address: u32,
+    pub flags: u32,
+}
+
+/// One interrupt controller structure. Types that aren't parsed yet only have their type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Entry {
+    ProcessorLocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        io_apic_id: u8,
+        io_apic_address: u32,
+        global_system_interrupt_base: u32,
+    },
+    /// An isa irq wired to a different global system interrupt than its number
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        global_system_interrupt: u32,
+        flags: u16,
+    },
+    Other(u8),
+}
+
+/// Where an isa irq arrives and how it's signalled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IsaRoute {
+    pub global_system_interrupt: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+impl MADT {
+    pub fn local_apic_address(&self) -> u64 {
+        self.local_apic_address as u64
+    }
+
+    pub fn iter(&self) -> EntryIter<'_> {
+        // The local APIC address and flags come before the entries
+        EntryIter {
+            data: &self.header.data()[8..],
+        }
+    }
+
+    /// APIC ids of the cpus that are enabled or can be brought online
+    pub fn apic_ids(&self) -> Vec<u8> {
+        self.iter()
+            .filter_map(|entry| match entry {
+                Entry::ProcessorLocalApic { apic_id, flags, .. }
+                    if flags & (PROCESSOR_ENABLED | ONLINE_CAPABLE) != 0 =>
+                {
+                    Some(apic_id)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Where isa `irq` is wired, from its interrupt source override if it has one. Without one the
+    /// irq is identity mapped, active high and edge triggered.
+    pub fn isa_route(&self, irq: u8) -> IsaRoute {
+        self.iter()
+            .find_map(|entry| match entry {
+                Entry::InterruptSourceOverride {
+                    bus: ISA_BUS,
+                    source,
+                    global_system_interrupt,
+                    flags,
+                } if source == irq => Some(IsaRoute {
+                    global_system_interrupt,
+                    active_low: flags & POLARITY_MASK == POLARITY_ACTIVE_LOW,
+                    level_triggered: flags & TRIGGER_MASK == TRIGGER_LEVEL,
+                }),
+                _ => None,
+            })
+            .unwrap_or(IsaRoute {
+                global_system_interrupt: irq as u32,
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+
+    /// Base of the first I/O APIC's registers
+    pub fn io_apic_address(&self) -> Option<u64> {
+        self.iter().find_map(|entry| match entry {
+            Entry::IoApic {
+                io_apic_address, ..
+            } => Some(io_apic_address as u64),
+            _ => None,
+        })
+    }
+}
+
+/// Walks the variable length entries, stops at the first one whose length doesn't fit
+pub struct EntryIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for EntryIter<'a> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let entry_type = *self.data.get(0)?;
+        let length = *self.data.get(1)? as usize;
+        if length < 2 || length > self.data.len() {
+            return None;
+        }
+        let entry = &self.data[..length];
+        self.data = &self.data[length..];
+
+        let u16_at = |offset: usize| u16::from_le_bytes([entry[offset], entry[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(entry[offset..offset + 4].try_into().unwrap());
+
+        Some(match entry_type {
+            PROCESSOR_LOCAL_APIC if length >= 8 => Entry::ProcessorLocalApic {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                flags: u32_at(4),
+            },
+            IO_APIC if length >= 12 => Entry::IoApic {
+                io_apic_id: entry[2],
+                io_apic_address: u32_at(4),
+                global_system_interrupt_base: u32_at(8),
+            },
+            INTERRUPT_SOURCE_OVERRIDE if length >= 10 => Entry::InterruptSourceOverride {
+                bus: entry[2],
+                source: entry[3],
+                global_system_interrupt: u32_at(4),
+                flags: u16_at(8),
+            },
+            _ => Entry::Other(entry_type),
+        })
+    }
+}
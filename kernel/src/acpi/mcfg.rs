@@ -0,0 +1,43 @@
+use core::{marker::PhantomData, ops::Index};
+
+use super::SdtHeader;
+
+/// PCI Express memory mapped configuration table, one entry per segment group follows the
+/// reserved bytes
+#[repr(C, packed)]
+pub struct MCFG<'a> {
+    pub header: SdtHeader,
+    reserved: u64,
+    _entries: PhantomData<&'a Entry>,
+}
+
+/// Where the configuration space of a segment group's buses is mapped
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub address: u64,
+    pub segment: u16,
+    pub bus_start: u8,
+    pub bus_end: u8,
+    reserved: u32,
+}
+
+impl<'a> MCFG<'a> {
+    pub fn entries(&self) -> &'a [Entry] {
+        let data = &self.header.data()[8..];
+        let count = data.len() / core::mem::size_of::<Entry>();
+        unsafe { core::slice::from_raw_parts(data.as_ptr() as *const Entry, count) }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a Entry> {
+        self.entries().iter()
+    }
+}
+
+impl<'a> Index<u16> for MCFG<'a> {
+    type Output = Entry;
+
+    fn index(&self, segment: u16) -> &Entry {
+        &self.entries()[segment as usize]
+    }
+}
@@ -0,0 +1,395 @@
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::vec::Vec;
+use common::{
+    efi::{self, MemoryMap, MemoryType},
+    kassert, kprintln,
+    mem::{self, PageTableFrameAllocator},
+    util::{in32, out8},
+    x86_64::{structures::paging::PhysFrame, PhysAddr},
+};
+
+pub use common::efi::Rsdp;
+
+pub mod aml;
+pub mod madt;
+pub mod mcfg;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signature {
+    MADT,
+    FADT,
+    MCFG,
+    HPET,
+    DSDT,
+    SSDT,
+    XSDT,
+}
+
+impl Signature {
+    pub const fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            Signature::MADT => b"APIC",
+            Signature::FADT => b"FACP",
+            Signature::MCFG => b"MCFG",
+            Signature::HPET => b"HPET",
+            Signature::DSDT => b"DSDT",
+            Signature::SSDT => b"SSDT",
+            Signature::XSDT => b"XSDT",
+        }
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// The whole table as `T` (which includes the header)
+    pub fn get_entry<T>(&self) -> &'static T {
+        unsafe { &*(self as *const SdtHeader as *const T) }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.bytes()[core::mem::size_of::<SdtHeader>()..]
+    }
+}
+
+/// The tables from the XSDT (or the RSDT on ACPI 1.0 firmware), copied into kernel memory along
+/// with where the firmware had them
+pub struct Xsdt {
+    tables: Vec<(PhysAddr, &'static SdtHeader)>,
+}
+
+impl Xsdt {
+    /// Walks the root table at `root`, `entry_size` is 8 for an XSDT and 4 for an RSDT. Tables
+    /// with a bad checksum are skipped, None if the root table itself is bad.
+    pub unsafe fn parse(root: PhysAddr, entry_size: usize) -> Option<Xsdt> {
+        let root = copy_table(root.as_u64())?;
+
+        let mut tables = Vec::new();
+        for entry in root.data().chunks_exact(entry_size) {
+            let phys = match entry_size {
+                8 => u64::from_le_bytes(entry.try_into().unwrap()),
+                _ => u32::from_le_bytes(entry.try_into().unwrap()) as u64,
+            };
+            if let Some(table) = copy_table(phys) {
+                tables.push((PhysAddr::new(phys), table));
+            }
+        }
+        Some(Xsdt { tables })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'static SdtHeader> + '_ {
+        self.tables.iter().map(|(_, table)| *table)
+    }
+
+    pub fn find(&self, signature: Signature) -> Option<&'static SdtHeader> {
+        self.iter().find(|t| t.signature == signature.as_bytes())
+    }
+
+    /// Where the firmware put the table, for anything that has to be read in place
+    pub fn address(&self, signature: Signature) -> Option<PhysAddr> {
+        self.tables
+            .iter()
+            .find(|(_, table)| table.signature == signature.as_bytes())
+            .map(|(phys, _)| *phys)
+    }
+}
+
+static mut RSDP_COPY: Option<Rsdp> = None;
+static mut XSDT: Option<Xsdt> = None;
+static mut DSDT: Option<&'static SdtHeader> = None;
+
+static PARSED: AtomicBool = AtomicBool::new(false);
+static RECLAIMED: AtomicBool = AtomicBool::new(false);
+
+// Offset of the DSDT addresses in the FADT
+const FADT_DSDT: usize = 40;
+const FADT_X_DSDT: usize = 140;
+
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REG: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+const RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+const FADT_PM_TMR_BLK: usize = 76;
+const FADT_X_PM_TMR_BLK: usize = 208;
+// Set when the PM timer counts 32 bits instead of 24
+const TMR_VAL_EXT: u32 = 1 << 8;
+
+// Generic address structure address spaces
+const SYSTEM_MEMORY: u8 = 0;
+const SYSTEM_IO: u8 = 1;
+
+fn phys_to_virt<T>(phys: u64) -> *const T {
+    mem::phys_to_virt(PhysAddr::new(phys)).as_ptr()
+}
+
+fn checksum(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Copies the table at `phys` onto the heap so it outlives the firmware's copy
+unsafe fn copy_table(phys: u64) -> Option<&'static SdtHeader> {
+    let header = &*phys_to_virt::<SdtHeader>(phys);
+    let length = header.length as usize;
+    if length < core::mem::size_of::<SdtHeader>() {
+        return None;
+    }
+
+    let original = core::slice::from_raw_parts(phys_to_virt::<u8>(phys), length);
+    if !checksum(original) {
+        kprintln!(
+            "ACPI table {:?} has a bad checksum!",
+            core::str::from_utf8(&original[..4])
+        );
+        return None;
+    }
+
+    let layout = Layout::from_size_align(length, 8).ok()?;
+    let copy = alloc::alloc::alloc(layout);
+    if copy.is_null() {
+        return None;
+    }
+    common::util::memcpy(copy, original.as_ptr(), length);
+    Some(&*(copy as *const SdtHeader))
+}
+
+pub fn init(memory_map: MemoryMap<'_>) {
+    let ptr = efi::find_rsdp().expect("Unable to find RSDP!");
+
+    let rsdp = unsafe { Rsdp::read(phys_to_virt(ptr as u64)) };
+    kassert!(
+        &rsdp.signature == Rsdp::SIGNATURE,
+        "RSDP has a bad signature!"
+    );
+    kassert!(rsdp.validate_checksum(), "RSDP has a bad checksum!");
+
+    // The XSDT holds 8 byte pointers, the RSDT 4 byte ones
+    let (root, entry_size) = if rsdp.has_xsdt() {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (rsdp.rsdt_address as u64, 4)
+    };
+    let xsdt = unsafe { Xsdt::parse(PhysAddr::new(root), entry_size) }
+        .expect("Unable to copy root ACPI table!");
+
+    // The DSDT is only referenced from the FADT
+    let dsdt = xsdt.find(Signature::FADT).and_then(|fadt| {
+        let bytes = fadt.bytes();
+        let x_dsdt = bytes
+            .get(FADT_X_DSDT..FADT_X_DSDT + 8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .unwrap_or(0);
+        let dsdt = bytes
+            .get(FADT_DSDT..FADT_DSDT + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as u64)
+            .unwrap_or(0);
+        let phys = if x_dsdt != 0 { x_dsdt } else { dsdt };
+        unsafe { copy_table(phys) }
+    });
+
+    let reclaimable: usize = memory_map
+        .iter()
+        .filter(|d| d.memory_type == MemoryType::ACPIReclaim)
+        .map(|d| d.size * 4096)
+        .sum();
+    kprintln!(
+        "ACPI: {} tables, {:x} bytes reclaimable",
+        xsdt.tables.len(),
+        reclaimable
+    );
+
+    unsafe {
+        RSDP_COPY = Some(rsdp);
+        XSDT = Some(xsdt);
+        DSDT = dsdt;
+    }
+}
+
+pub fn get_rsdp() -> &'static Rsdp {
+    unsafe { RSDP_COPY.as_ref().expect("ACPI isn't initialized!") }
+}
+
+pub fn get_xsdt() -> &'static Xsdt {
+    unsafe { XSDT.as_ref().expect("ACPI isn't initialized!") }
+}
+
+pub fn get_dsdt() -> Option<&'static SdtHeader> {
+    unsafe { DSDT }
+}
+
+/// Looks up a table by signature. The DSDT isn't listed in the root table so it's handled here.
+pub fn find_table(signature: Signature) -> Option<&'static SdtHeader> {
+    match signature {
+        Signature::DSDT => get_dsdt(),
+        _ => unsafe { XSDT.as_ref() }?.find(signature),
+    }
+}
+
+/// Called once nothing will read ACPI tables from firmware memory anymore (AML included)
+pub fn parsing_complete() {
+    PARSED.store(true, Ordering::SeqCst);
+}
+
+/// Hands the ACPIReclaim regions to the frame allocator. Every table the kernel uses has been
+/// copied by `init` so the originals aren't needed, but the AML interpreter may still read them
+/// until `parsing_complete` is called.
+pub fn reclaim(memory_map: MemoryMap<'_>, frame_allocator: &mut PageTableFrameAllocator) -> usize {
+    kassert!(
+        PARSED.load(Ordering::SeqCst),
+        "ACPI memory reclaimed before parsing is complete!"
+    );
+    if RECLAIMED.swap(true, Ordering::SeqCst) {
+        return 0;
+    }
+
+    let mut count = 0;
+    for desc in memory_map
+        .iter()
+        .filter(|d| d.memory_type == MemoryType::ACPIReclaim)
+    {
+        for i in 0..desc.size {
+            let addr = PhysAddr::new((desc.physical_address + i * 4096) as u64);
+            frame_allocator.free_frame(PhysFrame::containing_address(addr));
+            count += 1;
+        }
+    }
+
+    kprintln!("ACPI: reclaimed {} frames", count);
+    count
+}
+
+/// The ACPI power management timer, a free running counter at a fixed frequency. Every machine
+/// with a FADT has one, so it's there when the PIT and HPET aren't.
+#[derive(Debug, Clone, Copy)]
+pub struct PmTimer {
+    address: u64,
+    space: u8,
+    mask: u32,
+}
+
+impl PmTimer {
+    pub const FREQUENCY: u64 = 3_579_545;
+
+    pub fn read(&self) -> u32 {
+        let value = match self.space {
+            SYSTEM_MEMORY => unsafe { core::ptr::read_volatile(phys_to_virt::<u32>(self.address)) },
+            _ => unsafe { in32(self.address as u16) },
+        };
+        value & self.mask
+    }
+
+    /// Width of the counter, 24 or 32
+    pub fn bits(&self) -> u32 {
+        self.mask.count_ones()
+    }
+
+    /// Ticks from `start` to `end`, correct across one wrap of the counter
+    pub fn ticks_between(&self, start: u32, end: u32) -> u32 {
+        end.wrapping_sub(start) & self.mask
+    }
+}
+
+/// Finds the PM timer in the FADT, preferring the extended address when it's set
+pub fn pm_timer() -> Option<PmTimer> {
+    let bytes = find_table(Signature::FADT)?.bytes();
+    let flags = bytes
+        .get(FADT_FLAGS..FADT_FLAGS + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .unwrap_or(0);
+    let mask = if flags & TMR_VAL_EXT != 0 {
+        u32::MAX
+    } else {
+        0x00FF_FFFF
+    };
+
+    let extended = bytes
+        .get(FADT_X_PM_TMR_BLK..FADT_X_PM_TMR_BLK + 12)
+        .map(|gas| {
+            let space = gas[0];
+            let address = u64::from_le_bytes(gas[4..12].try_into().unwrap());
+            (space, address)
+        })
+        .filter(|(space, address)| {
+            *address != 0 && (*space == SYSTEM_IO || *space == SYSTEM_MEMORY)
+        });
+
+    let (space, address) = match extended {
+        Some(extended) => extended,
+        None => {
+            let port = bytes
+                .get(FADT_PM_TMR_BLK..FADT_PM_TMR_BLK + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .unwrap_or(0);
+            if port == 0 {
+                return None;
+            }
+            (SYSTEM_IO, port as u64)
+        }
+    };
+
+    Some(PmTimer {
+        address,
+        space,
+        mask,
+    })
+}
+
+/// Resets the machine through the FADT reset register, falling back to pulsing the reset line
+/// through the keyboard controller
+pub fn reset() -> ! {
+    let fadt = find_table(Signature::FADT);
+
+    if let Some(fadt) = fadt {
+        let bytes = fadt.bytes();
+        let flags = bytes
+            .get(FADT_FLAGS..FADT_FLAGS + 4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .unwrap_or(0);
+
+        if flags & RESET_REG_SUPPORTED != 0 && bytes.len() > FADT_RESET_VALUE {
+            let space = bytes[FADT_RESET_REG];
+            let address = u64::from_le_bytes(
+                bytes[FADT_RESET_REG + 4..FADT_RESET_REG + 12]
+                    .try_into()
+                    .unwrap(),
+            );
+            let value = bytes[FADT_RESET_VALUE];
+
+            match space {
+                SYSTEM_IO => unsafe { out8(address as u16, value) },
+                SYSTEM_MEMORY => unsafe {
+                    core::ptr::write_volatile(phys_to_virt::<u8>(address) as *mut u8, value)
+                },
+                _ => (),
+            }
+        }
+    }
+
+    unsafe {
+        out8(0x64, 0xFE);
+    }
+
+    loop {
+        unsafe { core::arch::asm!("cli; hlt") }
+    }
+}
@@ -0,0 +1,236 @@
+use alloc::{collections::VecDeque, vec::Vec};
+use common::x86_64::instructions::interrupts;
+
+use crate::lock::OrderedMutex;
+
+pub const DEFAULT_SCROLLBACK: usize = 500;
+pub const DEFAULT_ATTRIBUTE: u8 = 0x07; // Light grey on black
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cell {
+    pub character: u8,
+    pub attribute: u8,
+}
+
+pub struct Scrollback {
+    lines: VecDeque<Vec<Cell>>,
+    depth: usize,
+    // Number of lines the view is scrolled back from the bottom
+    view: usize,
+}
+
+impl Scrollback {
+    pub const fn new(depth: usize) -> Scrollback {
+        Scrollback {
+            lines: VecDeque::new(),
+            depth,
+            view: 0,
+        }
+    }
+
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth.max(1);
+        while self.lines.len() > self.depth {
+            self.lines.pop_front();
+        }
+        self.view = self.view.min(self.lines.len().saturating_sub(1));
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn push(&mut self, character: u8, attribute: u8) {
+        if self.lines.is_empty() {
+            self.lines.push_back(Vec::new());
+        }
+
+        match character {
+            b'\n' => self.new_line(),
+            b'\r' => (),
+            _ => self
+                .lines
+                .back_mut()
+                .unwrap()
+                .push(Cell { character, attribute }),
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.lines.push_back(Vec::new());
+        if self.lines.len() > self.depth {
+            self.lines.pop_front();
+        }
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.view = (self.view + lines).min(self.lines.len().saturating_sub(1));
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.view = self.view.saturating_sub(lines);
+    }
+
+    pub fn is_scrolled(&self) -> bool {
+        self.view > 0
+    }
+
+    /// Lines that should be on screen for a console `rows` lines tall
+    pub fn visible(&self, rows: usize) -> impl Iterator<Item = &Vec<Cell>> {
+        let end = self.lines.len() - self.view.min(self.lines.len());
+        let start = end.saturating_sub(rows);
+        self.lines.range(start..end)
+    }
+}
+
+// ANSI colour order to the vga attribute colour order
+const ANSI_COLORS: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+const MAX_PARAMS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq)]
+enum AnsiState {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// Keeps track of the attribute selected by SGR (ESC [ ... m) sequences in the output. Other
+/// control sequences are swallowed.
+struct AnsiWriter {
+    attribute: u8,
+    state: AnsiState,
+    params: [u8; MAX_PARAMS],
+    param_count: usize,
+}
+
+impl AnsiWriter {
+    const fn new() -> AnsiWriter {
+        AnsiWriter {
+            attribute: DEFAULT_ATTRIBUTE,
+            state: AnsiState::Ground,
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+        }
+    }
+
+    // Returns the byte if it should be printed
+    fn feed(&mut self, byte: u8) -> Option<u8> {
+        match self.state {
+            AnsiState::Ground if byte == 0x1B => {
+                self.state = AnsiState::Escape;
+                None
+            }
+            AnsiState::Ground => Some(byte),
+            AnsiState::Escape if byte == b'[' => {
+                self.state = AnsiState::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.param_count = 0;
+                None
+            }
+            AnsiState::Escape => {
+                self.state = AnsiState::Ground;
+                None
+            }
+            AnsiState::Csi => {
+                match byte {
+                    b'0'..=b'9' => {
+                        if self.param_count == 0 {
+                            self.param_count = 1;
+                        }
+                        let param = &mut self.params[self.param_count - 1];
+                        *param = param.saturating_mul(10).saturating_add(byte - b'0');
+                    }
+                    b';' => {
+                        if self.param_count < MAX_PARAMS {
+                            self.param_count = self.param_count.max(1) + 1;
+                        }
+                    }
+                    b'm' => {
+                        self.select_graphics();
+                        self.state = AnsiState::Ground;
+                    }
+                    _ => self.state = AnsiState::Ground,
+                }
+                None
+            }
+        }
+    }
+
+    fn select_graphics(&mut self) {
+        // ESC [ m is the same as ESC [ 0 m
+        let count = self.param_count.max(1);
+        for &param in &self.params[..count] {
+            let fg = self.attribute & 0x0F;
+            let bg = self.attribute & 0xF0;
+            self.attribute = match param {
+                0 => DEFAULT_ATTRIBUTE,
+                1 => self.attribute | 0x08,
+                22 => self.attribute & !0x08,
+                30..=37 => bg | (fg & 0x08) | ANSI_COLORS[(param - 30) as usize],
+                39 => bg | (DEFAULT_ATTRIBUTE & 0x0F),
+                40..=47 => fg | ANSI_COLORS[(param - 40) as usize] << 4,
+                49 => fg | (DEFAULT_ATTRIBUTE & 0xF0),
+                90..=97 => bg | 0x08 | ANSI_COLORS[(param - 90) as usize],
+                100..=107 => fg | (0x08 | ANSI_COLORS[(param - 100) as usize]) << 4,
+                _ => self.attribute,
+            };
+        }
+    }
+}
+
+// write_ansi holds the ansi state while pushing to the scrollback
+static ANSI: OrderedMutex<AnsiWriter> = OrderedMutex::new("console ansi", 10, AnsiWriter::new());
+static SCROLLBACK: OrderedMutex<Scrollback> = OrderedMutex::new(
+    "console scrollback",
+    11,
+    Scrollback::new(DEFAULT_SCROLLBACK),
+);
+
+// Redraws the screen from the scrollback, registered by whichever console is drawing
+static mut RENDERER: Option<fn(&Scrollback)> = None;
+
+pub fn register_renderer(renderer: fn(&Scrollback)) {
+    unsafe {
+        RENDERER.replace(renderer);
+    }
+}
+
+pub fn set_scrollback(depth: usize) {
+    SCROLLBACK.lock().set_depth(depth);
+}
+
+/// Adds `bytes` to the scrollback and hands each printed byte to `draw` with its attribute while
+/// the view is at the bottom, so the console can draw new text without redrawing everything. ANSI
+/// colour escapes set the attribute and aren't printed. Interrupts are off throughout since irq
+/// handlers print too.
+pub fn write_ansi(bytes: &[u8], mut draw: impl FnMut(u8, u8)) {
+    interrupts::without_interrupts(|| {
+        let mut ansi = ANSI.lock();
+        let mut scrollback = SCROLLBACK.lock();
+        let in_view = !scrollback.is_scrolled();
+        for &b in bytes {
+            if let Some(b) = ansi.feed(b) {
+                scrollback.push(b, ansi.attribute);
+                if in_view {
+                    draw(b, ansi.attribute);
+                }
+            }
+        }
+    });
+}
+
+pub fn scroll_up(lines: usize) {
+    interrupts::without_interrupts(|| SCROLLBACK.lock().scroll_up(lines));
+    redraw();
+}
+
+pub fn scroll_down(lines: usize) {
+    interrupts::without_interrupts(|| SCROLLBACK.lock().scroll_down(lines));
+    redraw();
+}
+
+pub fn redraw() {
+    if let Some(renderer) = unsafe { RENDERER } {
+        interrupts::without_interrupts(|| renderer(&SCROLLBACK.lock()));
+    }
+}
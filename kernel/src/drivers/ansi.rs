@@ -0,0 +1,140 @@
+use super::keyboard::{Key, KeyEvent};
+
+const ESC: u8 = 0x1B;
+const MAX_PARAMS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Ground,
+    Escape,
+    // ESC [
+    Csi,
+    // ESC O
+    Ss3,
+}
+
+/// Turns bytes from a terminal into key events. Control sequences are only complete once their
+/// final byte arrives so `feed` returns `None` while in the middle of one.
+pub struct AnsiParser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+}
+
+impl AnsiParser {
+    pub const fn new() -> AnsiParser {
+        AnsiParser {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        let key = match self.state {
+            State::Ground => match byte {
+                ESC => {
+                    self.state = State::Escape;
+                    None
+                }
+                b'\r' => Some(Key::Char('\n')),
+                0x7F => Some(Key::Char('\x08')),
+                _ => Some(Key::Char(byte as char)),
+            },
+            State::Escape => match byte {
+                b'[' => {
+                    self.state = State::Csi;
+                    self.params = [0; MAX_PARAMS];
+                    self.param_count = 0;
+                    None
+                }
+                b'O' => {
+                    self.state = State::Ss3;
+                    None
+                }
+                ESC => Some(Key::Escape),
+                // Alt+key is sent as ESC key, there are no modifiers yet so just the key
+                _ => {
+                    self.state = State::Ground;
+                    Some(Key::Char(byte as char))
+                }
+            },
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    if self.param_count == 0 {
+                        self.param_count = 1;
+                    }
+                    let param = &mut self.params[self.param_count - 1];
+                    *param = param.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                    None
+                }
+                b';' => {
+                    if self.param_count == 0 {
+                        self.param_count = 1;
+                    }
+                    if self.param_count < MAX_PARAMS {
+                        self.param_count += 1;
+                    }
+                    None
+                }
+                _ => {
+                    self.state = State::Ground;
+                    self.csi(byte)
+                }
+            },
+            State::Ss3 => {
+                self.state = State::Ground;
+                match byte {
+                    b'P' => Some(Key::Function(1)),
+                    b'Q' => Some(Key::Function(2)),
+                    b'R' => Some(Key::Function(3)),
+                    b'S' => Some(Key::Function(4)),
+                    _ => AnsiParser::cursor_key(byte),
+                }
+            }
+        };
+
+        key.map(KeyEvent::Pressed)
+    }
+
+    /// A lone ESC can't be told apart from the start of a sequence until more bytes arrive,
+    /// call this once the line has gone quiet to get it out of the parser.
+    pub fn flush(&mut self) -> Option<KeyEvent> {
+        if self.state == State::Escape {
+            self.state = State::Ground;
+            Some(KeyEvent::Pressed(Key::Escape))
+        } else {
+            None
+        }
+    }
+
+    fn csi(&self, byte: u8) -> Option<Key> {
+        match byte {
+            b'~' => match self.params[0] {
+                1 | 7 => Some(Key::Home),
+                2 => Some(Key::Insert),
+                3 => Some(Key::Delete),
+                4 | 8 => Some(Key::End),
+                5 => Some(Key::PageUp),
+                6 => Some(Key::PageDown),
+                11..=15 => Some(Key::Function((self.params[0] - 10) as u8)),
+                17..=21 => Some(Key::Function((self.params[0] - 11) as u8)),
+                23 | 24 => Some(Key::Function((self.params[0] - 12) as u8)),
+                _ => None,
+            },
+            _ => AnsiParser::cursor_key(byte),
+        }
+    }
+
+    fn cursor_key(byte: u8) -> Option<Key> {
+        match byte {
+            b'A' => Some(Key::Up),
+            b'B' => Some(Key::Down),
+            b'C' => Some(Key::Right),
+            b'D' => Some(Key::Left),
+            b'H' => Some(Key::Home),
+            b'F' => Some(Key::End),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use core::fmt;
+
+use common::{
+    framebuffer::{Framebuffer, PixelFormat},
+    output,
+    x86_64::instructions::interrupts,
+};
+use spin::Mutex;
+
+use crate::console::{self, Scrollback, DEFAULT_ATTRIBUTE};
+
+pub const GLYPH_WIDTH: usize = 8;
+pub const GLYPH_HEIGHT: usize = 16;
+
+// Grey on black, the channels are equal so it's the same in RGB and BGR
+const FOREGROUND: u32 = 0x00AA_AAAA;
+const BACKGROUND: u32 = 0;
+
+// Attribute colours in vga order as 0xRRGGBB
+const PALETTE: [u32; 16] = [
+    0x000000, 0x0000AA, 0x00AA00, 0x00AAAA, 0xAA0000, 0xAA00AA, 0xAA5500, 0xAAAAAA, 0x555555,
+    0x5555FF, 0x55FF55, 0x55FFFF, 0xFF5555, 0xFF55FF, 0xFFFF55, 0xFFFFFF,
+];
+
+// FONT starts at the space, everything below it is a control character
+const FIRST_GLYPH: u8 = b' ';
+// Drawn for bytes the font has no glyph for
+const REPLACEMENT: u8 = b'?';
+
+// Locked with interrupts off since irq handlers print too. Set by `init`.
+static CONSOLE: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+
+/// Text drawn straight into a framebuffer a glyph at a time. Lines wrap at the right edge and the
+/// whole screen scrolls up a line once the cursor moves past the bottom row.
+pub struct FramebufferConsole {
+    framebuffer: Framebuffer,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    foreground: u32,
+    background: u32,
+}
+
+// The framebuffer pointer is only used with the console's lock held
+unsafe impl Send for FramebufferConsole {}
+
+impl FramebufferConsole {
+    pub fn new(framebuffer: Framebuffer) -> FramebufferConsole {
+        FramebufferConsole {
+            columns: framebuffer.width() / GLYPH_WIDTH,
+            rows: framebuffer.height() / GLYPH_HEIGHT,
+            framebuffer,
+            column: 0,
+            row: 0,
+            foreground: FOREGROUND,
+            background: BACKGROUND,
+        }
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Column and row the next glyph goes at
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.column, self.row)
+    }
+
+    /// Colours for glyphs drawn from now on, in the framebuffer's pixel format
+    pub fn set_colors(&mut self, foreground: u32, background: u32) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    /// Colours for glyphs drawn from now on from a vga attribute, foreground in the low nibble
+    pub fn set_attribute(&mut self, attribute: u8) {
+        let foreground = self.color(attribute & 0x0F);
+        let background = self.color(attribute >> 4);
+        self.set_colors(foreground, background);
+    }
+
+    // Palette entry in the framebuffer's pixel format, RGB keeps red in the low byte
+    fn color(&self, index: u8) -> u32 {
+        let color = PALETTE[index as usize & 0x0F];
+        match self.framebuffer.format() {
+            PixelFormat::Rgb => {
+                (color & 0x00FF00) | ((color >> 16) & 0xFF) | ((color & 0xFF) << 16)
+            }
+            _ => color,
+        }
+    }
+
+    /// Fills the screen with the background and moves the cursor to the top left
+    pub fn clear(&mut self) {
+        self.framebuffer.fill(self.background);
+        self.column = 0;
+        self.row = 0;
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        if self.columns == 0 || self.rows == 0 {
+            return;
+        }
+
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.column = 0,
+            _ => {
+                // Wrapping waits for the next glyph, so a newline right after a full line doesn't
+                // leave an empty one
+                if self.column == self.columns {
+                    self.new_line();
+                }
+                self.draw_glyph(byte, self.column, self.row);
+                self.column += 1;
+            }
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.write_byte(b);
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    // Moves every text row up one and clears the bottom one. Pixels below the last full row are
+    // never drawn to so they're left alone.
+    fn scroll(&mut self) {
+        let line = GLYPH_HEIGHT * self.framebuffer.stride();
+        let end = self.rows * line;
+        let background = self.background;
+
+        let buffer = self.framebuffer.buffer_mut();
+        buffer.copy_within(line..end, 0);
+        buffer[end - line..end].fill(background);
+    }
+
+    fn draw_glyph(&mut self, byte: u8, column: usize, row: usize) {
+        let (foreground, background) = (self.foreground, self.background);
+        let stride = self.framebuffer.stride();
+        let buffer = self.framebuffer.buffer_mut();
+
+        for (y, bits) in glyph(byte).iter().enumerate() {
+            let start = (row * GLYPH_HEIGHT + y) * stride + column * GLYPH_WIDTH;
+            for (x, pixel) in buffer[start..start + GLYPH_WIDTH].iter_mut().enumerate() {
+                *pixel = if bits & (0x80 >> x) != 0 {
+                    foreground
+                } else {
+                    background
+                };
+            }
+        }
+    }
+}
+
+impl fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Rows of `byte`'s glyph from the top, the most significant bit is the leftmost pixel
+pub fn glyph(byte: u8) -> &'static [u8; GLYPH_HEIGHT] {
+    let index = match byte {
+        b' '..=b'~' => byte - FIRST_GLYPH,
+        _ => REPLACEMENT - FIRST_GLYPH,
+    };
+    &FONT[index as usize]
+}
+
+/// Clears `framebuffer` and draws all console output to it from now on. Kernel output only goes
+/// there too once the output target includes the framebuffer. Everything drawn is kept in the
+/// console's scrollback, which is drawn from again when it's scrolled.
+pub fn init(framebuffer: Framebuffer) {
+    let mut console = FramebufferConsole::new(framebuffer);
+    console.clear();
+    interrupts::without_interrupts(|| *CONSOLE.lock() = Some(console));
+    console::register_renderer(render);
+    output::register_framebuffer(write_bytes);
+}
+
+/// Rows and columns of text that fit on screen, None before `init`
+pub fn size() -> Option<(usize, usize)> {
+    interrupts::without_interrupts(|| {
+        CONSOLE
+            .lock()
+            .as_ref()
+            .map(|console| (console.rows(), console.columns()))
+    })
+}
+
+fn write_bytes(bytes: &[u8]) {
+    console::write_ansi(bytes, draw_cell);
+}
+
+// New text at the bottom of the scrollback, only drawn while it's in view
+fn draw_cell(byte: u8, attribute: u8) {
+    interrupts::without_interrupts(|| {
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            console.set_attribute(attribute);
+            console.write_byte(byte);
+        }
+    });
+}
+
+// Redraws the screen with the lines the scrollback has in view
+fn render(scrollback: &Scrollback) {
+    interrupts::without_interrupts(|| {
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            console.set_attribute(DEFAULT_ATTRIBUTE);
+            console.clear();
+            for (i, line) in scrollback.visible(console.rows()).enumerate() {
+                if i > 0 {
+                    console.write_byte(b'\n');
+                }
+                for cell in line {
+                    console.set_attribute(cell.attribute);
+                    console.write_byte(cell.character);
+                }
+            }
+        }
+    });
+}
+
+// Sends formatted text through the scrollback like the rest of the console output
+struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Prints to the framebuffer only, dropped before `init`
+pub fn print(args: fmt::Arguments) {
+    if size().is_some() {
+        // Writing to the console can't fail
+        let _ = fmt::write(&mut Writer, args);
+    }
+}
+
+/// `kprint!` for the framebuffer console
+#[macro_export]
+macro_rules! fbprint {
+    ($($arg:tt)*) => ($crate::drivers::fbcon::print(format_args!($($arg)*)));
+}
+
+/// `kprintln!` for the framebuffer console
+#[macro_export]
+macro_rules! fbprintln {
+    () => ($crate::fbprint!("\r\n"));
+    ($($arg:tt)*) => ({
+        $crate::drivers::fbcon::print(format_args!($($arg)*));
+        $crate::drivers::fbcon::print(format_args!("\r\n"));
+    })
+}
+
+// 8x8 IBM PC style glyphs with every row doubled, for the printable ASCII range from the space
+static FONT: [[u8; GLYPH_HEIGHT]; 95] = [
+    *b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00", // ' '
+    *b"\x18\x18\x3c\x3c\x3c\x3c\x18\x18\x18\x18\x00\x00\x18\x18\x00\x00", // '!'
+    *b"\x6c\x6c\x6c\x6c\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00", // '"'
+    *b"\x6c\x6c\x6c\x6c\xfe\xfe\x6c\x6c\xfe\xfe\x6c\x6c\x6c\x6c\x00\x00", // '#'
+    *b"\x30\x30\x7c\x7c\xc0\xc0\x78\x78\x0c\x0c\xf8\xf8\x30\x30\x00\x00", // '$'
+    *b"\x00\x00\xc6\xc6\xcc\xcc\x18\x18\x30\x30\x66\x66\xc6\xc6\x00\x00", // '%'
+    *b"\x38\x38\x6c\x6c\x38\x38\x76\x76\xdc\xdc\xcc\xcc\x76\x76\x00\x00", // '&'
+    *b"\x60\x60\x60\x60\xc0\xc0\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00", // '\''
+    *b"\x18\x18\x30\x30\x60\x60\x60\x60\x60\x60\x30\x30\x18\x18\x00\x00", // '('
+    *b"\x60\x60\x30\x30\x18\x18\x18\x18\x18\x18\x30\x30\x60\x60\x00\x00", // ')'
+    *b"\x00\x00\x66\x66\x3c\x3c\xff\xff\x3c\x3c\x66\x66\x00\x00\x00\x00", // '*'
+    *b"\x00\x00\x30\x30\x30\x30\xfc\xfc\x30\x30\x30\x30\x00\x00\x00\x00", // '+'
+    *b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x30\x30\x30\x30\x60\x60", // ','
+    *b"\x00\x00\x00\x00\x00\x00\xfc\xfc\x00\x00\x00\x00\x00\x00\x00\x00", // '-'
+    *b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x30\x30\x30\x30\x00\x00", // '.'
+    *b"\x06\x06\x0c\x0c\x18\x18\x30\x30\x60\x60\xc0\xc0\x80\x80\x00\x00", // '/'
+    *b"\x7c\x7c\xc6\xc6\xce\xce\xde\xde\xf6\xf6\xe6\xe6\x7c\x7c\x00\x00", // '0'
+    *b"\x30\x30\x70\x70\x30\x30\x30\x30\x30\x30\x30\x30\xfc\xfc\x00\x00", // '1'
+    *b"\x78\x78\xcc\xcc\x0c\x0c\x38\x38\x60\x60\xcc\xcc\xfc\xfc\x00\x00", // '2'
+    *b"\x78\x78\xcc\xcc\x0c\x0c\x38\x38\x0c\x0c\xcc\xcc\x78\x78\x00\x00", // '3'
+    *b"\x1c\x1c\x3c\x3c\x6c\x6c\xcc\xcc\xfe\xfe\x0c\x0c\x1e\x1e\x00\x00", // '4'
+    *b"\xfc\xfc\xc0\xc0\xf8\xf8\x0c\x0c\x0c\x0c\xcc\xcc\x78\x78\x00\x00", // '5'
+    *b"\x38\x38\x60\x60\xc0\xc0\xf8\xf8\xcc\xcc\xcc\xcc\x78\x78\x00\x00", // '6'
+    *b"\xfc\xfc\xcc\xcc\x0c\x0c\x18\x18\x30\x30\x30\x30\x30\x30\x00\x00", // '7'
+    *b"\x78\x78\xcc\xcc\xcc\xcc\x78\x78\xcc\xcc\xcc\xcc\x78\x78\x00\x00", // '8'
+    *b"\x78\x78\xcc\xcc\xcc\xcc\x7c\x7c\x0c\x0c\x18\x18\x70\x70\x00\x00", // '9'
+    *b"\x00\x00\x30\x30\x30\x30\x00\x00\x00\x00\x30\x30\x30\x30\x00\x00", // ':'
+    *b"\x00\x00\x30\x30\x30\x30\x00\x00\x00\x00\x30\x30\x30\x30\x60\x60", // ';'
+    *b"\x18\x18\x30\x30\x60\x60\xc0\xc0\x60\x60\x30\x30\x18\x18\x00\x00", // '<'
+    *b"\x00\x00\x00\x00\xfc\xfc\x00\x00\x00\x00\xfc\xfc\x00\x00\x00\x00", // '='
+    *b"\x60\x60\x30\x30\x18\x18\x0c\x0c\x18\x18\x30\x30\x60\x60\x00\x00", // '>'
+    *b"\x78\x78\xcc\xcc\x0c\x0c\x18\x18\x30\x30\x00\x00\x30\x30\x00\x00", // '?'
+    *b"\x7c\x7c\xc6\xc6\xde\xde\xde\xde\xde\xde\xc0\xc0\x78\x78\x00\x00", // '@'
+    *b"\x30\x30\x78\x78\xcc\xcc\xcc\xcc\xfc\xfc\xcc\xcc\xcc\xcc\x00\x00", // 'A'
+    *b"\xfc\xfc\x66\x66\x66\x66\x7c\x7c\x66\x66\x66\x66\xfc\xfc\x00\x00", // 'B'
+    *b"\x3c\x3c\x66\x66\xc0\xc0\xc0\xc0\xc0\xc0\x66\x66\x3c\x3c\x00\x00", // 'C'
+    *b"\xf8\xf8\x6c\x6c\x66\x66\x66\x66\x66\x66\x6c\x6c\xf8\xf8\x00\x00", // 'D'
+    *b"\xfe\xfe\x62\x62\x68\x68\x78\x78\x68\x68\x62\x62\xfe\xfe\x00\x00", // 'E'
+    *b"\xfe\xfe\x62\x62\x68\x68\x78\x78\x68\x68\x60\x60\xf0\xf0\x00\x00", // 'F'
+    *b"\x3c\x3c\x66\x66\xc0\xc0\xc0\xc0\xce\xce\x66\x66\x3e\x3e\x00\x00", // 'G'
+    *b"\xcc\xcc\xcc\xcc\xcc\xcc\xfc\xfc\xcc\xcc\xcc\xcc\xcc\xcc\x00\x00", // 'H'
+    *b"\x78\x78\x30\x30\x30\x30\x30\x30\x30\x30\x30\x30\x78\x78\x00\x00", // 'I'
+    *b"\x1e\x1e\x0c\x0c\x0c\x0c\x0c\x0c\xcc\xcc\xcc\xcc\x78\x78\x00\x00", // 'J'
+    *b"\xe6\xe6\x66\x66\x6c\x6c\x78\x78\x6c\x6c\x66\x66\xe6\xe6\x00\x00", // 'K'
+    *b"\xf0\xf0\x60\x60\x60\x60\x60\x60\x62\x62\x66\x66\xfe\xfe\x00\x00", // 'L'
+    *b"\xc6\xc6\xee\xee\xfe\xfe\xfe\xfe\xd6\xd6\xc6\xc6\xc6\xc6\x00\x00", // 'M'
+    *b"\xc6\xc6\xe6\xe6\xf6\xf6\xde\xde\xce\xce\xc6\xc6\xc6\xc6\x00\x00", // 'N'
+    *b"\x38\x38\x6c\x6c\xc6\xc6\xc6\xc6\xc6\xc6\x6c\x6c\x38\x38\x00\x00", // 'O'
+    *b"\xfc\xfc\x66\x66\x66\x66\x7c\x7c\x60\x60\x60\x60\xf0\xf0\x00\x00", // 'P'
+    *b"\x78\x78\xcc\xcc\xcc\xcc\xcc\xcc\xdc\xdc\x78\x78\x1c\x1c\x00\x00", // 'Q'
+    *b"\xfc\xfc\x66\x66\x66\x66\x7c\x7c\x6c\x6c\x66\x66\xe6\xe6\x00\x00", // 'R'
+    *b"\x78\x78\xcc\xcc\xe0\xe0\x70\x70\x1c\x1c\xcc\xcc\x78\x78\x00\x00", // 'S'
+    *b"\xfc\xfc\xb4\xb4\x30\x30\x30\x30\x30\x30\x30\x30\x78\x78\x00\x00", // 'T'
+    *b"\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xfc\xfc\x00\x00", // 'U'
+    *b"\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xcc\x78\x78\x30\x30\x00\x00", // 'V'
+    *b"\xc6\xc6\xc6\xc6\xc6\xc6\xd6\xd6\xfe\xfe\xee\xee\xc6\xc6\x00\x00", // 'W'
+    *b"\xc6\xc6\xc6\xc6\x6c\x6c\x38\x38\x38\x38\x6c\x6c\xc6\xc6\x00\x00", // 'X'
+    *b"\xcc\xcc\xcc\xcc\xcc\xcc\x78\x78\x30\x30\x30\x30\x78\x78\x00\x00", // 'Y'
+    *b"\xfe\xfe\xc6\xc6\x8c\x8c\x18\x18\x32\x32\x66\x66\xfe\xfe\x00\x00", // 'Z'
+    *b"\x78\x78\x60\x60\x60\x60\x60\x60\x60\x60\x60\x60\x78\x78\x00\x00", // '['
+    *b"\xc0\xc0\x60\x60\x30\x30\x18\x18\x0c\x0c\x06\x06\x02\x02\x00\x00", // '\\'
+    *b"\x78\x78\x18\x18\x18\x18\x18\x18\x18\x18\x18\x18\x78\x78\x00\x00", // ']'
+    *b"\x10\x10\x38\x38\x6c\x6c\xc6\xc6\x00\x00\x00\x00\x00\x00\x00\x00", // '^'
+    *b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\xff\xff", // '_'
+    *b"\x30\x30\x30\x30\x18\x18\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00", // '`'
+    *b"\x00\x00\x00\x00\x78\x78\x0c\x0c\x7c\x7c\xcc\xcc\x76\x76\x00\x00", // 'a'
+    *b"\xe0\xe0\x60\x60\x60\x60\x7c\x7c\x66\x66\x66\x66\xdc\xdc\x00\x00", // 'b'
+    *b"\x00\x00\x00\x00\x78\x78\xcc\xcc\xc0\xc0\xcc\xcc\x78\x78\x00\x00", // 'c'
+    *b"\x1c\x1c\x0c\x0c\x0c\x0c\x7c\x7c\xcc\xcc\xcc\xcc\x76\x76\x00\x00", // 'd'
+    *b"\x00\x00\x00\x00\x78\x78\xcc\xcc\xfc\xfc\xc0\xc0\x78\x78\x00\x00", // 'e'
+    *b"\x38\x38\x6c\x6c\x60\x60\xf0\xf0\x60\x60\x60\x60\xf0\xf0\x00\x00", // 'f'
+    *b"\x00\x00\x00\x00\x76\x76\xcc\xcc\xcc\xcc\x7c\x7c\x0c\x0c\xf8\xf8", // 'g'
+    *b"\xe0\xe0\x60\x60\x6c\x6c\x76\x76\x66\x66\x66\x66\xe6\xe6\x00\x00", // 'h'
+    *b"\x30\x30\x00\x00\x70\x70\x30\x30\x30\x30\x30\x30\x78\x78\x00\x00", // 'i'
+    *b"\x0c\x0c\x00\x00\x0c\x0c\x0c\x0c\x0c\x0c\xcc\xcc\xcc\xcc\x78\x78", // 'j'
+    *b"\xe0\xe0\x60\x60\x66\x66\x6c\x6c\x78\x78\x6c\x6c\xe6\xe6\x00\x00", // 'k'
+    *b"\x70\x70\x30\x30\x30\x30\x30\x30\x30\x30\x30\x30\x78\x78\x00\x00", // 'l'
+    *b"\x00\x00\x00\x00\xcc\xcc\xfe\xfe\xfe\xfe\xd6\xd6\xc6\xc6\x00\x00", // 'm'
+    *b"\x00\x00\x00\x00\xf8\xf8\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xcc\x00\x00", // 'n'
+    *b"\x00\x00\x00\x00\x78\x78\xcc\xcc\xcc\xcc\xcc\xcc\x78\x78\x00\x00", // 'o'
+    *b"\x00\x00\x00\x00\xdc\xdc\x66\x66\x66\x66\x7c\x7c\x60\x60\xf0\xf0", // 'p'
+    *b"\x00\x00\x00\x00\x76\x76\xcc\xcc\xcc\xcc\x7c\x7c\x0c\x0c\x1e\x1e", // 'q'
+    *b"\x00\x00\x00\x00\xdc\xdc\x76\x76\x66\x66\x60\x60\xf0\xf0\x00\x00", // 'r'
+    *b"\x00\x00\x00\x00\x7c\x7c\xc0\xc0\x78\x78\x0c\x0c\xf8\xf8\x00\x00", // 's'
+    *b"\x10\x10\x30\x30\x7c\x7c\x30\x30\x30\x30\x34\x34\x18\x18\x00\x00", // 't'
+    *b"\x00\x00\x00\x00\xcc\xcc\xcc\xcc\xcc\xcc\xcc\xcc\x76\x76\x00\x00", // 'u'
+    *b"\x00\x00\x00\x00\xcc\xcc\xcc\xcc\xcc\xcc\x78\x78\x30\x30\x00\x00", // 'v'
+    *b"\x00\x00\x00\x00\xc6\xc6\xd6\xd6\xfe\xfe\xfe\xfe\x6c\x6c\x00\x00", // 'w'
+    *b"\x00\x00\x00\x00\xc6\xc6\x6c\x6c\x38\x38\x6c\x6c\xc6\xc6\x00\x00", // 'x'
+    *b"\x00\x00\x00\x00\xcc\xcc\xcc\xcc\xcc\xcc\x7c\x7c\x0c\x0c\xf8\xf8", // 'y'
+    *b"\x00\x00\x00\x00\xfc\xfc\x98\x98\x30\x30\x64\x64\xfc\xfc\x00\x00", // 'z'
+    *b"\x1c\x1c\x30\x30\x30\x30\xe0\xe0\x30\x30\x30\x30\x1c\x1c\x00\x00", // '{'
+    *b"\x18\x18\x18\x18\x18\x18\x00\x00\x18\x18\x18\x18\x18\x18\x00\x00", // '|'
+    *b"\xe0\xe0\x30\x30\x30\x30\x1c\x1c\x30\x30\x30\x30\xe0\xe0\x00\x00", // '}'
+    *b"\x76\x76\xdc\xdc\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00", // '~'
+];
@@ -0,0 +1,181 @@
+use core::task::{Context, Poll, Waker};
+
+use common::{serial::SerialPort, x86_64::instructions::interrupts};
+use spin::Mutex;
+
+use super::{
+    ansi::AnsiParser,
+    keyboard::{Key, KeyEvent, Keyboard},
+    pit,
+};
+use crate::{
+    interrupts::{CpuSnapshot, InterruptStackFrame},
+    time,
+};
+
+pub const COM1: u16 = 0x3F8;
+
+// Fixed size so irq handlers can queue input without touching the heap
+const BUFFER_SIZE: usize = 64;
+// Terminals send a whole escape sequence at once, so an ESC with nothing after it for this long
+// was the Escape key
+const ESCAPE_TIMEOUT_MS: u64 = 50;
+
+/// Ring buffer of input waiting to be read. When it's full new input is dropped, the oldest input
+/// is what the reader expects next.
+pub struct InputBuffer<T: Copy> {
+    items: [Option<T>; BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy> InputBuffer<T> {
+    pub const fn new() -> InputBuffer<T> {
+        InputBuffer {
+            items: [None; BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T) -> bool {
+        if self.len == BUFFER_SIZE {
+            return false;
+        }
+        self.items[(self.head + self.len) % BUFFER_SIZE] = Some(item);
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % BUFFER_SIZE;
+        self.len -= 1;
+        item
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Input filled by an irq handler with a single waker slot for whoever is waiting on it. Only the
+/// last registered waker is kept, so there should be one reader per source.
+pub struct InputSource<T: Copy> {
+    buffer: Mutex<InputBuffer<T>>,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl<T: Copy> InputSource<T> {
+    pub const fn new() -> InputSource<T> {
+        InputSource {
+            buffer: Mutex::new(InputBuffer::new()),
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Queues `item` and wakes the waiting reader. Called from the irq handler, so the waker runs
+    /// in interrupt context. Returns false if the buffer was full and `item` was dropped.
+    pub fn push(&self, item: T) -> bool {
+        let (pushed, waker) = interrupts::without_interrupts(|| {
+            (self.buffer.lock().push(item), self.waker.lock().take())
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        pushed
+    }
+
+    /// The next input without waiting
+    pub fn try_pop(&self) -> Option<T> {
+        interrupts::without_interrupts(|| self.buffer.lock().pop())
+    }
+
+    /// Ready with the next input, or Pending with `cx`'s waker kept until input arrives
+    pub fn poll(&self, cx: &mut Context<'_>) -> Poll<T> {
+        interrupts::without_interrupts(|| {
+            // Checked with interrupts off so input can't arrive between the check and storing the
+            // waker and never wake it
+            match self.buffer.lock().pop() {
+                Some(item) => Poll::Ready(item),
+                None => {
+                    self.waker.lock().replace(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        interrupts::without_interrupts(|| self.buffer.lock().len())
+    }
+}
+
+// Characters typed on the COM1 terminal, filled by its irq once `SerialPort::enable_interrupts`
+// has been called. Escape sequences only show up as key events.
+pub static SERIAL: InputSource<u8> = InputSource::new();
+
+struct SerialTerminal {
+    parser: AnsiParser,
+    // PIT tick the last byte arrived on
+    last_byte: u64,
+}
+
+// Only locked from irq handlers
+static TERMINAL: Mutex<SerialTerminal> = Mutex::new(SerialTerminal {
+    parser: AnsiParser::new(),
+    last_byte: 0,
+});
+
+/// Irq 4 handler, drains the UART's receive fifo through the escape sequence parser
+pub fn serial_handler(_frame: &mut InterruptStackFrame, _snapshot: &CpuSnapshot) {
+    let port = SerialPort::from(COM1);
+    let mut terminal = TERMINAL.lock();
+    while let Some(b) = port.read_byte() {
+        terminal.last_byte = pit::ticks();
+        if let Some(event) = terminal.parser.feed(b) {
+            queue_serial_event(event);
+        }
+    }
+}
+
+/// Called from the timer irq, hands on an ESC the parser has been holding once the line has been
+/// quiet long enough that no sequence is coming
+pub fn flush_serial() {
+    let mut terminal = TERMINAL.lock();
+    let timeout = time::ms_to_ticks(ESCAPE_TIMEOUT_MS, pit::frequency());
+    if time::ticks_between(terminal.last_byte, pit::ticks()) < timeout {
+        return;
+    }
+    if let Some(event) = terminal.parser.flush() {
+        queue_serial_event(event);
+    }
+}
+
+// Every event goes to the keyboard's queue, characters also go to `SERIAL` for `serial_byte`
+fn queue_serial_event(event: KeyEvent) {
+    if let KeyEvent::Pressed(Key::Char(c)) = event {
+        if c.is_ascii() {
+            // Nothing to do about overflow, the reader is too slow
+            SERIAL.push(c as u8);
+        }
+    }
+    Keyboard::queue_event(event);
+}
+
+/// Next byte received on `port`. COM1 is interrupt driven so its bytes come from `SERIAL`, other
+/// ports are read straight from the UART.
+pub fn serial_byte(port: u16) -> Option<u8> {
+    if port == COM1 {
+        SERIAL.try_pop()
+    } else {
+        SerialPort::from(port).read_byte()
+    }
+}
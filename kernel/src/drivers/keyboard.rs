@@ -1,7 +1,684 @@
-pub struct Keyboard {}
+use core::{
+    arch::asm,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+
+use common::{kprintln, util::Port};
+
+use super::{fbcon, input::InputSource};
+use crate::{
+    console,
+    interrupts::{self, CpuSnapshot, InterruptStackFrame},
+};
+
+const DATA_PORT: Port<u8> = Port::new(0x60);
+const STATUS_PORT: Port<u8> = Port::new(0x64);
+// Writes to the status port are controller commands
+const COMMAND_PORT: Port<u8> = Port::new(0x64);
+
+const READ_CONFIG: u8 = 0x20;
+// Config byte bit for the controller translating set 2 into set 1
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const OUTPUT_FULL: u8 = 0x01;
+const INPUT_FULL: u8 = 0x02;
+
+const ACK: u8 = 0xFA;
+const SET_SCANCODE: u8 = 0xF0;
+
+const SET2_BREAK: u8 = 0xF0;
+const EXTENDED: u8 = 0xE0;
+
+// Set 1 make codes of the modifier keys, their breaks have bit 7 set
+const LEFT_SHIFT: u8 = 0x2A;
+const RIGHT_SHIFT: u8 = 0x36;
+const CAPS_LOCK: u8 = 0x3A;
+
+// Spins before giving up on the controller
+const TIMEOUT: usize = 100000;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScancodeSet {
+    Set1 = 1,
+    Set2 = 2,
+    Set3 = 3,
+}
+
+static SCANCODE_SET: AtomicU8 = AtomicU8::new(ScancodeSet::Set1 as u8);
+static SET2_RELEASE: AtomicBool = AtomicBool::new(false);
+// The last set 1 code was the 0xE0 prefix
+static EXTENDED_PENDING: AtomicBool = AtomicBool::new(false);
+// Shift keys held according to the irq handler, bit 0 left and bit 1 right. Events don't carry
+// modifiers, this is only for the console's Shift+PageUp/PageDown.
+static SHIFT_HELD: AtomicU8 = AtomicU8::new(0);
+
+// Filled by the irq handler
+static EVENTS: InputSource<KeyEvent> = InputSource::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Char(char),
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    Function(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyEvent {
+    Pressed(Key),
+    Released(Key),
+}
+
+pub struct ComposeTable {
+    entries: &'static [(char, char, char)],
+}
+
+impl ComposeTable {
+    pub const fn new(entries: &'static [(char, char, char)]) -> ComposeTable {
+        ComposeTable { entries }
+    }
+
+    /// Either order of the two keys works. Letters keep their case in the result.
+    pub fn lookup(&self, first: char, second: char) -> Option<char> {
+        let a = first.to_ascii_lowercase();
+        let b = second.to_ascii_lowercase();
+        let (_, _, composed) = self
+            .entries
+            .iter()
+            .find(|(x, y, _)| (*x == a && *y == b) || (*x == b && *y == a))?;
+
+        if first.is_ascii_uppercase() || second.is_ascii_uppercase() {
+            // Some characters (ß) don't have a single character uppercase
+            let mut upper = composed.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(c), None) => return Some(c),
+                _ => (),
+            }
+        }
+        Some(*composed)
+    }
+}
+
+pub static DEFAULT_COMPOSE: ComposeTable = ComposeTable::new(&[
+    ('\'', 'a', 'á'),
+    ('\'', 'e', 'é'),
+    ('\'', 'i', 'í'),
+    ('\'', 'o', 'ó'),
+    ('\'', 'u', 'ú'),
+    ('`', 'a', 'à'),
+    ('`', 'e', 'è'),
+    ('`', 'i', 'ì'),
+    ('`', 'o', 'ò'),
+    ('`', 'u', 'ù'),
+    (',', 'c', 'ç'),
+    ('/', 'o', 'ø'),
+    ('a', 'e', 'æ'),
+    ('s', 's', 'ß'),
+]);
+
+#[derive(Clone, Copy)]
+enum ComposeState {
+    Idle,
+    // Compose key was pressed, waiting for the first key
+    Started,
+    First(char),
+}
+
+/// Future returned by `Keyboard::next_key`
+pub struct NextKey;
+
+impl Future for NextKey {
+    type Output = KeyEvent;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<KeyEvent> {
+        Keyboard::poll_key(cx)
+    }
+}
+
+/// Characters typed by set 1 make codes
+pub trait Layout {
+    /// The character `code` types with or without shift held, `'\0'` for keys that don't type one.
+    /// Caps lock is left to the caller.
+    fn map(&self, code: u8, shift: bool) -> char;
+}
+
+// Letters follow shift, everything else uses the US shifted symbols
+fn apply_shift(c: char, shift: bool) -> char {
+    if c.is_ascii_alphabetic() {
+        if shift {
+            c.to_ascii_uppercase()
+        } else {
+            c.to_ascii_lowercase()
+        }
+    } else if shift {
+        Keyboard::shifted(c)
+    } else {
+        c
+    }
+}
+
+pub struct UsQwerty;
+
+impl Layout for UsQwerty {
+    fn map(&self, code: u8, shift: bool) -> char {
+        apply_shift(Keyboard::make_to_char(code), shift)
+    }
+}
+
+/// US Dvorak, keys outside the letters and punctuation match US QWERTY
+pub struct Dvorak;
+
+impl Layout for Dvorak {
+    fn map(&self, code: u8, shift: bool) -> char {
+        let c = match code {
+            0x0C => '[',
+            0x0D => ']',
+
+            0x10 => '\'',
+            0x11 => ',',
+            0x12 => '.',
+            0x13 => 'p',
+            0x14 => 'y',
+            0x15 => 'f',
+            0x16 => 'g',
+            0x17 => 'c',
+            0x18 => 'r',
+            0x19 => 'l',
+            0x1A => '/',
+            0x1B => '=',
+
+            0x1E => 'a',
+            0x1F => 'o',
+            0x20 => 'e',
+            0x21 => 'u',
+            0x22 => 'i',
+            0x23 => 'd',
+            0x24 => 'h',
+            0x25 => 't',
+            0x26 => 'n',
+            0x27 => 's',
+            0x28 => '-',
+
+            0x2C => ';',
+            0x2D => 'q',
+            0x2E => 'j',
+            0x2F => 'k',
+            0x30 => 'x',
+            0x31 => 'b',
+            0x32 => 'm',
+            0x33 => 'w',
+            0x34 => 'v',
+            0x35 => 'z',
+
+            _ => Keyboard::make_to_char(code),
+        };
+        apply_shift(c, shift)
+    }
+}
+
+/// Modifier and compose state for turning codes into correctly cased characters. Init, decoding
+/// and the event queue don't need one and stay associated functions.
+#[derive(Clone, Copy)]
+pub struct Keyboard {
+    left_shift: bool,
+    right_shift: bool,
+    caps: bool,
+    // The last code was the 0xE0 prefix, the next one isn't a character key
+    extended: bool,
+    layout: &'static dyn Layout,
+    // Set 1 make code of the compose key, None disables composing
+    compose_key: Option<u8>,
+    compose_table: &'static ComposeTable,
+    compose: ComposeState,
+    // Second key of a compose sequence that didn't match, see `take_pending`
+    pending: Option<char>,
+}
+
+impl Default for Keyboard {
+    fn default() -> Keyboard {
+        Keyboard::new()
+    }
+}
 
 impl Keyboard {
-    pub fn code_to_char(code: u8) -> char {
+    /// US QWERTY with no modifiers held
+    pub fn new() -> Keyboard {
+        Keyboard::with_layout(&UsQwerty)
+    }
+
+    pub fn with_layout(layout: &'static dyn Layout) -> Keyboard {
+        Keyboard {
+            left_shift: false,
+            right_shift: false,
+            caps: false,
+            extended: false,
+            layout,
+            compose_key: None,
+            compose_table: &DEFAULT_COMPOSE,
+            compose: ComposeState::Idle,
+            pending: None,
+        }
+    }
+
+    pub fn set_layout(&mut self, layout: &'static dyn Layout) {
+        self.layout = layout;
+    }
+
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn caps(&self) -> bool {
+        self.caps
+    }
+
+    /// Tracks the shift keys and caps lock and returns the character typed by a make code.
+    /// Letters are upper case when exactly one of shift and caps lock is on, the other keys only
+    /// follow shift. While a compose sequence is being typed nothing is returned until its second
+    /// key, which gives the composed character.
+    pub fn process(&mut self, code: u8) -> Option<char> {
+        if code == EXTENDED {
+            self.extended = true;
+            return None;
+        }
+        // Extended codes include fake shifts sent around some keys, none of them type anything
+        if core::mem::take(&mut self.extended) {
+            return None;
+        }
+
+        match code {
+            LEFT_SHIFT => self.left_shift = true,
+            RIGHT_SHIFT => self.right_shift = true,
+            c if c == LEFT_SHIFT | 0x80 => self.left_shift = false,
+            c if c == RIGHT_SHIFT | 0x80 => self.right_shift = false,
+            CAPS_LOCK => self.caps = !self.caps,
+            _ => (),
+        }
+
+        if code & 0x80 != 0 {
+            return None;
+        }
+        if self.compose_key == Some(code) {
+            self.compose = ComposeState::Started;
+            return None;
+        }
+        let c = self.layout.map(code, self.shift());
+        if c == '\0' {
+            return None;
+        }
+
+        // Caps lock flips whatever case shift picked
+        let c = match c {
+            c if self.caps && c.is_ascii_uppercase() => c.to_ascii_lowercase(),
+            c if self.caps && c.is_ascii_lowercase() => c.to_ascii_uppercase(),
+            c => c,
+        };
+        self.compose_char(c)
+    }
+
+    // Feeds a typed character to the compose sequence in progress, if there is one
+    fn compose_char(&mut self, c: char) -> Option<char> {
+        match self.compose {
+            ComposeState::Idle => Some(c),
+            ComposeState::Started => {
+                self.compose = ComposeState::First(c);
+                None
+            }
+            ComposeState::First(first) => {
+                self.compose = ComposeState::Idle;
+                match self.compose_table.lookup(first, c) {
+                    Some(composed) => Some(composed),
+                    None => {
+                        self.pending = Some(c);
+                        Some(first)
+                    }
+                }
+            }
+        }
+    }
+
+    /// A compose sequence without a match types both of its keys, `process` returns the first
+    /// and this the second
+    pub fn take_pending(&mut self) -> Option<char> {
+        self.pending.take()
+    }
+
+    /// Enables compose sequences started by the key with set 1 make code `key`, or disables them
+    /// with None (the default).
+    pub fn set_compose_key(&mut self, key: Option<u8>) {
+        self.compose_key = key;
+        self.compose = ComposeState::Idle;
+    }
+
+    pub fn set_compose_table(&mut self, table: &'static ComposeTable) {
+        self.compose_table = table;
+    }
+
+    // US symbols on the shifted number row and punctuation keys
+    fn shifted(c: char) -> char {
+        match c {
+            '1' => '!',
+            '2' => '@',
+            '3' => '#',
+            '4' => '$',
+            '5' => '%',
+            '6' => '^',
+            '7' => '&',
+            '8' => '*',
+            '9' => '(',
+            '0' => ')',
+            '-' => '_',
+            '=' => '+',
+            '[' => '{',
+            ']' => '}',
+            ';' => ':',
+            '\'' => '"',
+            '`' => '~',
+            '\\' => '|',
+            ',' => '<',
+            '.' => '>',
+            '/' => '?',
+            c => c,
+        }
+    }
+
+    /// Makes sure scancodes can be decoded with the set 1 table and returns the set the bytes on
+    /// the data port are in. When the controller translates (the default on PCs and QEMU) the
+    /// keyboard has to stay in set 2, which comes out as set 1. Otherwise the keyboard is asked
+    /// which set it is using (0xF0 0x00) and if it isn't set 1 it is told to switch (0xF0 0x01).
+    /// Keyboards that refuse to switch are left in set 2 and their codes are translated to set 1
+    /// in `translate`, so everything past that point only ever sees set 1.
+    pub fn init() -> ScancodeSet {
+        let translated = Keyboard::controller_translates();
+        let set = if translated {
+            if Keyboard::query_set(true) != Some(ScancodeSet::Set2) {
+                Keyboard::send(SET_SCANCODE);
+                Keyboard::send(ScancodeSet::Set2 as u8);
+            }
+            ScancodeSet::Set1
+        } else {
+            match Keyboard::query_set(false) {
+                Some(ScancodeSet::Set1) => ScancodeSet::Set1,
+                _ => {
+                    Keyboard::send(SET_SCANCODE);
+                    Keyboard::send(ScancodeSet::Set1 as u8);
+                    match Keyboard::query_set(false) {
+                        Some(ScancodeSet::Set1) => ScancodeSet::Set1,
+                        _ => ScancodeSet::Set2,
+                    }
+                }
+            }
+        };
+
+        kprintln!(
+            "Keyboard using scancode {:?}, controller translation {}",
+            set,
+            translated
+        );
+        SCANCODE_SET.store(set as u8, Ordering::SeqCst);
+
+        // Only once the replies above have been read, the handler would take them otherwise
+        interrupts::register_handler(interrupts::KEYBOARD_VECTOR, keyboard_handler);
+        set
+    }
+
+    /// Ready with the next key event, or Pending until the irq handler queues one and wakes `cx`
+    pub fn poll_key(cx: &mut Context<'_>) -> Poll<KeyEvent> {
+        EVENTS.poll(cx)
+    }
+
+    /// The next key event without waiting
+    pub fn try_key() -> Option<KeyEvent> {
+        EVENTS.try_pop()
+    }
+
+    /// Queues a key event from another source, such as a serial terminal, so readers of the
+    /// keyboard see it like a key press. Returns false if the buffer was full.
+    pub fn queue_event(event: KeyEvent) -> bool {
+        EVENTS.push(event)
+    }
+
+    /// Resolves to the next key event
+    pub fn next_key() -> NextKey {
+        NextKey
+    }
+
+    pub fn scancode_set() -> ScancodeSet {
+        match SCANCODE_SET.load(Ordering::SeqCst) {
+            2 => ScancodeSet::Set2,
+            3 => ScancodeSet::Set3,
+            _ => ScancodeSet::Set1,
+        }
+    }
+
+    /// The set the keyboard itself is in. `translated` is whether the controller translates, the
+    /// reply goes through the translation as well then.
+    fn query_set(translated: bool) -> Option<ScancodeSet> {
+        if !Keyboard::send(SET_SCANCODE) || !Keyboard::send(0x00) {
+            return None;
+        }
+
+        match (translated, Keyboard::read()?) {
+            (false, 0x01) | (true, 0x43) => Some(ScancodeSet::Set1),
+            (false, 0x02) | (true, 0x41) => Some(ScancodeSet::Set2),
+            (false, 0x03) | (true, 0x3F) => Some(ScancodeSet::Set3),
+            _ => None,
+        }
+    }
+
+    // Reads the controller config byte, assumes translation when the controller doesn't answer
+    // since that's what firmware leaves on
+    fn controller_translates() -> bool {
+        for _ in 0..TIMEOUT {
+            if unsafe { STATUS_PORT.read() } & INPUT_FULL == 0 {
+                unsafe { COMMAND_PORT.write(READ_CONFIG) };
+                return Keyboard::read().map_or(true, |config| config & CONFIG_TRANSLATION != 0);
+            }
+            unsafe { asm!("pause") }
+        }
+        true
+    }
+
+    fn send(value: u8) -> bool {
+        for _ in 0..TIMEOUT {
+            if unsafe { STATUS_PORT.read() } & INPUT_FULL == 0 {
+                unsafe { DATA_PORT.write(value) };
+                return Keyboard::read() == Some(ACK);
+            }
+            unsafe { asm!("pause") }
+        }
+        false
+    }
+
+    fn read() -> Option<u8> {
+        for _ in 0..TIMEOUT {
+            if unsafe { STATUS_PORT.read() } & OUTPUT_FULL != 0 {
+                return Some(unsafe { DATA_PORT.read() });
+            }
+            unsafe { asm!("pause") }
+        }
+        None
+    }
+
+    /// Converts a raw byte from the keyboard into a set 1 code. Returns `None` for bytes that
+    /// are only part of a sequence (the set 2 break prefix).
+    pub fn translate(code: u8) -> Option<u8> {
+        match Keyboard::scancode_set() {
+            ScancodeSet::Set2 => match code {
+                EXTENDED => Some(EXTENDED),
+                SET2_BREAK => {
+                    SET2_RELEASE.store(true, Ordering::SeqCst);
+                    None
+                }
+                _ => {
+                    let code = Keyboard::set2_to_set1(code);
+                    if SET2_RELEASE.swap(false, Ordering::SeqCst) {
+                        Some(code | 0x80)
+                    } else {
+                        Some(code)
+                    }
+                }
+            },
+            _ => Some(code),
+        }
+    }
+
+    fn set2_to_set1(code: u8) -> u8 {
+        match code {
+            0x76 => 0x01,
+            0x16 => 0x02,
+            0x1E => 0x03,
+            0x26 => 0x04,
+            0x25 => 0x05,
+            0x2E => 0x06,
+            0x36 => 0x07,
+            0x3D => 0x08,
+            0x3E => 0x09,
+            0x46 => 0x0A,
+            0x45 => 0x0B,
+            0x4E => 0x0C,
+            0x55 => 0x0D,
+            0x66 => 0x0E,
+            0x0D => 0x0F,
+
+            0x15 => 0x10,
+            0x1D => 0x11,
+            0x24 => 0x12,
+            0x2D => 0x13,
+            0x2C => 0x14,
+            0x35 => 0x15,
+            0x3C => 0x16,
+            0x43 => 0x17,
+            0x44 => 0x18,
+            0x4D => 0x19,
+            0x54 => 0x1A,
+            0x5B => 0x1B,
+            0x5A => 0x1C,
+            0x14 => 0x1D,
+
+            0x1C => 0x1E,
+            0x1B => 0x1F,
+            0x23 => 0x20,
+            0x2B => 0x21,
+            0x34 => 0x22,
+            0x33 => 0x23,
+            0x3B => 0x24,
+            0x42 => 0x25,
+            0x4B => 0x26,
+            0x4C => 0x27,
+            0x52 => 0x28,
+            0x0E => 0x29,
+            0x12 => 0x2A,
+            0x5D => 0x2B,
+
+            0x1A => 0x2C,
+            0x22 => 0x2D,
+            0x21 => 0x2E,
+            0x2A => 0x2F,
+            0x32 => 0x30,
+            0x31 => 0x31,
+            0x3A => 0x32,
+            0x41 => 0x33,
+            0x49 => 0x34,
+            0x4A => 0x35,
+            0x59 => 0x36,
+            0x7C => 0x37,
+            0x11 => 0x38,
+            0x29 => 0x39,
+            0x58 => 0x3A,
+
+            0x05 => 0x3B,
+            0x06 => 0x3C,
+            0x04 => 0x3D,
+            0x0C => 0x3E,
+            0x03 => 0x3F,
+            0x0B => 0x40,
+            0x83 => 0x41,
+            0x0A => 0x42,
+            0x01 => 0x43,
+            0x09 => 0x44,
+            0x78 => 0x57,
+            0x07 => 0x58,
+
+            // Keypad, with the 0xE0 prefix these are the arrows/navigation keys like set 1
+            0x77 => 0x45,
+            0x7E => 0x46,
+            0x6C => 0x47,
+            0x75 => 0x48,
+            0x7D => 0x49,
+            0x7B => 0x4A,
+            0x6B => 0x4B,
+            0x73 => 0x4C,
+            0x74 => 0x4D,
+            0x79 => 0x4E,
+            0x69 => 0x4F,
+            0x72 => 0x50,
+            0x7A => 0x51,
+            0x70 => 0x52,
+            0x71 => 0x53,
+
+            _ => 0x00,
+        }
+    }
+
+    /// Turns a set 1 code into an event. Returns `None` for the 0xE0 prefix, which changes the
+    /// meaning of the next code, and for keys without a `Key`.
+    pub fn decode(code: u8) -> Option<KeyEvent> {
+        if code == EXTENDED {
+            EXTENDED_PENDING.store(true, Ordering::SeqCst);
+            return None;
+        }
+        let extended = EXTENDED_PENDING.swap(false, Ordering::SeqCst);
+        Keyboard::lookup(code, extended)
+    }
+
+    /// The event for a set 1 code, `extended` when it followed the 0xE0 prefix. Break codes (bit 7
+    /// set) are releases of the key their low 7 bits press.
+    pub fn lookup(code: u8, extended: bool) -> Option<KeyEvent> {
+        let key = match (extended, code & 0x7F) {
+            (true, 0x48) => Key::Up,
+            (true, 0x50) => Key::Down,
+            (true, 0x4B) => Key::Left,
+            (true, 0x4D) => Key::Right,
+            (true, 0x47) => Key::Home,
+            (true, 0x4F) => Key::End,
+            (true, 0x52) => Key::Insert,
+            (true, 0x53) => Key::Delete,
+            (true, 0x49) => Key::PageUp,
+            (true, 0x51) => Key::PageDown,
+            (true, _) => return None,
+
+            (false, 0x01) => Key::Escape,
+            (false, f @ 0x3B..=0x44) => Key::Function(f - 0x3A),
+            (false, 0x57) => Key::Function(11),
+            (false, 0x58) => Key::Function(12),
+            (false, make) => match Keyboard::make_to_char(make) {
+                '\0' => return None,
+                c => Key::Char(c),
+            },
+        };
+
+        if code & 0x80 != 0 {
+            Some(KeyEvent::Released(key))
+        } else {
+            Some(KeyEvent::Pressed(key))
+        }
+    }
+
+    fn make_to_char(code: u8) -> char {
         match code {
             0x02 => '1',
             0x03 => '2',
@@ -65,3 +742,50 @@ impl Keyboard {
         }
     }
 }
+
+fn keyboard_handler(_frame: &mut InterruptStackFrame, _snapshot: &CpuSnapshot) {
+    let code = match Keyboard::translate(unsafe { DATA_PORT.read() }) {
+        Some(code) => code,
+        None => return,
+    };
+    // Extended shifts are the fake ones sent around some keys
+    if !EXTENDED_PENDING.load(Ordering::SeqCst) {
+        track_shift(code);
+    }
+
+    if let Some(event) = Keyboard::decode(code) {
+        if scroll_console(event) {
+            return;
+        }
+        if !EVENTS.push(event) {
+            kprintln!("Keyboard buffer full, dropped {:?}", event);
+        }
+    }
+}
+
+fn track_shift(code: u8) {
+    let bit = match code & 0x7F {
+        LEFT_SHIFT => 1,
+        RIGHT_SHIFT => 2,
+        _ => return,
+    };
+    if code & 0x80 != 0 {
+        SHIFT_HELD.fetch_and(!bit, Ordering::SeqCst);
+    } else {
+        SHIFT_HELD.fetch_or(bit, Ordering::SeqCst);
+    }
+}
+
+// Shift+PageUp/PageDown scroll the console a screen at a time instead of reaching readers
+fn scroll_console(event: KeyEvent) -> bool {
+    if SHIFT_HELD.load(Ordering::SeqCst) == 0 {
+        return false;
+    }
+    let page = fbcon::size().map_or(1, |(rows, _)| rows.max(1));
+    match event {
+        KeyEvent::Pressed(Key::PageUp) => console::scroll_up(page),
+        KeyEvent::Pressed(Key::PageDown) => console::scroll_down(page),
+        _ => return false,
+    }
+    true
+}
@@ -0,0 +1,202 @@
+use common::{
+    kassert,
+    x86_64::{PhysAddr, VirtAddr},
+};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use spin::Mutex;
+
+use crate::{
+    acpi::{find_table, madt, Signature},
+    drivers::pit,
+};
+
+// Every cpu sees its own local apic at the same address, so one lock covers all of them
+static LAPIC: Mutex<LocalApic> = Mutex::new(LocalApic::new());
+// Measured by calibrate_timer, 0 until then
+static TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+// How long calibration lets the timer count for
+const CALIBRATION_MS: u32 = 10;
+
+pub struct LocalApic {
+    base: u64,
+}
+
+impl LocalApic {
+    const ID: u16 = 0x20;
+    const VERSION: u16 = 0x30;
+    const TPR: u16 = 0x80;
+    const APR: u16 = 0x90;
+    const PPR: u16 = 0xA0;
+    const EOI: u16 = 0xB0;
+    const RRD: u16 = 0xC0;
+    const LDR: u16 = 0xD0;
+    const DFR: u16 = 0xE0;
+    const SIV: u16 = 0xF0;
+
+    const ERROR_STATUS: u16 = 0x280;
+
+    const ICR_LOW: u16 = 0x300;
+    const ICR_HIGH: u16 = 0x310;
+
+    const LVT_TIMER: u16 = 0x320;
+    const LVT_THERMAL: u16 = 0x330;
+    const LVT_PMC: u16 = 0x340;
+    const LVT_LINT0: u16 = 0x350;
+    const LVT_LINT1: u16 = 0x360;
+    const LVT_ERROR: u16 = 0x370;
+    const INITCNT_TIMER: u16 = 0x380;
+    const CURCNT_TIMER: u16 = 0x390;
+    const DCR_TIMER: u16 = 0x3E0;
+
+    // Software enable bit of the spurious interrupt vector register
+    const SIV_ENABLE: u32 = 0x100;
+    const TIMER_MASKED: u32 = 0x10000;
+    const TIMER_PERIODIC: u32 = 0x20000;
+
+    const ICR_PENDING: u32 = 1 << 12;
+    const ICR_ASSERT: u32 = 1 << 14;
+    const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+    pub const fn new() -> LocalApic {
+        LocalApic { base: 0xFEE00000 }
+    }
+
+    fn write(&mut self, offset: u16, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.base + offset as u64) as *mut _, value);
+        }
+    }
+
+    fn read(&self, offset: u16) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset as u64) as *mut _) }
+    }
+}
+
+/// Vector the local apic raises for spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// Divisor the timer is calibrated with, `set_timer` needs the same one for counts from
+/// `ticks_per_ms` to hold
+pub const TIMER_DIVIDE: u8 = 16;
+
+/// Maps the registers at the address the MADT gives and enables the local apic of the calling cpu.
+/// The timer is left alone, see `set_timer`.
+pub fn init() {
+    let madt = find_table(Signature::MADT).expect("Unable to get MADT!");
+    let base = madt.get_entry::<madt::MADT>().local_apic_address();
+
+    common::mem::map_mmio(PhysAddr::new(base), 4096).expect("Unable to map local apic registers!");
+    kassert!(
+        common::mem::is_uncacheable(VirtAddr::new(base)),
+        "Local apic registers are cacheable!"
+    );
+
+    let mut apic = LAPIC.lock();
+    apic.base = base;
+
+    let siv = apic.read(LocalApic::SIV);
+    apic.write(
+        LocalApic::SIV,
+        siv | LocalApic::SIV_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+}
+
+/// Signals the end of the interrupt being handled. Every irq and ipi has to end with this once the
+/// legacy PIC is masked, the PIC's own EOI does nothing for them.
+pub fn eoi() {
+    LAPIC.lock().write(LocalApic::EOI, 0);
+}
+
+/// Starts the periodic timer raising `vector` every `initial_count` ticks of the bus clock divided
+/// by `divide`. Panics if `divide` isn't a power of two up to 128.
+pub fn set_timer(vector: u8, initial_count: u32, divide: u8) {
+    let config = divide_config(divide).expect("Invalid local apic timer divisor!");
+
+    let mut apic = LAPIC.lock();
+    apic.write(
+        LocalApic::LVT_TIMER,
+        vector as u32 | LocalApic::TIMER_PERIODIC,
+    );
+    apic.write(LocalApic::DCR_TIMER, config);
+    // Writing the count starts the timer so it goes last
+    apic.write(LocalApic::INITCNT_TIMER, initial_count);
+}
+
+/// Measures how many times the timer counts down in a millisecond with `TIMER_DIVIDE`, which
+/// depends on the bus clock and isn't reported anywhere. Stops the timer, `set_timer` has to be
+/// called again afterwards.
+///
+/// The timer is started masked in one shot mode from the largest count, then PIT channel 2 is
+/// polled for CALIBRATION_MS. Whatever the timer counted down in the meantime divided by the
+/// interval is the rate. Nothing else may touch the local apic until it's done, interrupts should
+/// be off.
+pub fn calibrate_timer() -> u32 {
+    let mut apic = LAPIC.lock();
+    apic.write(LocalApic::LVT_TIMER, LocalApic::TIMER_MASKED);
+    apic.write(LocalApic::DCR_TIMER, divide_config(TIMER_DIVIDE).unwrap());
+
+    apic.write(LocalApic::INITCNT_TIMER, u32::MAX);
+    pit::wait_ms(CALIBRATION_MS);
+    let remaining = apic.read(LocalApic::CURCNT_TIMER);
+    // A zero count stops the timer
+    apic.write(LocalApic::INITCNT_TIMER, 0);
+
+    let ticks_per_ms = (u32::MAX - remaining) / CALIBRATION_MS;
+    TICKS_PER_MS.store(ticks_per_ms, Ordering::SeqCst);
+    ticks_per_ms
+}
+
+/// Timer ticks per millisecond found by `calibrate_timer`, 0 before it has run
+pub fn ticks_per_ms() -> u32 {
+    TICKS_PER_MS.load(Ordering::SeqCst)
+}
+
+/// Calibrates the timer if it hasn't been and starts it raising `vector` `hz` times a second
+pub fn start_periodic_timer(vector: u8, hz: u32) {
+    let ticks_per_ms = match ticks_per_ms() {
+        0 => calibrate_timer(),
+        ticks => ticks,
+    };
+    let count = (ticks_per_ms as u64 * 1000 / hz.max(1) as u64).max(1);
+    set_timer(vector, count.min(u32::MAX as u64) as u32, TIMER_DIVIDE);
+}
+
+/// Current count of the timer, counting down from the initial count
+pub fn timer_count() -> u32 {
+    LAPIC.lock().read(LocalApic::CURCNT_TIMER)
+}
+
+/// Divide configuration register value for dividing the timer clock by `divide`. The encoding
+/// skips bit 2, by 2 through 16 are 0-3 and by 32 through 128 are 8-10 with 1 wrapping to 11.
+pub fn divide_config(divide: u8) -> Option<u32> {
+    if !divide.is_power_of_two() {
+        return None;
+    }
+    let shift = match divide.trailing_zeros() {
+        0 => 7,
+        shift => shift - 1,
+    };
+    Some((shift & 0b11) | ((shift & 0b100) << 1))
+}
+
+/// Apic id of the calling cpu
+pub fn id() -> u8 {
+    (LAPIC.lock().read(LocalApic::ID) >> 24) as u8
+}
+
+/// Sends `vector` to every cpu but this one and waits for the ipi to be accepted
+pub fn send_ipi_others(vector: u8) {
+    let mut apic = LAPIC.lock();
+    apic.write(LocalApic::ICR_HIGH, 0);
+    apic.write(
+        LocalApic::ICR_LOW,
+        vector as u32 | LocalApic::ICR_ASSERT | LocalApic::ICR_ALL_EXCLUDING_SELF,
+    );
+
+    while apic.read(LocalApic::ICR_LOW) & LocalApic::ICR_PENDING != 0 {
+        unsafe { asm!("pause") }
+    }
+}
@@ -1,5 +1,11 @@
+pub mod ansi;
 pub mod device;
+pub mod fbcon;
+pub mod input;
 pub mod keyboard;
+pub mod lapic;
 pub mod pci;
+pub mod pit;
+pub mod rtc;
 
 pub use common::serial;
\ No newline at end of file
@@ -0,0 +1,116 @@
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use common::{kassert, util::Port};
+
+use super::input;
+use crate::interrupts::{self, CpuSnapshot, InterruptStackFrame};
+
+/// Input clock of all three channels
+pub const FREQUENCY: u32 = 1_193_182;
+
+/// Isa irq of channel 0, the MADT usually overrides it to another io apic pin
+pub const ISA_IRQ: u8 = 0;
+
+const CHANNEL0: Port<u8> = Port::new(0x40);
+const CHANNEL2: Port<u8> = Port::new(0x42);
+const COMMAND: Port<u8> = Port::new(0x43);
+// Bit 0 gates channel 2, bit 1 connects it to the speaker and bit 5 reads back its output
+const CHANNEL2_GATE: Port<u8> = Port::new(0x61);
+
+// Channel 0, low then high byte, mode 2 (rate generator), binary count
+const CHANNEL0_RATE_GENERATOR: u8 = 0x34;
+// Channel 2, low then high byte, mode 0 (interrupt on terminal count), binary count
+const CHANNEL2_ONE_SHOT: u8 = 0xB0;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// Io apic pin channel 0 is routed to, set by `set_irq` when the io apic is set up
+static IRQ: AtomicU8 = AtomicU8::new(ISA_IRQ);
+// Rate channel 0 was programmed for, 0 until init
+static HZ: AtomicU32 = AtomicU32::new(0);
+
+/// Programs channel 0 to interrupt `hz` times a second and unmasks its irq. The PIT can only
+/// divide its clock by a whole number, so the rate actually used is returned (see `divisor`).
+pub fn init(hz: u32) -> u32 {
+    let divisor = divisor(hz);
+    unsafe {
+        COMMAND.write(CHANNEL0_RATE_GENERATOR);
+        CHANNEL0.write(divisor as u8);
+        CHANNEL0.write((divisor >> 8) as u8);
+    }
+
+    let actual = rate(divisor);
+    HZ.store(actual, Ordering::SeqCst);
+    interrupts::unmask_irq(irq());
+    actual
+}
+
+/// Records the io apic pin `ISA_IRQ` arrives on, from the MADT
+pub fn set_irq(irq: u8) {
+    IRQ.store(irq, Ordering::SeqCst);
+}
+
+/// Io apic pin channel 0 interrupts on
+pub fn irq() -> u8 {
+    IRQ.load(Ordering::SeqCst)
+}
+
+/// Channel 0 reload value for `hz`, rounded to the nearest rate. Rates below what a 16 bit count
+/// allows (about 18Hz) get the slowest rate, 0 is treated as the fastest.
+pub fn divisor(hz: u32) -> u16 {
+    let hz = hz.max(1);
+    let divisor = (FREQUENCY + hz / 2) / hz;
+    match divisor {
+        0 | 1 => 1,
+        d if d > 0xFFFF => 0,
+        d => d as u16,
+    }
+}
+
+/// Interrupts per second with `divisor` as the reload value
+pub fn rate(divisor: u16) -> u32 {
+    // 0 counts down from 65536
+    match divisor {
+        0 => FREQUENCY / 0x10000,
+        d => FREQUENCY / d as u32,
+    }
+}
+
+/// Spins until channel 2 has counted down `ms` milliseconds. Channel 2 has no irq and nothing
+/// else uses it, so this works with interrupts off and before `init`, which is what calibrating
+/// other timers against it needs. A 16 bit count lasts at most 54ms.
+pub fn wait_ms(ms: u32) {
+    let count = FREQUENCY as u64 * ms as u64 / 1000;
+    kassert!(count <= 0xFFFF, "PIT wait longer than one count!");
+
+    unsafe {
+        // Gate channel 2 on, speaker off
+        let gate = CHANNEL2_GATE.read();
+        CHANNEL2_GATE.write((gate & !0x02) | 0x01);
+
+        COMMAND.write(CHANNEL2_ONE_SHOT);
+        CHANNEL2.write(count as u8);
+        CHANNEL2.write((count >> 8) as u8);
+
+        // The output goes high once the count reaches zero
+        while CHANNEL2_GATE.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        CHANNEL2_GATE.write(gate);
+    }
+}
+
+/// Timer interrupts since `init`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Interrupts per second, 0 before `init`
+pub fn frequency() -> u32 {
+    HZ.load(Ordering::SeqCst)
+}
+
+/// Irq 0 handler
+pub fn tick_handler(_frame: &mut InterruptStackFrame, _snapshot: &CpuSnapshot) {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    input::flush_serial();
+}
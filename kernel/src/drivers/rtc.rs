@@ -0,0 +1,112 @@
+use common::{efi::Time, util::Port};
+
+const ADDRESS: Port<u8> = Port::new(0x70);
+const DATA: Port<u8> = Port::new(0x71);
+
+// Clock registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+// Where practically every board keeps it, the FADT can name another register but never does
+const CENTURY: u8 = 0x32;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+// Status A, set while the clock is being updated and the registers can't be trusted
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+// Status B
+const HOURS_24: u8 = 0x02;
+const BINARY: u8 = 0x04;
+// Set in the hours register for pm on a 12 hour clock
+const PM: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read(register: u8) -> u8 {
+    unsafe {
+        // Bit 7 of the address disables NMIs, keep it clear
+        ADDRESS.write(register & 0x7F);
+        DATA.read()
+    }
+}
+
+fn read_registers() -> Registers {
+    while read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    Registers {
+        second: read(SECONDS),
+        minute: read(MINUTES),
+        hour: read(HOURS),
+        day: read(DAY),
+        month: read(MONTH),
+        year: read(YEAR),
+        century: read(CENTURY),
+    }
+}
+
+/// Two packed BCD digits as a number, 0x59 is 59
+pub fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Reads the real time clock, assuming it's kept in UTC. Works after boot services have exited,
+/// unlike GetTime. The RTC doesn't count anything finer than seconds.
+pub fn now() -> Time {
+    // An update can still start between the flag being checked and the registers being read, so
+    // read until two reads in a row agree
+    let mut registers = read_registers();
+    loop {
+        let again = read_registers();
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+
+    let status = read(STATUS_B);
+    let decode = |value: u8| {
+        if status & BINARY != 0 {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    };
+
+    let mut hour = decode(registers.hour & !PM);
+    if status & HOURS_24 == 0 {
+        // 12 is midnight or noon on a 12 hour clock
+        hour %= 12;
+        if registers.hour & PM != 0 {
+            hour += 12;
+        }
+    }
+
+    // Boards without a century register read back garbage there
+    let century = match decode(registers.century) {
+        century if (19..=21).contains(&century) => century as u16,
+        _ => 20,
+    };
+
+    let mut time = Time::default();
+    time.year = century * 100 + decode(registers.year) as u16;
+    time.month = decode(registers.month);
+    time.day = decode(registers.day);
+    time.hour = hour;
+    time.minute = decode(registers.minute);
+    time.second = decode(registers.second);
+    time
+}
@@ -0,0 +1,89 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use alloc::{boxed::Box, sync::Arc, task::Wake, vec::Vec};
+use common::x86_64::instructions::interrupts;
+use spin::Mutex;
+
+use crate::softirq;
+
+// Set by any wake, lets the run loop halt without scanning every task
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+// Spawned but not yet picked up by `run`, so tasks can spawn more tasks while being polled
+static SPAWNED: Mutex<Vec<Task>> = Mutex::new(Vec::new());
+
+// Wakes only set flags so they are safe from irq handlers
+struct TaskWaker {
+    ready: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.ready.store(true, Ordering::SeqCst);
+        WOKEN.store(true, Ordering::SeqCst);
+    }
+}
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    waker: Arc<TaskWaker>,
+}
+
+/// Queues `future` to be polled by `run`. New tasks are polled once before waiting on a wake.
+pub fn spawn(future: impl Future<Output = ()> + 'static) {
+    let task = Task {
+        future: Box::pin(future),
+        waker: Arc::new(TaskWaker {
+            ready: AtomicBool::new(true),
+        }),
+    };
+    interrupts::without_interrupts(|| SPAWNED.lock().push(task));
+    WOKEN.store(true, Ordering::SeqCst);
+}
+
+/// Polls woken tasks until every one of them is pending, then halts until an interrupt wakes one.
+/// Finished tasks are dropped, with none left this is the plain idle loop. Queued softirq work runs
+/// between passes.
+pub fn run() -> ! {
+    let mut tasks: Vec<Task> = Vec::new();
+
+    loop {
+        WOKEN.store(false, Ordering::SeqCst);
+        tasks.append(&mut interrupts::without_interrupts(|| {
+            core::mem::take(&mut *SPAWNED.lock())
+        }));
+
+        let mut i = 0;
+        while i < tasks.len() {
+            let task = &mut tasks[i];
+            if task.waker.ready.swap(false, Ordering::SeqCst) {
+                let waker = Waker::from(task.waker.clone());
+                let mut cx = Context::from_waker(&waker);
+                if let Poll::Ready(()) = task.future.as_mut().poll(&mut cx) {
+                    tasks.swap_remove(i);
+                    continue;
+                }
+            }
+            i += 1;
+        }
+
+        softirq::run();
+
+        // Same as softirq::idle, a wake right before the halt has to stop it
+        interrupts::disable();
+        if WOKEN.load(Ordering::SeqCst) || softirq::pending() {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
@@ -1,41 +1,77 @@
 use alloc::vec::Vec;
 use bit_field::BitField;
 use common::{
-    kprint, kprintln,
+    kassert, kprint, kprintln,
     util::{in8, out8},
-    x86_64::PhysAddr,
+    x86_64::{PhysAddr, VirtAddr},
+};
+use core::{
+    arch::asm,
+    borrow::Borrow,
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
 };
-use core::{arch::asm, borrow::Borrow};
 use macros::{generate_isrs, set_isrs};
 
 use crate::{
-    acpi::{
-        get_xsdt,
-        madt::{self, Entry},
-        Signature, RSDP,
-    },
-    drivers::keyboard::Keyboard,
+    acpi::{get_xsdt, madt, Rsdp, Signature},
+    drivers::{input, keyboard::Keyboard, lapic, pit},
     gdt,
 };
 
 use common::process::{self, SYSCALL_SP, SYSCALL_UMAP, SYSCALL_USP};
-use common::util;
-use common::x86_64::{
-    registers::model_specific::Msr,
-    structures::idt::{self, InterruptDescriptorTable},
-};
+use common::x86_64::structures::idt::{self, InterruptDescriptorTable};
+use common::{serial, util};
 use lazy_static::lazy_static;
 
-const IA32_APIC_BASE: u32 = 0x1b;
-
 use spin;
 
-pub static APIC: spin::Mutex<LocalApic> = spin::Mutex::new(LocalApic::new());
 pub static IOAPIC: spin::Mutex<IOApic> = spin::Mutex::new(IOApic::new());
+pub static PIC: spin::Mutex<Pic> = spin::Mutex::new(Pic);
+
+// Set once irqs are routed through the io apic instead of the PIC
+static IOAPIC_ACTIVE: AtomicBool = AtomicBool::new(false);
 static mut HANDLERS: [Vec<fn(&mut InterruptStackFrame, &CpuSnapshot)>; 256 - 32] =
     [const { Vec::new() }; 256 - 32];
 
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF0;
+// Irq 1 is routed here by the io apic
+pub const KEYBOARD_VECTOR: u8 = 0x45;
+// Periodic local apic timer, drives the scheduler and wakes sleepers
+pub const TIMER_VECTOR: u8 = 0x3C;
+pub const TIMER_HZ: u32 = 100;
+// Irq 4 (COM1 receive) is routed here by the io apic, next to the keyboard's. The EOI is sent by
+// the isr stub to the local apic, the PIC is masked once the io apic is up.
+pub const SERIAL_VECTOR: u8 = 0x44;
+// PIT channel 0, irq 0. Masked until `pit::init` programs a rate.
+pub const PIT_VECTOR: u8 = 0x40;
+
+// int3, the only vector that runs with interrupts enabled
+const BREAKPOINT_VECTOR: u8 = 3;
+
+/// Whether the cpu clears IF when entering a handler
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GateType {
+    /// Interrupts stay disabled until the handler returns. Use for hardware irqs and ipis.
+    Interrupt,
+    /// Interrupts stay enabled so the handler can be preempted. Only for vectors raised with `int`
+    /// by code that can tolerate it, the stubs save the interrupted stack in a single global.
+    Trap,
+}
+
+// Breakpoints taken, lets the selftest see int3 come back
+pub static BREAKPOINTS: AtomicUsize = AtomicUsize::new(0);
+
+// Number of cpus that can ack a shootdown, each one counts itself in `init`. The aps aren't
+// started yet so for now this is only the bsp and a shootdown returns without sending anything
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+static SHOOTDOWN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+static SHOOTDOWN_ADDRESS: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct CpuSnapshot {
     pub rbp: u64,
 
@@ -59,13 +95,13 @@ pub struct CpuSnapshot {
 
 pub use idt::InterruptStackFrame;
 
+// Leaves IF alone, the gate type or SFMASK decides whether interrupts are off
 #[macro_export]
 macro_rules! interrupt_begin {
     () => {
         unsafe {
         asm!(
         "
-        cli
         push rax
         push rbx
         push rcx
@@ -155,7 +191,10 @@ generate_isrs!();
 lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // Gate types come from `gate_type`, they're fixed once the IDT is loaded
+        idt.breakpoint
+            .set_handler_fn(breakpoint_handler)
+            .disable_interrupts(gate_type(BREAKPOINT_VECTOR) == GateType::Interrupt);
         idt.general_protection_fault
             .set_handler_fn(general_protection_handler);
         idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
@@ -174,7 +213,7 @@ lazy_static! {
         /* APIC Stuff */
         unsafe {
             set_isrs!(idt);
-            idt[0xFF].set_handler_fn(lapic_spurious);
+            idt[lapic::SPURIOUS_VECTOR as usize].set_handler_fn(lapic_spurious);
         }
         idt
     };
@@ -185,15 +224,72 @@ pub fn init() {
 
     IDT.load();
 
-    APIC.lock().init();
+    lapic::init();
+    lapic::start_periodic_timer(TIMER_VECTOR, TIMER_HZ);
+    kprintln!("APIC timer: {} ticks/ms", lapic::ticks_per_ms());
     IOAPIC.lock().init();
 
-    unsafe {
-        out8(0x21, 0xFF);
-        out8(0xA1, 0xFF);
+    register_handler(TLB_SHOOTDOWN_VECTOR, tlb_shootdown_handler);
+    common::mem::register_shootdown(tlb_shootdown);
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+
+    register_handler(PIT_VECTOR, pit::tick_handler);
+
+    if serial::is_present(input::COM1) {
+        register_handler(SERIAL_VECTOR, input::serial_handler);
+        serial::SerialPort::from(input::COM1).enable_interrupts();
+    }
+
+    mask_legacy_pic();
+}
+
+/// Masks every line on the legacy PIC and moves irq masking over to the io apic. After this irqs
+/// only arrive through the io apic and are acknowledged with `lapic::eoi`.
+pub fn mask_legacy_pic() {
+    PIC.lock().mask_all();
+    IOAPIC_ACTIVE.store(true, Ordering::SeqCst);
+}
+
+// Runs `f` on whichever controller is routing irqs
+fn with_irq_controller<R>(f: impl FnOnce(&mut dyn IrqController) -> R) -> R {
+    if IOAPIC_ACTIVE.load(Ordering::SeqCst) {
+        f(&mut *IOAPIC.lock())
+    } else {
+        f(&mut *PIC.lock())
+    }
+}
+
+/// Stops `irq` from being delivered until it's unmasked, on the PIC or the io apic
+pub fn mask_irq(irq: u8) {
+    with_irq_controller(|controller| controller.mask(irq));
+}
+
+pub fn unmask_irq(irq: u8) {
+    with_irq_controller(|controller| controller.unmask(irq));
+}
+
+pub fn is_irq_masked(irq: u8) -> bool {
+    with_irq_controller(|controller| controller.is_masked(irq))
+}
+
+/// Apic id of the cpu this runs on
+pub fn current_cpu() -> usize {
+    (unsafe { core::arch::x86_64::__cpuid(1) }.ebx >> 24) as usize
+}
+
+/// Gate type the IDT is built with for `vector`:
+///   breakpoint       trap, doesn't touch shared state so it can be interrupted
+///   faults, nmi      interrupt, they run on the IST stack or can't be nested safely
+///   0x20..0xFF       interrupt, every irq and ipi, and the stubs switch to one shared stack
+pub fn gate_type(vector: u8) -> GateType {
+    match vector {
+        BREAKPOINT_VECTOR => GateType::Trap,
+        _ => GateType::Interrupt,
     }
 }
 
+/// Adds a handler for `vector`. It runs under the vector's `gate_type`, which is fixed when the
+/// IDT is built since that happens before anything registers.
 pub fn register_handler(vector: u8, handler: fn(&mut InterruptStackFrame, &CpuSnapshot)) {
     unsafe {
         HANDLERS[vector as usize - 32].push(handler);
@@ -208,7 +304,32 @@ fn interrupt(stack_frame: &mut idt::InterruptStackFrame, snapshot: &CpuSnapshot,
     }
 }
 
+fn tlb_shootdown(addr: VirtAddr) {
+    let others = ONLINE_CPUS.load(Ordering::SeqCst) - 1;
+    if others == 0 {
+        return;
+    }
+
+    // Only one shootdown can be in flight at a time
+    let _guard = SHOOTDOWN_LOCK.lock();
+    SHOOTDOWN_ADDRESS.store(addr.as_u64(), Ordering::SeqCst);
+    SHOOTDOWN_PENDING.store(others, Ordering::SeqCst);
+
+    lapic::send_ipi_others(TLB_SHOOTDOWN_VECTOR);
+
+    while SHOOTDOWN_PENDING.load(Ordering::SeqCst) > 0 {
+        unsafe { asm!("pause") }
+    }
+}
+
+fn tlb_shootdown_handler(_frame: &mut InterruptStackFrame, _snapshot: &CpuSnapshot) {
+    let addr = VirtAddr::new(SHOOTDOWN_ADDRESS.load(Ordering::SeqCst));
+    common::x86_64::instructions::tlb::flush(addr);
+    SHOOTDOWN_PENDING.fetch_sub(1, Ordering::SeqCst);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: idt::InterruptStackFrame) {
+    BREAKPOINTS.fetch_add(1, Ordering::SeqCst);
     kprintln!("EXCPETION: BREAKPOINT\n{:#?}\n", stack_frame);
 }
 
@@ -238,91 +359,157 @@ extern "x86-interrupt" fn double_fault_handler(
     loop {}
 }
 
-extern "x86-interrupt" fn pagefault_handler(
-    _stack_frame: idt::InterruptStackFrame,
-    _error_code: idt::PageFaultErrorCode,
-) {
-    use common::x86_64::registers::control::Cr2;
-
-    kprintln!(
-        "EXCPETION: PAGE FAULT\n{:#?}\n{:#?}\n",
-        _stack_frame,
-        _error_code
-    );
-    kprintln!("Address: {:?}\n", Cr2::read());
-
-    loop {}
+/// A page fault decoded from CR2 and the error code
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    pub address: VirtAddr,
+    /// The page was mapped and the access broke its protection, otherwise it wasn't mapped
+    pub present: bool,
+    pub write: bool,
+    /// Happened at cpl 3
+    pub user: bool,
+    pub instruction_fetch: bool,
+    /// A reserved bit was set in one of the page table entries, the tables are corrupt
+    pub reserved: bool,
 }
 
-extern "x86-interrupt" fn lapic_spurious(_stack_frame: idt::InterruptStackFrame) {
-    kprintln!("LAPIC Spurious");
-    APIC.lock().send_eoi();
+impl PageFault {
+    pub fn new(address: VirtAddr, error_code: idt::PageFaultErrorCode) -> PageFault {
+        use idt::PageFaultErrorCode as Code;
+
+        PageFault {
+            address,
+            present: error_code.contains(Code::PROTECTION_VIOLATION),
+            write: error_code.contains(Code::CAUSED_BY_WRITE),
+            user: error_code.contains(Code::USER_MODE),
+            instruction_fetch: error_code.contains(Code::INSTRUCTION_FETCH),
+            reserved: error_code.contains(Code::MALFORMED_TABLE),
+        }
+    }
 }
 
-pub struct LocalApic {
-    msr: Msr,
-    base: u64,
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = if self.instruction_fetch {
+            "instruction fetch from"
+        } else if self.write {
+            "write to"
+        } else {
+            "read from"
+        };
+        let page = if self.present {
+            "protected"
+        } else {
+            "unmapped"
+        };
+        let mode = if self.user { "user" } else { "kernel" };
+        write!(
+            f,
+            "{} {} page at {:#x} in {} mode",
+            access,
+            page,
+            self.address.as_u64(),
+            mode
+        )?;
+        if self.reserved {
+            write!(f, ", reserved bit set in the page tables")?;
+        }
+        Ok(())
+    }
 }
 
-impl LocalApic {
-    pub const fn new() -> LocalApic {
-        let msr = Msr::new(IA32_APIC_BASE);
+// Given every page fault the kernel doesn't handle itself, returns true if it resolved the fault
+// and the access should be retried
+static mut PAGE_FAULT_HANDLER: Option<fn(&PageFault) -> bool> = None;
 
-        LocalApic {
-            msr,
-            base: 0xFEE00000,
-        }
+/// Lets something like a demand pager resolve page faults. Only one handler is kept, it's tried
+/// after heap faults and before the fault is treated as fatal.
+pub fn register_page_fault_handler(handler: fn(&PageFault) -> bool) {
+    unsafe {
+        PAGE_FAULT_HANDLER.replace(handler);
     }
+}
 
-    pub fn init(&mut self) {
-        let value = unsafe { self.msr.read() };
-        self.base = value & 0xFFFFFF000;
+// Vector 14, pushes an error code so it has to be set through `idt.page_fault` to get the right
+// handler signature
+extern "x86-interrupt" fn pagefault_handler(
+    stack_frame: idt::InterruptStackFrame,
+    error_code: idt::PageFaultErrorCode,
+) {
+    use common::x86_64::registers::control::Cr2;
 
-        self.write(LocalApic::SIV, self.read(LocalApic::SIV) | 0x1FF);
+    let fault = PageFault::new(Cr2::read(), error_code);
 
-        self.write(LocalApic::LVT_TIMER, 60 | LocalApic::TIMER_PERIODIC);
-        self.write(LocalApic::DCR_TIMER, 3);
+    if !fault.present && common::allocator::handle_heap_fault(fault.address) {
+        return;
+    }
+    if let Some(handler) = unsafe { PAGE_FAULT_HANDLER } {
+        if handler(&fault) {
+            return;
+        }
+    }
 
-        self.write(LocalApic::INITCNT_TIMER, 1000000);
+    if common::mem::is_stack_guard(fault.address) {
+        kprintln!("EXCEPTION: STACK OVERFLOW, hit the guard page below the process stack");
     }
+    kprintln!("EXCPETION: PAGE FAULT, {}", fault);
+    kprintln!("{:#?}\n{:#?}\n", stack_frame, error_code);
+
+    loop {
+        common::x86_64::instructions::hlt();
+    }
+}
 
-    const ID: u16 = 0x20;
-    const VERSION: u16 = 0x30;
-    const TPR: u16 = 0x80;
-    const APR: u16 = 0x90;
-    const PPR: u16 = 0xA0;
-    const EOI: u16 = 0xB0;
-    const RRD: u16 = 0xC0;
-    const LDR: u16 = 0xD0;
-    const DFR: u16 = 0xE0;
-    const SIV: u16 = 0xF0;
+extern "x86-interrupt" fn lapic_spurious(_stack_frame: idt::InterruptStackFrame) {
+    kprintln!("LAPIC Spurious");
+    lapic::eoi();
+}
 
-    const ERROR_STATUS: u16 = 0x280;
+/// An interrupt controller that can mask its irq lines one at a time
+pub trait IrqController {
+    fn mask(&mut self, irq: u8);
+    fn unmask(&mut self, irq: u8);
+    fn is_masked(&self, irq: u8) -> bool;
+}
 
-    const LVT_TIMER: u16 = 0x320;
-    const LVT_THERMAL: u16 = 0x330;
-    const LVT_PMC: u16 = 0x340;
-    const LVT_LINT0: u16 = 0x350;
-    const LVT_LINT1: u16 = 0x360;
-    const LVT_ERROR: u16 = 0x370;
-    const INITCNT_TIMER: u16 = 0x380;
-    const CURCNT_TIMER: u16 = 0x390;
-    const DCR_TIMER: u16 = 0x3E0;
+/// The legacy 8259 pair, irqs 0-7 on the master and 8-15 on the slave
+pub struct Pic;
 
-    const TIMER_PERIODIC: u32 = 0x20000;
+impl Pic {
+    const MASTER_DATA: u16 = 0x21;
+    const SLAVE_DATA: u16 = 0xA1;
 
-    fn write(&mut self, offset: u16, value: u32) {
+    // Data port holding the mask for `irq` and its bit in it
+    fn line(irq: u8) -> (u16, usize) {
+        if irq < 8 {
+            (Pic::MASTER_DATA, irq as usize)
+        } else {
+            (Pic::SLAVE_DATA, irq as usize - 8)
+        }
+    }
+
+    pub fn mask_all(&mut self) {
         unsafe {
-            core::ptr::write_volatile((self.base + offset as u64) as *mut _, value);
+            out8(Pic::MASTER_DATA, 0xFF);
+            out8(Pic::SLAVE_DATA, 0xFF);
         }
     }
+}
+
+impl IrqController for Pic {
+    fn mask(&mut self, irq: u8) {
+        let (port, bit) = Pic::line(irq);
+        unsafe { out8(port, *in8(port).set_bit(bit, true)) }
+    }
 
-    fn read(&self, offset: u16) -> u32 {
-        unsafe { core::ptr::read_volatile((self.base + offset as u64) as *mut _) }
+    fn unmask(&mut self, irq: u8) {
+        let (port, bit) = Pic::line(irq);
+        unsafe { out8(port, *in8(port).set_bit(bit, false)) }
     }
 
-    pub fn send_eoi(&mut self) {
-        self.write(LocalApic::EOI, 0);
+    fn is_masked(&self, irq: u8) -> bool {
+        let (port, bit) = Pic::line(irq);
+        unsafe { in8(port).get_bit(bit) }
     }
 }
 
@@ -391,6 +578,11 @@ impl RedirectionEntry {
         self.set_mask(0);
     }
 
+    #[inline]
+    pub fn is_masked(&self) -> bool {
+        self.low.get_bit(16)
+    }
+
     #[inline]
     pub fn get_low(&self) -> u32 {
         self.low
@@ -419,34 +611,38 @@ impl IOApic {
     pub fn init(&mut self) {
         let xsdt = get_xsdt();
         let madt = xsdt
-            .iter()
-            .find(|table| table.signature == Signature::MADT.as_bytes())
-            .expect("Unable to get MADT!");
-
-        let ioapic = madt
-            .get_entry::<madt::MADT>()
-            .iter()
-            .find(|e| {
-                if let Entry::IoApic { .. } = e {
-                    true
-                } else {
-                    false
-                }
-            })
-            .expect("Unable to find Local Apic in madt!");
-
-        match ioapic {
-            Entry::IoApic {
-                io_apic_address, ..
-            } => self.base = *io_apic_address as u64,
-            _ => (),
-        }
+            .find(Signature::MADT)
+            .expect("Unable to get MADT!")
+            .get_entry::<madt::MADT>();
+
+        self.base = madt
+            .io_apic_address()
+            .expect("Unable to find Io Apic in madt!");
 
-        common::mem::map_phys(PhysAddr::new(self.base), 4096);
+        common::mem::map_mmio(PhysAddr::new(self.base), 4096)
+            .expect("Unable to map io apic registers!");
+        kassert!(
+            common::mem::is_uncacheable(VirtAddr::new(self.base)),
+            "Io apic registers are cacheable!"
+        );
 
         let mut re = RedirectionEntry::new();
-        re.set_vector(0x45);
+        re.set_vector(KEYBOARD_VECTOR);
         self.write_entry(1, &re);
+
+        let mut re = RedirectionEntry::new();
+        re.set_vector(SERIAL_VECTOR);
+        self.write_entry(4, &re);
+
+        // The override can also change the pin from isa's active high, edge triggered signalling
+        let route = madt.isa_route(pit::ISA_IRQ);
+        let mut re = RedirectionEntry::new();
+        re.set_vector(PIT_VECTOR);
+        re.set_polarity(route.active_low as u8);
+        re.set_trigger_mode(route.level_triggered as u8);
+        re.set_mask(1);
+        self.write_entry(route.global_system_interrupt as u8, &re);
+        pit::set_irq(route.global_system_interrupt as u8);
     }
 
     pub fn write(&mut self, offset: u16, value: u32) {
@@ -459,7 +655,7 @@ impl IOApic {
     pub fn read(&self, offset: u16) -> u32 {
         unsafe {
             core::ptr::write_volatile(self.base as *mut u32, offset as _); // IOREGSEL
-            core::ptr::read_volatile((self.base + 10) as *mut u32) // IOWIN
+            core::ptr::read_volatile((self.base + 0x10) as *mut u32) // IOWIN
         }
     }
 
@@ -468,4 +664,30 @@ impl IOApic {
         self.write(address, entry.get_low());
         self.write(address + 1, entry.get_high());
     }
+
+    pub fn read_entry(&self, irq: u8) -> RedirectionEntry {
+        let address = IOApic::RED_TABLE + (2 * irq as u16);
+        RedirectionEntry {
+            low: self.read(address),
+            high: self.read(address + 1),
+        }
+    }
+}
+
+impl IrqController for IOApic {
+    fn mask(&mut self, irq: u8) {
+        let mut entry = self.read_entry(irq);
+        entry.set_mask(1);
+        self.write_entry(irq, &entry);
+    }
+
+    fn unmask(&mut self, irq: u8) {
+        let mut entry = self.read_entry(irq);
+        entry.set_mask(0);
+        self.write_entry(irq, &entry);
+    }
+
+    fn is_masked(&self, irq: u8) -> bool {
+        self.read_entry(irq).is_masked()
+    }
 }
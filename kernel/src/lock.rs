@@ -0,0 +1,126 @@
+use core::ops::{Deref, DerefMut};
+
+use spin::{Mutex, MutexGuard};
+
+/// A spin lock with a place in the global lock order. Locks have to be taken in increasing rank,
+/// taking a lock while holding one of the same or higher rank panics with both names. The
+/// checking is compiled out with the `release` feature.
+pub struct OrderedMutex<T> {
+    name: &'static str,
+    rank: u8,
+    inner: Mutex<T>,
+}
+
+pub struct OrderedMutexGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    #[cfg(not(feature = "release"))]
+    rank: u8,
+}
+
+impl<T> OrderedMutex<T> {
+    pub const fn new(name: &'static str, rank: u8, value: T) -> OrderedMutex<T> {
+        OrderedMutex {
+            name,
+            rank,
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn rank(&self) -> u8 {
+        self.rank
+    }
+
+    pub fn lock(&self) -> OrderedMutexGuard<'_, T> {
+        #[cfg(not(feature = "release"))]
+        order::acquire(self.name, self.rank);
+
+        OrderedMutexGuard {
+            guard: self.inner.lock(),
+            #[cfg(not(feature = "release"))]
+            rank: self.rank,
+        }
+    }
+}
+
+impl<'a, T> Deref for OrderedMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for OrderedMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(not(feature = "release"))]
+impl<'a, T> Drop for OrderedMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        order::release(self.rank);
+    }
+}
+
+#[cfg(not(feature = "release"))]
+mod order {
+    use core::arch::x86_64::__cpuid;
+
+    use common::x86_64::instructions::interrupts;
+
+    const MAX_CPUS: usize = 64;
+    const MAX_HELD: usize = 16;
+
+    #[derive(Clone, Copy)]
+    struct Held {
+        locks: [(u8, &'static str); MAX_HELD],
+        len: usize,
+    }
+
+    // Locks held by each cpu, indexed by apic id
+    static mut HELD: [Held; MAX_CPUS] = [Held {
+        locks: [(0, ""); MAX_HELD],
+        len: 0,
+    }; MAX_CPUS];
+
+    fn held() -> &'static mut Held {
+        let id = (unsafe { __cpuid(1) }.ebx >> 24) as usize;
+        unsafe { &mut HELD[id % MAX_CPUS] }
+    }
+
+    pub fn acquire(name: &'static str, rank: u8) {
+        interrupts::without_interrupts(|| {
+            let held = held();
+            if let Some((held_rank, held_name)) =
+                held.locks[..held.len].iter().find(|(r, _)| *r >= rank)
+            {
+                panic!(
+                    "Lock order violation! Taking {} (rank {}) while holding {} (rank {})",
+                    name, rank, held_name, held_rank
+                );
+            }
+
+            if held.len == MAX_HELD {
+                panic!("Too many locks held to track {}!", name);
+            }
+            held.locks[held.len] = (rank, name);
+            held.len += 1;
+        });
+    }
+
+    // Guards don't have to be dropped in order, so remove the most recent lock with this rank
+    pub fn release(rank: u8) {
+        interrupts::without_interrupts(|| {
+            let held = held();
+            if let Some(index) = held.locks[..held.len].iter().rposition(|(r, _)| *r == rank) {
+                held.locks.copy_within(index + 1..held.len, index);
+                held.len -= 1;
+            }
+        });
+    }
+}
@@ -13,17 +13,27 @@
 extern crate alloc;
 
 mod acpi;
+mod console;
 mod drivers;
+mod executor;
 mod interrupts;
+mod lock;
 mod process_manager;
+#[cfg(feature = "selftest")]
+mod selftest;
+mod softirq;
 mod syscall;
+mod time;
 
 use core::arch::{asm, x86_64};
 use core::panic::PanicInfo;
 
 use boot_fs::BootImageFS;
-use common::memory_regions::PAGE_TABLE_OFFSET;
-use common::serial::SerialPort;
+use common::boot::{self, Phase};
+use common::cmdline::CommandLine;
+use common::memory_regions::PHYS_OFFSET;
+use common::output::{self, OutputTarget};
+use common::serial;
 use macros::wchar;
 
 use common::x86_64::registers::control::{Cr3, Cr3Flags};
@@ -32,9 +42,16 @@ use common::x86_64::structures::paging::{
     Size4KiB, Translate,
 };
 use common::x86_64::{PhysAddr, VirtAddr};
-use common::{allocator, efi, elf, gdt, kprint, kprintln, mem, process, size_gb, KernelParameters};
+use common::{
+    allocator, efi, elf, gdt, kassert, kprint, kprintln, mem, process, size_gb, size_mb,
+    KernelParameters,
+};
 
-use crate::drivers::pci;
+use crate::drivers::{
+    fbcon,
+    keyboard::{Key, KeyEvent, Keyboard},
+    pci, pit,
+};
 use crate::process_manager::ManagedProcess;
 use common::efi::{
     get_system_table, guid, FileHandle, FileInfo, FileProtocol, FILE_HIDDEN, FILE_MODE_READ,
@@ -42,16 +59,19 @@ use common::efi::{
 };
 
 const BOOT_IMAGE: u64 = size_gb!(100);
+#[cfg(feature = "demo")]
+const DEMO_PROCESSES: usize = 3;
 
 #[no_mangle]
 pub extern "C" fn _start(parameters: &'static mut KernelParameters) -> ! {
     // kprintln!("Kernel... {:p}", parameters.system_table);
 
-    use core::fmt;
-    let mut serial = SerialPort::from(0x3F8);
-    fmt::write(&mut serial, format_args!("Kernel.. {:p}", parameters.system_table)).expect("Unable to print!");
-    fmt::write(&mut serial, format_args!("\r\n")).expect("Unable to print!");
+    serial::probe(0x3F8);
+    kprintln!("Kernel.. {:p}", parameters.system_table);
 
+    boot::phase(Phase::Kernel);
+
+    boot::phase(Phase::Heap);
     allocator::init_heap(&parameters.heap);
 
     unsafe {
@@ -59,16 +79,104 @@ pub extern "C" fn _start(parameters: &'static mut KernelParameters) -> ! {
         efi::register_global_system_table(parameters.system_table).unwrap();
     }
 
-    let wait = false;
+    if let Some(time) = parameters.boot_time {
+        efi::set_boot_time(time);
+    }
+
+    let command_line = CommandLine::new(parameters.command_line);
+    kprintln!("Command line: {}", command_line.as_str());
+    if let Some(level) = command_line.get("loglevel") {
+        kprintln!("Log level: {}", level);
+    }
+    // COM1 runs at whatever the firmware set up unless asked otherwise
+    if let Some(baud) = command_line.get("baud").and_then(|b| b.parse::<u32>().ok()) {
+        serial::SerialPort::with_baud(0x3F8, baud);
+        kprintln!("Serial: {} baud", serial::SerialPort::actual_baud(baud));
+    }
+    // Keeps crash output off COM1 so it doesn't interleave with the logs
+    if command_line.get("panicport") == Some("com2") {
+        serial::SerialPort::new(output::COM2);
+        if serial::probe(output::COM2) {
+            output::set_panic_port(output::COM2);
+            kprintln!("Panics go to COM2");
+        } else {
+            kprintln!("No UART on COM2, panics stay on COM1");
+        }
+    }
+
+    // Spins until a debugger clears it
+    let wait = command_line.flag("debugwait");
     while core::convert::identity(wait) {
         unsafe { asm!("pause") }
     }
 
     // let frame_allocator = mem::PageTableFrameAllocator::new(parameters.memory_map);
-    let mut mapper = unsafe { mem::init(parameters.frame_allocator.clone(), PAGE_TABLE_OFFSET) };
+    boot::phase(Phase::Memory);
+    let mut mapper = unsafe { mem::init(parameters.frame_allocator.clone(), PHYS_OFFSET) };
     mem::allocator().lock().swap_map(parameters.memory_map);
+    kassert!(
+        Cr3::read().0 == mem::kernel_map(),
+        "Kernel map doesn't match CR3!"
+    );
+    if !mem::enable_global_pages() {
+        kprintln!("Global pages aren't supported, kernel TLB entries are flushed on every switch");
+    }
 
     let mem_size = efi::get_mem_size(parameters.memory_map);
+    let regions = mem::coalesce_map(parameters.memory_map);
+    let largest = regions
+        .iter()
+        .filter(|r| r.memory_type.is_usable())
+        .map(|r| r.size)
+        .max()
+        .unwrap_or(0);
+    kprintln!(
+        "Memory: {} regions, largest usable {:x} bytes",
+        regions.len(),
+        largest
+    );
+    {
+        let allocator = mem::allocator().lock();
+        kprintln!(
+            "Memory: {} usable frames ({:x} bytes), highest usable address {:?}",
+            allocator.usable_frame_count(),
+            allocator.total_usable_bytes(),
+            allocator.highest_usable_address()
+        );
+        let stats = allocator.stats();
+        kprintln!(
+            "Memory: {:x} bytes total, {:x} allocated, {:x} free",
+            stats.total_bytes,
+            stats.allocated_bytes,
+            stats.free_bytes()
+        );
+        if let Some(bitmap) = allocator.bitmap() {
+            kprintln!(
+                "Memory: bitmap frame allocator, {} of {} frames free",
+                bitmap.free_frames(),
+                bitmap.frame_count()
+            );
+        }
+    }
+    if let Some(framebuffer) = parameters.framebuffer.take() {
+        mem::map_mmio(PhysAddr::new(framebuffer.base() as u64), framebuffer.size())
+            .expect("Unable to map framebuffer!");
+        kprintln!(
+            "Framebuffer: {}x{}, {} pixels per scanline",
+            framebuffer.width(),
+            framebuffer.height(),
+            framebuffer.stride()
+        );
+        fbcon::init(framebuffer);
+
+        // Kernel output stays on serial unless asked for, drawing every line is slow
+        match command_line.get("console") {
+            Some("fb") => output::set_target(OutputTarget::Framebuffer),
+            Some("both") => output::set_target(OutputTarget::Both),
+            _ => (),
+        }
+        fbprintln!("RustKernel");
+    }
     // unsafe {
     //     mem::KERNEL_MAP = table as u64;
     // }
@@ -77,13 +185,52 @@ pub extern "C" fn _start(parameters: &'static mut KernelParameters) -> ! {
     // allocator::init_heap_new(&mut mapper, &mut frame_allocator, parameters.heap_top, false).expect("Unable to create heap!");
 
     // acpi::init(parameters.memory_map);
+    boot::phase(Phase::Acpi);
     acpi::init(parameters.memory_map);
+    time::init();
 
     // Setup interrupts
+    boot::phase(Phase::Idt);
     interrupts::init();
 
+    // The rest of the heap's reservation is backed on demand now that page faults are handled,
+    // `heapcommit` caps how much of it (in MiB) can be backed
+    let commit_limit = command_line
+        .get("heapcommit")
+        .and_then(|mb| mb.parse::<usize>().ok())
+        .map_or(usize::MAX, |mb| size_mb!(mb));
+    let lazy = allocator::init_heap_lazy(commit_limit);
+    kprintln!(
+        "Heap: {:x} bytes mapped, {:x} more on demand",
+        allocator::stats().size - lazy,
+        lazy
+    );
+    let hz = pit::init(100);
+    kprintln!("PIT: {}Hz", hz);
+    time::init_sleep();
+    Keyboard::init();
+
+    #[cfg(feature = "selftest")]
+    {
+        use common::util;
+
+        let failed = selftest::run_all();
+        let code = if failed == 0 {
+            util::QEMU_EXIT_SUCCESS
+        } else {
+            util::QEMU_EXIT_FAILURE
+        };
+        kprintln!("selftest: exit status {:#x}", code);
+        util::qemu_exit(code);
+        // Not running under qemu
+        acpi::reset();
+    }
+
+    boot::phase(Phase::Drivers);
     pci::init();
     acpi::aml::init();
+    acpi::parsing_complete();
+    acpi::reclaim(parameters.memory_map, mem::allocator().get_mut());
     //pci::gather_devices();
 
     // interrupts::enable_apic();
@@ -116,7 +263,7 @@ pub extern "C" fn _start(parameters: &'static mut KernelParameters) -> ! {
         );
         unsafe {
             <OffsetPageTable as Mapper<Size2MiB>>::map_to(
-                &mut mem::active_offset_page_table(PAGE_TABLE_OFFSET),
+                &mut mem::active_offset_page_table(PHYS_OFFSET),
                 page,
                 frame,
                 PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
@@ -150,23 +297,47 @@ pub extern "C" fn _start(parameters: &'static mut KernelParameters) -> ! {
     let driver_exec_file = elf::ElfFile::new(image.file_data(driver));
     let ddate = driver_exec_file.data;
 
+    boot::phase(Phase::Processes);
     let new_process =
-        ManagedProcess::new_kernel_process(&driver_exec_file, &kernel_exec_file, 0, 0, mem_size);
+        ManagedProcess::new_kernel_process(
+            driver.name(),
+            &driver_exec_file,
+            &kernel_exec_file,
+            0,
+            0,
+            mem_size,
+        );
 
     // unsafe {
     //     processes::jump_usermode(&mapper, &new_process);
     // }
 
+    #[cfg(feature = "demo")]
+    process_manager::spawn_demo(&driver_exec_file, &kernel_exec_file, mem_size, DEMO_PROCESSES);
+
+    boot::phase(Phase::Usermode);
     process_manager::init();
 
     common::x86_64::instructions::interrupts::enable();
 
     kprintln!("Done!");
-    loop {}
+    executor::spawn(echo_keys());
+    executor::run();
+}
+
+// Prints typed characters back out
+async fn echo_keys() {
+    loop {
+        if let KeyEvent::Pressed(Key::Char(c)) = Keyboard::next_key().await {
+            kprint!("{}", c);
+        }
+    }
 }
 
 #[panic_handler]
 fn panic_handler(_info: &PanicInfo) -> ! {
-    kprintln!("PANIC! {}\n", _info);
+    let port = output::panic_port();
+    common::kprintln_to!(port, "PANIC! {}\n", _info);
+    common::kprintln_to!(port, "Last boot phase: {:?}", boot::current_phase());
     loop {}
 }
@@ -1,26 +1,45 @@
-use core::arch::asm;
+use core::{
+    arch::x86_64::_rdtsc,
+    mem::size_of,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use crate::interrupts;
+use crate::{
+    interrupts::{self, CpuSnapshot, InterruptStackFrame},
+    softirq,
+};
 use alloc::{collections::LinkedList, vec::Vec};
 use bitflags::bitflags;
 use common::{
-    elf, kprintln,
-    process::Process,
+    elf, kprintln, mem,
+    memory_regions::PHYS_OFFSET,
+    process::{self, Pid, Process},
     x86_64::{
+        instructions::interrupts as cpu_interrupts,
         registers::control::{Cr3, Cr3Flags},
         structures::paging::{Mapper, OffsetPageTable, PageTable, PhysFrame, Size4KiB, Translate},
         VirtAddr,
     },
 };
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum State {
     Ready,
     Blocked,
     Running,
+    Exited,
 }
 
-static mut PROCESSES: Vec<ManagedProcess> = Vec::new();
-static mut NEXT_PROCESS: usize = 0;
+// Interrupts enabled with nothing else set
+const INITIAL_RFLAGS: u64 = 0x202;
+// Exit codes kept for wait, nothing collects them yet so only the most recent ones are
+const MAX_EXIT_CODES: usize = 64;
+
+static mut SCHEDULER: Scheduler = Scheduler::new();
+
+static SWITCHED_AT: AtomicU64 = AtomicU64::new(0);
+static TOTAL_CYCLES: AtomicU64 = AtomicU64::new(0);
+static IDLE_CYCLES: AtomicU64 = AtomicU64::new(0);
 
 bitflags! {
     struct ProcessFlags: u32 {
@@ -32,10 +51,15 @@ pub struct ManagedProcess {
     process: Process,
     state: State,
     flags: ProcessFlags,
+    // User stack pointer and registers to go back to when a signal handler returns
+    signal_frame: Option<(u64, interrupts::CpuSnapshot)>,
+    // Where the process was switched away from, None until it has run
+    context: Option<Context>,
 }
 
 impl ManagedProcess {
     pub fn new_kernel_process(
+        name: &str,
         elf: &elf::ElfFile<'_>,
         kernel: &elf::ElfFile<'_>,
         kernel_stack_start: u64,
@@ -43,58 +67,498 @@ impl ManagedProcess {
         mem_size: usize,
     ) -> ManagedProcess {
         let mut current_mapper =
-            common::mem::active_offset_page_table(common::memory_regions::PAGE_TABLE_OFFSET);
+            common::mem::active_offset_page_table(common::memory_regions::PHYS_OFFSET);
+        let mut managed = ManagedProcess::from_process(Process::from_elf(
+            name,
+            elf,
+            kernel,
+            kernel_stack_start,
+            kernel_stack_end,
+            mem_size,
+            &mut current_mapper,
+            common::mem::allocator().get_mut(),
+        ));
+        managed.flags = ProcessFlags::KERNEL;
+        managed
+    }
+
+    pub fn from_process(process: Process) -> ManagedProcess {
         ManagedProcess {
-            process: Process::from_elf(
-                elf,
-                kernel,
-                kernel_stack_start,
-                kernel_stack_end,
-                mem_size,
-                &mut current_mapper,
-                common::mem::allocator().get_mut(),
-            ),
+            process,
             state: State::Ready,
-            flags: ProcessFlags::KERNEL,
+            flags: ProcessFlags::empty(),
+            signal_frame: None,
+            context: None,
         }
     }
 
+    pub fn process(&self) -> &Process {
+        &self.process
+    }
+
+    pub fn process_mut(&mut self) -> &mut Process {
+        &mut self.process
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn in_signal_handler(&self) -> bool {
+        self.signal_frame.is_some()
+    }
+
+    pub fn save_signal_frame(&mut self, user_sp: u64, snapshot: interrupts::CpuSnapshot) {
+        self.signal_frame = Some((user_sp, snapshot));
+    }
+
+    pub fn take_signal_frame(&mut self) -> Option<(u64, interrupts::CpuSnapshot)> {
+        self.signal_frame.take()
+    }
+
     pub fn spawn(self) {
         unsafe {
-            PROCESSES.push(self);
+            SCHEDULER.add(self);
         }
     }
 
     pub fn load(&self) {
+        unsafe {
+            Cr3::write(self.page_table_frame(), Cr3Flags::empty());
+        }
+    }
+
+    fn page_table_frame(&self) -> PhysFrame {
         let ptr: *const PageTable = self.process.address_space.as_ref();
 
-        let frame = match <OffsetPageTable as Translate>::translate_addr(
-            &mut crate::mem::active_offset_page_table(common::memory_regions::PAGE_TABLE_OFFSET),
-            VirtAddr::new(ptr as u64),
-        ) {
+        match common::mem::virt_to_phys(VirtAddr::new(ptr as u64)) {
             Some(addr) => match PhysFrame::<Size4KiB>::from_start_address(addr) {
                 Err(_) => panic!("Unable to get frame! (1)"),
                 Ok(frame) => frame,
             },
             None => panic!("Unable to get frame! (2)"),
-        };
+        }
+    }
 
+    // What the first switch to the process resumes, the top of its stack at the entry point
+    fn initial_context(&self) -> Context {
+        Context {
+            registers: unsafe { core::mem::zeroed() },
+            rip: self.process.entry as u64,
+            rflags: INITIAL_RFLAGS,
+            rsp: self.process.stack_base as u64,
+            address_space: self.page_table_frame().start_address().as_u64(),
+        }
+    }
+}
+
+/// Registers of a stopped process and where to resume it
+#[derive(Clone, Copy)]
+pub struct Context {
+    registers: CpuSnapshot,
+    rip: u64,
+    rflags: u64,
+    // Stack pointer once the registers are popped off
+    rsp: u64,
+    // Physical address of the top level page table
+    address_space: u64,
+}
+
+impl Context {
+    /// What the timer irq interrupted. The stub left the registers at `SYSCALL_USP`.
+    pub fn from_interrupt(frame: &InterruptStackFrame, snapshot: &CpuSnapshot) -> Context {
+        Context {
+            registers: *snapshot,
+            rip: frame.instruction_pointer.as_u64(),
+            rflags: frame.cpu_flags,
+            rsp: frame.stack_pointer.as_u64(),
+            address_space: unsafe { process::SYSCALL_UMAP } & !0xFFF,
+        }
+    }
+
+    /// The process making a syscall. sysret returns to rcx with the flags from r11.
+    pub fn from_syscall(cpu: &CpuSnapshot) -> Context {
         unsafe {
-            Cr3::write(frame, Cr3Flags::empty());
+            Context {
+                registers: *cpu,
+                rip: cpu.rcx,
+                rflags: cpu.r11,
+                rsp: process::SYSCALL_USP + size_of::<CpuSnapshot>() as u64,
+                address_space: process::SYSCALL_UMAP & !0xFFF,
+            }
+        }
+    }
+
+    /// Points the irq stub at this context, it pops the registers and irets into it
+    pub unsafe fn resume_from_interrupt(&self, frame: &mut InterruptStackFrame) {
+        let registers = self.push_registers(self.registers);
+        frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(self.rip);
+            frame.cpu_flags = self.rflags;
+            frame.stack_pointer = VirtAddr::new(self.rsp);
+        });
+        process::SYSCALL_USP = registers;
+        process::SYSCALL_UMAP = self.address_space;
+    }
+
+    /// Points the syscall stub at this context. sysret takes the return address and flags from
+    /// rcx and r11, so whatever they held when the process was interrupted is lost.
+    pub unsafe fn resume_from_syscall(&self) {
+        let mut registers = self.registers;
+        registers.rcx = self.rip;
+        registers.r11 = self.rflags;
+        process::SYSCALL_USP = self.push_registers(registers);
+        process::SYSCALL_UMAP = self.address_space;
+    }
+
+    // Writes the registers just below the stack pointer for the stub to pop. The address space
+    // isn't loaded yet so every word goes through the physical mapping, the registers can
+    // straddle a page boundary.
+    unsafe fn push_registers(&self, registers: CpuSnapshot) -> u64 {
+        let at = self.rsp - size_of::<CpuSnapshot>() as u64;
+        let table = &mut *((PHYS_OFFSET + self.address_space) as *mut PageTable);
+        let mapper = OffsetPageTable::new(table, VirtAddr::new(PHYS_OFFSET));
+
+        let words = core::slice::from_raw_parts(
+            &registers as *const CpuSnapshot as *const u64,
+            size_of::<CpuSnapshot>() / size_of::<u64>(),
+        );
+        for (i, word) in words.iter().enumerate() {
+            let virt = VirtAddr::new(at + (i * size_of::<u64>()) as u64);
+            match mapper.translate_addr(virt) {
+                Some(phys) => *((PHYS_OFFSET + phys.as_u64()) as *mut u64) = *word,
+                None => panic!("Process stack not mapped at {:x}", virt.as_u64()),
+            }
+        }
+        at
+    }
+}
+
+/// Every live process kept in pid order, so lookups by pid are a binary search. Exit codes of
+/// removed processes are kept until they're waited on, or until `MAX_EXIT_CODES` later processes
+/// have exited.
+pub struct ProcessTable {
+    processes: Vec<ManagedProcess>,
+    exit_codes: Vec<(Pid, u64)>,
+}
+
+impl ProcessTable {
+    pub const fn new() -> ProcessTable {
+        ProcessTable {
+            processes: Vec::new(),
+            exit_codes: Vec::new(),
+        }
+    }
+
+    /// Adds `process`. Pids increase so this is normally a push, but a process can be spawned
+    /// after one created later.
+    pub fn insert(&mut self, process: ManagedProcess) {
+        let pid = process.process.pid();
+        let index = self.processes.partition_point(|p| p.process.pid() < pid);
+        self.processes.insert(index, process);
+    }
+
+    pub fn get(&self, pid: Pid) -> Option<&ManagedProcess> {
+        let index = self.index_of(pid)?;
+        Some(&self.processes[index])
+    }
+
+    pub fn get_mut(&mut self, pid: Pid) -> Option<&mut ManagedProcess> {
+        let index = self.index_of(pid)?;
+        Some(&mut self.processes[index])
+    }
+
+    /// Takes `pid` out of the table, recording `code` as how it exited
+    pub fn remove(&mut self, pid: Pid, code: u64) -> Option<ManagedProcess> {
+        let index = self.index_of(pid)?;
+        // Oldest first, since they're pushed in the order processes exit
+        if self.exit_codes.len() == MAX_EXIT_CODES {
+            self.exit_codes.remove(0);
+        }
+        self.exit_codes.push((pid, code));
+        Some(self.processes.remove(index))
+    }
+
+    /// How `pid` exited, None while it's still running or once the code has been taken
+    pub fn exit_code(&self, pid: Pid) -> Option<u64> {
+        let (_, code) = self.exit_codes.iter().find(|&&(id, _)| id == pid)?;
+        Some(*code)
+    }
+
+    /// Removes the exit code of `pid`, for wait
+    pub fn take_exit_code(&mut self, pid: Pid) -> Option<u64> {
+        let index = self.exit_codes.iter().position(|&(id, _)| id == pid)?;
+        Some(self.exit_codes.remove(index).1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.processes.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ManagedProcess> {
+        self.processes.iter()
+    }
+
+    fn index_of(&self, pid: Pid) -> Option<usize> {
+        self.processes
+            .binary_search_by_key(&pid, |p| p.process.pid())
+            .ok()
+    }
+}
+
+/// Round robin over every spawned process. Whatever was running when nothing could (the idle
+/// loop) is kept aside and resumed once every process has exited or can't run on the cpu.
+pub struct Scheduler {
+    table: ProcessTable,
+    // Table index the search for the next process starts at
+    next: usize,
+    // The running process, None when idle
+    current: Option<Pid>,
+    idle: Option<Context>,
+    // Removed from the table but maybe still on the cpu, see `reap`
+    exited: Vec<ManagedProcess>,
+}
+
+impl Scheduler {
+    pub const fn new() -> Scheduler {
+        Scheduler {
+            table: ProcessTable::new(),
+            next: 0,
+            current: None,
+            idle: None,
+            exited: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, process: ManagedProcess) {
+        self.table.insert(process);
+    }
+
+    /// The running process, None when idle
+    pub fn current(&self) -> Option<Pid> {
+        self.current
+    }
+
+    pub fn table(&self) -> &ProcessTable {
+        &self.table
+    }
+
+    /// Picks the next process after the last one picked that hasn't exited and can run on `cpu`.
+    /// None when there isn't one and the cpu should idle.
+    pub fn schedule(&mut self, cpu: usize) -> Option<Pid> {
+        let processes = &self.table.processes;
+        let count = processes.len();
+        let next = (0..count).map(|i| (self.next + i) % count).find(|&i| {
+            let process = &processes[i];
+            process.state != State::Exited && process.process.can_run_on(cpu)
+        });
+
+        if let Some(next) = next {
+            self.next = next + 1;
+        }
+        self.current = next.map(|index| processes[index].process.pid());
+        self.current
+    }
+
+    /// Saves `context` as where the running process (or the idle loop) stopped and picks the next
+    /// one. Returns the context to resume, None if the pick didn't change and `context` just
+    /// carries on.
+    pub fn switch(&mut self, cpu: usize, context: Context) -> Option<Context> {
+        let previous = self.current;
+        match previous.and_then(|pid| self.table.get_mut(pid)) {
+            Some(process) => process.context = Some(context),
+            None => self.idle = Some(context),
+        }
+
+        let next = self.schedule(cpu);
+        if next == previous {
+            return None;
         }
+        self.resume(next)
     }
+
+    /// Takes the running process out of the table with its exit code and picks the next one.
+    /// Returns the context to resume, None when there's neither a process nor an idle loop to go
+    /// back to. The process is kept in `exited` since its address space is still loaded.
+    pub fn exit_current(&mut self, cpu: usize, code: u64) -> Option<Context> {
+        if let Some(pid) = self.current.take() {
+            if let Some(mut process) = self.table.remove(pid, code) {
+                kprintln!("Process {} exited with {}", pid, code);
+                process.state = State::Exited;
+                self.exited.push(process);
+            }
+        }
+
+        let next = self.schedule(cpu);
+        self.resume(next)
+    }
+
+    /// Exited processes whose address space isn't `active`, so they're safe to free
+    pub fn take_exited(&mut self, active: u64) -> Vec<ManagedProcess> {
+        let (done, running): (Vec<_>, Vec<_>) = core::mem::take(&mut self.exited)
+            .into_iter()
+            .partition(|p| p.page_table_frame().start_address().as_u64() != active);
+        self.exited = running;
+        done
+    }
+
+    fn resume(&mut self, next: Option<Pid>) -> Option<Context> {
+        match next.and_then(|pid| self.table.get(pid)) {
+            Some(process) => Some(process.context.unwrap_or_else(|| process.initial_context())),
+            None => self.idle.take(),
+        }
+    }
+}
+
+pub fn current() -> Option<&'static mut ManagedProcess> {
+    unsafe {
+        let pid = SCHEDULER.current?;
+        SCHEDULER.table.get_mut(pid)
+    }
+}
+
+/// The process that is running, None when idle
+pub fn current_process() -> Option<&'static mut Process> {
+    current().map(|p| &mut p.process)
+}
+
+/// Every live process
+pub fn processes() -> &'static mut ProcessTable {
+    unsafe { &mut SCHEDULER.table }
+}
+
+pub fn find(pid: Pid) -> Option<&'static mut ManagedProcess> {
+    processes().get_mut(pid)
+}
+
+/// Removes the running process and returns what to run instead, see `Scheduler::exit_current`.
+/// Its stack is freed later by a softirq, once nothing runs on its address space.
+pub fn exit_current(code: u64) -> Option<Context> {
+    account();
+    let next = unsafe { SCHEDULER.exit_current(interrupts::current_cpu(), code) };
+    softirq::raise(reap, 0);
+    next
+}
+
+// Softirq work, frees what exited processes left behind
+fn reap(_: u64) {
+    let (frame, _) = Cr3::read();
+    let active = frame.start_address().as_u64();
+    let exited = cpu_interrupts::without_interrupts(|| unsafe { SCHEDULER.take_exited(active) });
+
+    for mut process in exited {
+        let table = process.process.address_space.as_mut();
+        let mut mapper = unsafe { OffsetPageTable::new(table, VirtAddr::new(PHYS_OFFSET)) };
+        let mut allocator = mem::allocator().lock();
+        mem::unmap_stack(&mut mapper, &mut *allocator, mem::PROCESS_STACK_PAGES);
+    }
+}
+
+/// Every process with its pid, name and state, for ps
+pub fn list() -> impl Iterator<Item = (Pid, &'static str, State)> {
+    unsafe {
+        SCHEDULER
+            .table
+            .iter()
+            .map(|p| (p.process.pid(), p.process.name(), p.state))
+    }
+}
+
+/// Spawns `count` copies of `elf` named demo0, demo1... so the schedular has several processes
+/// to rotate between. The schedular logs every switch and reports the cpu time split while the
+/// `demo` feature is on.
+#[cfg(feature = "demo")]
+pub fn spawn_demo(
+    elf: &elf::ElfFile<'_>,
+    kernel: &elf::ElfFile<'_>,
+    mem_size: usize,
+    count: usize,
+) {
+    for i in 0..count {
+        let name = alloc::format!("demo{}", i);
+        ManagedProcess::new_kernel_process(&name, elf, kernel, 0, 0, mem_size).spawn();
+    }
+    kprintln!("Spawned {} demo processes", count);
 }
 
 pub fn init() {
-    interrupts::register_handler(0x3C, schedular);
+    SWITCHED_AT.store(unsafe { _rdtsc() }, Ordering::SeqCst);
+    // Timer irq, switching processes with interrupts enabled would race the next tick
+    interrupts::register_handler(interrupts::TIMER_VECTOR, schedular);
+}
+
+// Charges the time since the last switch to whatever was running
+fn account() {
+    let now = unsafe { _rdtsc() };
+    let elapsed = now.wrapping_sub(SWITCHED_AT.swap(now, Ordering::SeqCst));
+    TOTAL_CYCLES.fetch_add(elapsed, Ordering::SeqCst);
+
+    unsafe {
+        match current() {
+            Some(process) => process.process.charge(elapsed),
+            None => {
+                IDLE_CYCLES.fetch_add(elapsed, Ordering::SeqCst);
+            }
+        }
+    }
 }
 
-pub fn schedular(frame: &mut interrupts::InterruptStackFrame, snapshot: &interrupts::CpuSnapshot) {
+pub fn idle_percent() -> u64 {
+    let total = TOTAL_CYCLES.load(Ordering::SeqCst);
+    if total == 0 {
+        return 100;
+    }
+    IDLE_CYCLES.load(Ordering::SeqCst) * 100 / total
+}
+
+pub fn report() {
+    let total = TOTAL_CYCLES.load(Ordering::SeqCst).max(1);
+    kprintln!("  PID NAME                 CYCLES  CPU");
+    unsafe {
+        for process in SCHEDULER.table.iter() {
+            let time = process.process.cpu_time();
+            kprintln!(
+                "{:>5} {:<16} {:>9} {:>3}%",
+                process.process.pid(),
+                process.process.name(),
+                time,
+                time * 100 / total
+            );
+        }
+    }
+    kprintln!("Idle {}%", idle_percent());
+}
+
+/// Timer irq handler, switches to the next process in the rotation
+pub fn schedular(frame: &mut InterruptStackFrame, snapshot: &CpuSnapshot) {
     kprintln!("Scheduling");
+    account();
+    let cpu = interrupts::current_cpu();
     unsafe {
-        if PROCESSES.len() > 0 {
-            let next = &PROCESSES[NEXT_PROCESS];
-            NEXT_PROCESS += 1;
+        let next = SCHEDULER.switch(cpu, Context::from_interrupt(frame, snapshot));
+        if let Some(next) = next {
+            next.resume_from_interrupt(frame);
+        }
+
+        #[cfg(feature = "demo")]
+        {
+            // Ticks between cpu time reports
+            const REPORT_INTERVAL: usize = 16;
+            static TICKS: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+            if let Some(process) = current() {
+                kprintln!("Switching to {}", process.process.name());
+            }
+            if TICKS.fetch_add(1, Ordering::SeqCst) % REPORT_INTERVAL == REPORT_INTERVAL - 1 {
+                report();
+            }
         }
     }
 }
+
+/// Cooperative version of the timer switch for the yield syscall. Returns the context the
+/// syscall stub should go back to, None to return to the caller.
+pub fn yield_current(cpu: &CpuSnapshot) -> Option<Context> {
+    account();
+    unsafe { SCHEDULER.switch(interrupts::current_cpu(), Context::from_syscall(cpu)) }
+}
@@ -0,0 +1,1318 @@
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    time::Duration,
+};
+
+use alloc::{
+    alloc::{alloc, dealloc, Layout},
+    boxed::Box,
+    vec,
+    vec::Vec,
+};
+use common::{
+    allocator::{self, HeapAccess},
+    efi::{FileInfo, MemoryDescriptor, MemoryMapIter, MemoryType, Rsdp, FILE_DIRECTORY},
+    kprintln,
+    mem::{self, BitmapFrameAllocator, MapError, PageTableFrameAllocator},
+    memory_regions::PHYS_OFFSET,
+    process::Process,
+    serial::SerialPort,
+    size_gb, size_tb,
+    slab_cache::SlabCache,
+    util::{self, CpuState},
+    x86_64::{
+        instructions::interrupts as cpu_interrupts,
+        structures::paging::{
+            mapper::TranslateResult, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable,
+            Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+        },
+        PhysAddr, VirtAddr,
+    },
+};
+
+use crate::{
+    acpi::{
+        madt::{Entry, MADT},
+        Signature, Xsdt,
+    },
+    drivers::{
+        input::InputSource,
+        keyboard::{Dvorak, Keyboard, Layout, UsQwerty},
+        lapic, pit, rtc,
+    },
+    interrupts,
+    process_manager::{ManagedProcess, ProcessTable, Scheduler},
+    syscall::{self, SYS_EXIT, SYS_GETPID, SYS_WRITE},
+    time,
+};
+
+// Nothing else lives here, so the paging check can map and unmap it freely
+const SCRATCH_PAGE: u64 = size_tb!(5);
+const COM1: u16 = 0x3F8;
+
+type Check = fn() -> Result<(), &'static str>;
+
+const CHECKS: &[(&str, Check)] = &[
+    ("frames", frames),
+    ("frame reuse", frame_reuse),
+    ("bitmap frames", bitmap_frames),
+    ("contiguous frames", contiguous_frames),
+    ("heap", heap),
+    ("heap conformance", heap_conformance),
+    ("mem routines", mem_routines),
+    ("paging", paging),
+    ("double mapping", double_mapping),
+    ("unmap", unmap),
+    ("huge mapping", huge_mapping),
+    ("stack guard", stack_guard),
+    ("int3", int3),
+    ("serial loopback", serial_loopback),
+    ("input polling", input_polling),
+    ("pit sleep", pit_sleep),
+    ("process ids", process_ids),
+    ("round robin", round_robin),
+    ("process exit", process_exit),
+    ("context switch", context_switch),
+    ("syscall dispatch", syscall_dispatch),
+    ("sys write", sys_write),
+    ("keyboard layouts", keyboard_layouts),
+    ("compose", compose),
+    ("rsdp checksum", rsdp_checksum),
+    ("xsdt walk", xsdt_walk),
+    ("madt parsing", madt_parsing),
+    ("local apic", local_apic),
+    ("apic timer", apic_timer),
+    ("rtc", rtc_time),
+    ("file info", file_info),
+    ("memory map stride", memory_map_stride),
+    ("slab cache", slab_cache),
+    ("heap stats", heap_stats),
+    ("heap sizing", heap_sizing),
+];
+
+/// Runs every check and prints the results over serial. A failing check doesn't stop the rest,
+/// returns the number that failed.
+pub fn run_all() -> usize {
+    kprintln!("selftest: running {} checks", CHECKS.len());
+
+    let mut failed = 0;
+    for (name, check) in CHECKS {
+        match check() {
+            Ok(()) => kprintln!("selftest: {} ... ok", name),
+            Err(reason) => {
+                kprintln!("selftest: {} ... FAILED ({})", name, reason);
+                failed += 1;
+            }
+        }
+    }
+
+    kprintln!(
+        "selftest: {} passed, {} failed",
+        CHECKS.len() - failed,
+        failed
+    );
+    failed
+}
+
+fn frames() -> Result<(), &'static str> {
+    let mut allocator = mem::allocator().lock();
+
+    let a = allocator.allocate_frame().ok_or("unable to allocate frame")?;
+    let b = allocator.allocate_frame().ok_or("unable to allocate frame")?;
+    if a == b {
+        return Err("same frame allocated twice");
+    }
+
+    allocator.free_frame(a);
+    allocator.free_frame(b);
+
+    // Freed frames are handed out again first, which one depends on whether the bitmap is in use
+    let c = allocator.allocate_frame().ok_or("unable to reallocate frame")?;
+    allocator.free_frame(c);
+    if c != a && c != b {
+        return Err("freed frame wasn't reused");
+    }
+    Ok(())
+}
+
+fn frame_reuse() -> Result<(), &'static str> {
+    const BATCH: usize = 16;
+
+    // The lock is only held for each call, growing the vecs can fault in heap pages which takes it
+    let allocate = || mem::allocator().lock().allocate_frame();
+    let deallocate = |frame| unsafe { mem::allocator().lock().deallocate_frame(frame) };
+
+    let mut batch = Vec::with_capacity(BATCH);
+    for _ in 0..BATCH {
+        let frame = allocate().ok_or("unable to allocate frame")?;
+        batch.push(frame);
+    }
+    for &frame in &batch {
+        deallocate(frame);
+    }
+
+    // Everything freed comes back before anything new is taken from the memory map
+    let mut reused = Vec::with_capacity(BATCH);
+    for _ in 0..BATCH {
+        let frame = allocate().ok_or("unable to reallocate frame")?;
+        reused.push(frame);
+    }
+    let all_reused = reused.iter().all(|frame| batch.contains(frame));
+    for &frame in &reused {
+        deallocate(frame);
+    }
+
+    if !all_reused {
+        return Err("freed frames weren't reused");
+    }
+    Ok(())
+}
+
+fn bitmap_frames() -> Result<(), &'static str> {
+    // Only the bitmap is touched, so made up frames are fine. Three words with the first 70 and
+    // every tenth after that in use.
+    let frame = |i: usize| PhysFrame::containing_address(PhysAddr::new(i as u64 * 4096));
+    let free = (70..192).filter(|i| i % 10 != 0).map(frame);
+    let mut bitmap = BitmapFrameAllocator::with_free(192 * 4096, free);
+    let available = bitmap.free_frames();
+    if available != 109 {
+        return Err("wrong free frame count");
+    }
+
+    let first = bitmap.allocate_frame().ok_or("unable to allocate frame")?;
+    if first != frame(71) {
+        return Err("lowest free frame wasn't allocated first");
+    }
+    let mut allocated = 1;
+    while let Some(f) = bitmap.allocate_frame() {
+        if f.start_address().as_u64() / 4096 % 10 == 0 {
+            return Err("used frame allocated");
+        }
+        allocated += 1;
+    }
+    if allocated != available || bitmap.free_frames() != 0 {
+        return Err("not every free frame was allocated");
+    }
+
+    // Freeing moves the search back so the frame is found again
+    unsafe { bitmap.deallocate_frame(frame(75)) };
+    unsafe { bitmap.deallocate_frame(frame(75)) };
+    if bitmap.free_frames() != 1 || bitmap.allocate_frame() != Some(frame(75)) {
+        return Err("freed frame wasn't reused");
+    }
+    Ok(())
+}
+
+fn contiguous_frames() -> Result<(), &'static str> {
+    const FRAMES: usize = 17;
+
+    // The free list is kept in freed frames, so the made up map has to be over real ones
+    let base = mem::allocator()
+        .lock()
+        .allocate_contiguous(FRAMES)
+        .ok_or("unable to allocate frames")?
+        .start_address()
+        .as_u64();
+    let region = |memory_type, offset: u64, size| MemoryDescriptor {
+        memory_type,
+        physical_address: (base + offset) as usize,
+        size,
+        ..Default::default()
+    };
+    let map = [
+        region(MemoryType::Conventional, 0x0000, 2),
+        region(MemoryType::Conventional, 0x3000, 3),
+        // Adjacent but not usable, so it doesn't join the region before
+        region(MemoryType::LoaderData, 0x6000, 4),
+        region(MemoryType::Conventional, 0xA000, 1),
+        region(MemoryType::Conventional, 0xC000, 5),
+    ];
+    let frame = |offset: u64| PhysFrame::containing_address(PhysAddr::new(base + offset));
+
+    let result = (|| {
+        let mut allocator = PageTableFrameAllocator::new(&map);
+        if allocator.allocate_contiguous(6).is_some() {
+            return Err("run longer than any region allocated");
+        }
+        if allocator.allocate_contiguous(4) != Some(frame(0xC000)) {
+            return Err("wrong run allocated");
+        }
+        // The frames skipped over are still available
+        let skipped = allocator.allocate_frame().ok_or("skipped frames were lost")?;
+        if skipped != frame(0xA000) {
+            return Err("skipped frames weren't freed");
+        }
+        if allocator.allocate_contiguous(1) != Some(frame(0x10000)) {
+            return Err("run after a contiguous allocation wasn't next");
+        }
+
+        let mut bitmap = BitmapFrameAllocator::new(&map);
+        if bitmap.allocate_contiguous(4) != Some(frame(0xC000)) {
+            return Err("wrong run allocated from the bitmap");
+        }
+        if bitmap.allocate_contiguous(3) != Some(frame(0x3000)) {
+            return Err("lowest run not allocated from the bitmap");
+        }
+        if bitmap.allocate_contiguous(2) != Some(frame(0)) || bitmap.free_frames() != 2 {
+            return Err("bitmap count wrong after contiguous allocations");
+        }
+        Ok(())
+    })();
+
+    let mut allocator = mem::allocator().lock();
+    for i in 0..FRAMES as u64 {
+        allocator.free_frame(frame(i * 4096));
+    }
+    result
+}
+
+fn heap() -> Result<(), &'static str> {
+    let boxed = Box::new(0xDEADBEEFu64);
+    if *boxed != 0xDEADBEEF {
+        return Err("box lost its value");
+    }
+
+    let v: Vec<u32> = (0..4096).collect();
+    if v.iter().enumerate().any(|(i, x)| *x != i as u32) {
+        return Err("vec lost its values");
+    }
+    drop(v);
+    drop(boxed);
+
+    // Something freed should leave room for an allocation of the same size
+    let again: Vec<u8> = Vec::with_capacity(4096);
+    if again.capacity() < 4096 {
+        return Err("unable to reallocate");
+    }
+
+    if allocator::stats().poisoned {
+        let fresh = unsafe { core::slice::from_raw_parts(again.as_ptr(), 4096) };
+        if fresh.iter().any(|b| *b != allocator::ALLOC_POISON) {
+            return Err("new allocation isn't poisoned");
+        }
+    }
+    Ok(())
+}
+
+// Rounds of the heap conformance check and how many allocations it keeps live at once
+const CONFORMANCE_ROUNDS: usize = 2000;
+const CONFORMANCE_LIVE: usize = 64;
+
+/// Random allocations and frees through the global allocator, whichever backend it is. Every live
+/// allocation is filled with its own byte and checked before it's freed, and none may overlap.
+fn heap_conformance() -> Result<(), &'static str> {
+    let mut live: Vec<(*mut u8, Layout, u8)> = Vec::with_capacity(CONFORMANCE_LIVE);
+
+    let result = (|| {
+        for round in 0..CONFORMANCE_ROUNDS {
+            let free = !live.is_empty()
+                && (live.len() == CONFORMANCE_LIVE || util::rng::random_below(2) == 0);
+
+            if free {
+                let (ptr, layout, fill) =
+                    live.swap_remove(util::rng::random_below(live.len() as u64) as usize);
+                let bytes = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+                let intact = bytes.iter().all(|b| *b == fill);
+                unsafe { dealloc(ptr, layout) };
+                if !intact {
+                    return Err("allocation was overwritten");
+                }
+                continue;
+            }
+
+            let size = 1 + util::rng::random_below(4096) as usize;
+            let align = 1 << util::rng::random_below(7);
+            let layout = Layout::from_size_align(size, align).map_err(|_| "bad layout")?;
+            let ptr = unsafe { alloc(layout) };
+            if ptr.is_null() {
+                return Err("allocation failed");
+            }
+            if ptr as usize % align != 0 {
+                return Err("allocation isn't aligned");
+            }
+
+            let (start, end) = (ptr as usize, ptr as usize + size);
+            if live
+                .iter()
+                .any(|(p, l, _)| start < *p as usize + l.size() && (*p as usize) < end)
+            {
+                unsafe { dealloc(ptr, layout) };
+                return Err("allocations overlap");
+            }
+
+            let fill = round as u8;
+            unsafe { util::memset(ptr, fill as i32, size) };
+            live.push((ptr, layout, fill));
+        }
+        Ok(())
+    })();
+
+    for (ptr, layout, _) in live.drain(..) {
+        unsafe { dealloc(ptr, layout) };
+    }
+    result
+}
+
+fn mem_routines() -> Result<(), &'static str> {
+    let mut a = [0u8; 64];
+    let mut b = [0u8; 64];
+
+    unsafe {
+        util::memset(a.as_mut_ptr(), 0xAB, a.len());
+        if a.iter().any(|x| *x != 0xAB) {
+            return Err("memset");
+        }
+
+        for (i, x) in a.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        util::memcpy(b.as_mut_ptr(), a.as_ptr(), a.len());
+        if a != b {
+            return Err("memcpy");
+        }
+
+        if util::memcmp(a.as_ptr(), b.as_ptr(), a.len()) != 0 {
+            return Err("memcmp of equal buffers");
+        }
+        b[32] = 0xFF;
+        if util::memcmp(a.as_ptr(), b.as_ptr(), a.len()) >= 0 {
+            return Err("memcmp of different buffers");
+        }
+
+        // Overlapping forward copy
+        util::memmove(a.as_mut_ptr().add(1), a.as_ptr(), 32);
+        if a[0] != 0 || (1..33).any(|i| a[i] != (i - 1) as u8) {
+            return Err("memmove");
+        }
+    }
+    Ok(())
+}
+
+fn paging() -> Result<(), &'static str> {
+    let mut pt = mem::active_offset_page_table(PHYS_OFFSET);
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(SCRATCH_PAGE));
+    let frame = mem::allocator()
+        .lock()
+        .allocate_frame()
+        .ok_or("unable to allocate frame")?;
+    // Set once unmap_global has freed the frame
+    let mut freed = false;
+
+    let result = (|| {
+        unsafe {
+            pt.map_to(
+                page,
+                frame,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                mem::allocator().get_mut(),
+            )
+            .map_err(|_| "map_to")?
+            .flush();
+        }
+
+        if pt.translate_addr(page.start_address() + 0x123u64)
+            != Some(frame.start_address() + 0x123u64)
+        {
+            return Err("translation doesn't match");
+        }
+
+        let ptr = page.start_address().as_mut_ptr::<u64>();
+        let through_offset = (PHYS_OFFSET + frame.start_address().as_u64()) as *const u64;
+        unsafe {
+            ptr.write_volatile(0x5E1F7E57);
+            if through_offset.read_volatile() != 0x5E1F7E57 {
+                return Err("write didn't reach the frame");
+            }
+        }
+
+        let unmapped = mem::unmap_global(page.start_address()).map_err(|_| "unmap")?;
+        freed = true;
+        if unmapped != frame {
+            return Err("unmapped the wrong frame");
+        }
+        if pt.translate_addr(page.start_address()).is_some() {
+            return Err("still mapped after unmap");
+        }
+        Ok(())
+    })();
+
+    // Unmapping frees the frame if a check bailed early with the page still mapped
+    if !freed && mem::unmap_global(page.start_address()).is_err() {
+        mem::allocator().lock().free_frame(frame);
+    }
+    result
+}
+
+fn double_mapping() -> Result<(), &'static str> {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(SCRATCH_PAGE));
+    let frame = mem::allocator()
+        .lock()
+        .allocate_frame()
+        .ok_or("unable to allocate frame")?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let result = (|| {
+        mem::map_checked(page, frame, flags).map_err(|_| "first mapping failed")?;
+
+        match mem::map_checked(page, frame, flags) {
+            Err(MapError::AlreadyMapped {
+                virt,
+                existing,
+                flags: existing_flags,
+            }) => {
+                if virt != page.start_address() || existing != frame.start_address() {
+                    return Err("error names the wrong mapping");
+                }
+                if !existing_flags.contains(flags) {
+                    return Err("error has the wrong flags");
+                }
+                Ok(())
+            }
+            Err(_) => Err("wrong error for a double mapping"),
+            Ok(()) => Err("page was mapped twice"),
+        }
+    })();
+
+    // Unmapping frees the frame, it only has to be freed here if it never got mapped
+    if mem::unmap_global(page.start_address()).is_err() {
+        mem::allocator().lock().free_frame(frame);
+    }
+    result
+}
+
+fn unmap() -> Result<(), &'static str> {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(SCRATCH_PAGE));
+    let frame = mem::allocator()
+        .lock()
+        .allocate_frame()
+        .ok_or("unable to allocate frame")?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let result = (|| {
+        mem::map_checked(page, frame, flags).map_err(|_| "unable to map page")?;
+
+        let mut pt = mem::active_offset_page_table(PHYS_OFFSET);
+        let unmapped = mem::unmap(&mut pt, page).map_err(|_| "unable to unmap page")?;
+        if unmapped != frame {
+            return Err("wrong frame returned");
+        }
+        if pt.translate_page(page).is_ok() {
+            return Err("page still translates after unmapping");
+        }
+        Ok(())
+    })();
+
+    // Only unmaps if the check failed before unmapping, and then frees the frame itself
+    if mem::unmap_global(page.start_address()).is_err() {
+        mem::allocator().lock().free_frame(frame);
+    }
+    result
+}
+
+fn huge_mapping() -> Result<(), &'static str> {
+    const HUGE: u64 = 0x200000;
+
+    // Nothing is accessed through these, so the physical addresses don't have to be free
+    let mut table = Box::new(PageTable::new());
+    let mut mapper = unsafe { OffsetPageTable::new(&mut table, VirtAddr::new(PHYS_OFFSET)) };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    // One page short of a 2MiB boundary on both sides, then a whole 2MiB page and a 3 page tail
+    let phys = PhysAddr::new(HUGE - 0x1000);
+    let virt = VirtAddr::new(SCRATCH_PAGE + HUGE - 0x1000);
+    let size = (0x1000 + HUGE + 0x3000) as usize;
+    mem::map_phys_huge(
+        &mut mapper,
+        &mut *mem::allocator().lock(),
+        phys,
+        virt,
+        size,
+        flags,
+    )
+    .map_err(|_| "unable to map aligned region")?;
+
+    let size_at = |mapper: &OffsetPageTable, virt: VirtAddr| match mapper.translate(virt) {
+        TranslateResult::Mapped { frame, .. } => Some(frame.size()),
+        _ => None,
+    };
+    if size_at(&mapper, virt) != Some(0x1000) {
+        return Err("unaligned head not mapped with a 4KiB page");
+    }
+    if size_at(&mapper, virt + 0x1000u64) != Some(HUGE) {
+        return Err("aligned middle not mapped with a 2MiB page");
+    }
+    if size_at(&mapper, virt + 0x1000u64 + HUGE + 0x2000u64) != Some(0x1000) {
+        return Err("tail not mapped with 4KiB pages");
+    }
+    if size_at(&mapper, virt + 0x1000u64 + HUGE + 0x3000u64).is_some() {
+        return Err("mapped past the end of the region");
+    }
+    match mapper.translate(virt + 0x1000u64 + 0x1234u64) {
+        TranslateResult::Mapped { frame, offset, .. }
+            if frame.start_address() + offset == PhysAddr::new(HUGE + 0x1234) => {}
+        _ => return Err("2MiB page translates to the wrong address"),
+    }
+
+    // Physical and virtual addresses at different offsets into a 2MiB page can't use one
+    let phys = PhysAddr::new(HUGE + 0x1000);
+    let virt = VirtAddr::new(SCRATCH_PAGE + 4 * HUGE);
+    let size = (2 * HUGE) as usize;
+    mem::map_phys_huge(
+        &mut mapper,
+        &mut *mem::allocator().lock(),
+        phys,
+        virt,
+        size,
+        flags,
+    )
+    .map_err(|_| "unable to map misaligned region")?;
+    if size_at(&mapper, virt) != Some(0x1000) || size_at(&mapper, virt + HUGE) != Some(0x1000) {
+        return Err("misaligned region used a 2MiB page");
+    }
+    Ok(())
+}
+
+fn stack_guard() -> Result<(), &'static str> {
+    const PAGES: usize = 4;
+
+    // A table of its own, the stack address is in use in every process's
+    let mut table = Box::new(PageTable::new());
+    let mut mapper = unsafe { OffsetPageTable::new(&mut table, VirtAddr::new(PHYS_OFFSET)) };
+
+    let top = mem::map_stack_with_guard(&mut mapper, &mut *mem::allocator().lock(), PAGES)
+        .map_err(|_| "unable to map stack")?;
+    let bottom = Page::<Size4KiB>::containing_address(top - PAGES as u64 * 4096);
+    let guard = mem::stack_guard_page(PAGES);
+
+    let mapped =
+        Page::range(bottom, bottom + PAGES as u64).all(|page| mapper.translate_page(page).is_ok());
+    let guard_absent = mapper.translate_page(guard).is_err() && guard + 1 == bottom;
+
+    for page in Page::range(bottom, bottom + PAGES as u64) {
+        if let Ok(frame) = mem::unmap(&mut mapper, page) {
+            mem::allocator().lock().free_frame(frame);
+        }
+    }
+
+    if !mapped {
+        return Err("stack not mapped");
+    }
+    if !guard_absent {
+        return Err("guard page mapped");
+    }
+    if !mem::is_stack_guard(mem::stack_guard_page(mem::PROCESS_STACK_PAGES).start_address()) {
+        return Err("guard page fault not recognised");
+    }
+    Ok(())
+}
+
+fn int3() -> Result<(), &'static str> {
+    let before = interrupts::BREAKPOINTS.load(Ordering::SeqCst);
+    unsafe { core::arch::asm!("int3") }
+    if interrupts::BREAKPOINTS.load(Ordering::SeqCst) != before + 1 {
+        return Err("breakpoint handler didn't run");
+    }
+    Ok(())
+}
+
+fn serial_loopback() -> Result<(), &'static str> {
+    if !SerialPort::from(COM1).self_test() {
+        return Err("pattern didn't come back");
+    }
+    Ok(())
+}
+
+// Counts wakes so the input check can see the waker fire
+static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+fn counting_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WAKES.fetch_add(1, Ordering::SeqCst);
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, noop);
+
+    unsafe { Waker::from_raw(clone(core::ptr::null())) }
+}
+
+fn input_polling() -> Result<(), &'static str> {
+    static SOURCE: InputSource<u8> = InputSource::new();
+    let waker = counting_waker();
+    let mut cx = Context::from_waker(&waker);
+    let wakes = WAKES.load(Ordering::SeqCst);
+
+    if SOURCE.poll(&mut cx) != Poll::Pending {
+        return Err("empty source wasn't pending");
+    }
+    SOURCE.push(1);
+    SOURCE.push(2);
+    if WAKES.load(Ordering::SeqCst) != wakes + 1 {
+        return Err("push didn't wake the reader once");
+    }
+
+    if SOURCE.poll(&mut cx) != Poll::Ready(1) || SOURCE.poll(&mut cx) != Poll::Ready(2) {
+        return Err("input came back out of order");
+    }
+
+    // A full buffer drops new input and keeps what's queued
+    let mut pushed = 0;
+    while SOURCE.push(pushed as u8) {
+        pushed += 1;
+    }
+    if SOURCE.len() != pushed || SOURCE.try_pop() != Some(0) {
+        return Err("full buffer lost queued input");
+    }
+    while SOURCE.try_pop().is_some() {}
+    Ok(())
+}
+
+fn pit_sleep() -> Result<(), &'static str> {
+    if time::ms_to_ticks(10, 100) != 1 || time::ms_to_ticks(15, 100) != 2 {
+        return Err("wrong tick count for a delay");
+    }
+    if time::ticks_between(u64::MAX - 1, 3) != 5 {
+        return Err("tick counter wrap not handled");
+    }
+
+    // Sleeping needs the tick irq, checks otherwise run with interrupts off
+    let start = pit::ticks();
+    let begin = time::now();
+    cpu_interrupts::enable();
+    time::sleep_ms(30);
+    cpu_interrupts::disable();
+
+    let ticks = time::ticks_between(start, pit::ticks());
+    if ticks < time::ms_to_ticks(30, pit::frequency()) {
+        return Err("woke before enough ticks");
+    }
+    if begin.elapsed() < Duration::from_millis(30) {
+        return Err("woke before the delay had passed");
+    }
+    Ok(())
+}
+
+fn process_ids() -> Result<(), &'static str> {
+    let mut table = ProcessTable::new();
+    let mut pids = Vec::new();
+    for _ in 0..4 {
+        let process = Process::placeholder("pid");
+        pids.push(process.pid());
+        table.insert(ManagedProcess::from_process(process));
+    }
+
+    if pids.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err("pids not unique and increasing");
+    }
+    for &pid in &pids {
+        match table.get(pid) {
+            Some(process) if process.process().pid() == pid => {}
+            _ => return Err("pid lookup found the wrong process"),
+        }
+    }
+    Ok(())
+}
+
+fn round_robin() -> Result<(), &'static str> {
+    let mut scheduler = Scheduler::new();
+    if scheduler.schedule(0).is_some() {
+        return Err("picked a process with none added");
+    }
+
+    let first = Process::placeholder("first");
+    let second = Process::placeholder("second");
+    let order = [first.pid(), second.pid()];
+    scheduler.add(ManagedProcess::from_process(first));
+    scheduler.add(ManagedProcess::from_process(second));
+    for expected in [order[0], order[1], order[0], order[1]] {
+        if scheduler.schedule(0) != Some(expected) || scheduler.current() != Some(expected) {
+            return Err("schedule didn't advance in order");
+        }
+    }
+    Ok(())
+}
+
+fn process_exit() -> Result<(), &'static str> {
+    let mut scheduler = Scheduler::new();
+    let first = Process::placeholder("first");
+    let second = Process::placeholder("second");
+    let (first_pid, second_pid) = (first.pid(), second.pid());
+    scheduler.add(ManagedProcess::from_process(first));
+    scheduler.add(ManagedProcess::from_process(second));
+
+    if scheduler.schedule(0) != Some(first_pid) {
+        return Err("first process not picked");
+    }
+    if scheduler.exit_current(0, 3).is_none() || scheduler.current() != Some(second_pid) {
+        return Err("exit didn't switch to the other process");
+    }
+    if scheduler.table().get(first_pid).is_some() || scheduler.table().len() != 1 {
+        return Err("exited process still in the table");
+    }
+    if scheduler.table().exit_code(first_pid) != Some(3) {
+        return Err("exit code not kept");
+    }
+
+    // Nothing to run and no idle loop saved to go back to, the caller halts
+    if scheduler.exit_current(0, 0).is_some() || scheduler.current().is_some() {
+        return Err("picked something after the last exit");
+    }
+    if scheduler.table().exit_code(second_pid) != Some(0) {
+        return Err("exit code not kept");
+    }
+    Ok(())
+}
+
+// Contexts for the context switch check, the task switches back to the check through them
+static mut CHECK_CONTEXT: CpuState = CpuState::empty();
+static mut TASK_CONTEXT: CpuState = CpuState::empty();
+static TASK_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn switch_task() -> ! {
+    loop {
+        TASK_RUNS.fetch_add(1, Ordering::SeqCst);
+        unsafe { util::switch_context(&mut TASK_CONTEXT, &CHECK_CONTEXT) }
+    }
+}
+
+fn context_switch() -> Result<(), &'static str> {
+    const STACK_SIZE: usize = 16 * 1024;
+
+    let stack = vec![0u8; STACK_SIZE];
+    let bottom = stack.as_ptr() as u64;
+    let top = bottom + STACK_SIZE as u64;
+    TASK_RUNS.store(0, Ordering::SeqCst);
+
+    unsafe {
+        TASK_CONTEXT = CpuState::new(switch_task, top);
+        if TASK_CONTEXT.stack_pointer() % 16 != 8 {
+            return Err("new context stack misaligned");
+        }
+
+        for expected in 1..=3 {
+            util::switch_context(&mut CHECK_CONTEXT, &TASK_CONTEXT);
+            if TASK_RUNS.load(Ordering::SeqCst) != expected {
+                return Err("task didn't run once per switch");
+            }
+            // Saved at the return address of its own switch, not the entry point again
+            if TASK_CONTEXT.instruction_pointer() == switch_task as u64 {
+                return Err("task context not saved");
+            }
+
+            let sp = TASK_CONTEXT.stack_pointer();
+            if sp <= bottom || sp >= top {
+                return Err("task context not saved on its own stack");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn syscall_dispatch() -> Result<(), &'static str> {
+    // Failures come back as all ones, unknown numbers as -ENOSYS
+    const ERROR: u64 = u64::MAX;
+
+    let message = b"";
+    let ptr = message.as_ptr() as u64;
+    if syscall::handle_syscall(SYS_WRITE, 1, ptr, 0) != 0 {
+        return Err("write to stdout failed");
+    }
+    if syscall::handle_syscall(SYS_WRITE, 99, ptr, 0) != ERROR {
+        return Err("write to a closed fd succeeded");
+    }
+
+    // The checks run before any process, so these have nothing to act on
+    if syscall::handle_syscall(SYS_GETPID, 0, 0, 0) != ERROR {
+        return Err("getpid without a process");
+    }
+    if syscall::handle_syscall(SYS_EXIT, 0, 0, 0) != ERROR {
+        return Err("exit without a process");
+    }
+
+    if syscall::handle_syscall(0xFFFF, 0, 0, 0) != -38i64 as u64 {
+        return Err("unknown syscall not rejected");
+    }
+    Ok(())
+}
+
+fn sys_write() -> Result<(), &'static str> {
+    const ERROR: u64 = u64::MAX;
+
+    // Longer than one copy chunk so the buffer goes out in pieces
+    let mut message = vec![b'.'; 300];
+    message.extend_from_slice(b"\r\n");
+    let len = message.len() as u64;
+    if syscall::handle_syscall(SYS_WRITE, 1, message.as_ptr() as u64, len) != len {
+        return Err("kernel buffer not written");
+    }
+
+    if syscall::handle_syscall(SYS_WRITE, 1, SCRATCH_PAGE, 16) != ERROR {
+        return Err("unmapped buffer accepted");
+    }
+    if syscall::handle_syscall(SYS_WRITE, 1, u64::MAX - 4, 16) != ERROR {
+        return Err("buffer wrapping the address space accepted");
+    }
+    Ok(())
+}
+
+fn keyboard_layouts() -> Result<(), &'static str> {
+    // Set 1 make code, shift, US QWERTY, Dvorak
+    const CASES: &[(u8, bool, char, char)] = &[
+        (0x02, false, '1', '1'),
+        (0x0C, false, '-', '['),
+        (0x10, false, 'q', '\''),
+        (0x10, true, 'Q', '"'),
+        (0x1E, false, 'a', 'a'),
+        (0x1F, false, 's', 'o'),
+        (0x1F, true, 'S', 'O'),
+        (0x2C, false, 'z', ';'),
+        (0x39, false, ' ', ' '),
+        (0x3B, false, '\0', '\0'),
+    ];
+
+    for (code, shift, qwerty, dvorak) in CASES {
+        if UsQwerty.map(*code, *shift) != *qwerty {
+            return Err("US QWERTY mapped a code wrong");
+        }
+        if Dvorak.map(*code, *shift) != *dvorak {
+            return Err("Dvorak mapped a code wrong");
+        }
+    }
+
+    // Caps lock on, then shift held over it
+    let mut keyboard = Keyboard::with_layout(&Dvorak);
+    keyboard.process(0x3A);
+    if keyboard.process(0x1F) != Some('O') {
+        return Err("caps lock didn't upper case");
+    }
+    keyboard.process(0x2A);
+    if keyboard.process(0x1F) != Some('o') || keyboard.process(0x02) != Some('!') {
+        return Err("shift with caps lock");
+    }
+    keyboard.process(0xAA);
+    if keyboard.shift() {
+        return Err("shift release wasn't tracked");
+    }
+
+    // Composing works on the layout's characters, Dvorak has ' and e where QWERTY has q and d
+    keyboard.process(0x3A);
+    keyboard.set_compose_key(Some(0x46));
+    let typed = [0x46, 0x10, 0x20].map(|code| keyboard.process(code));
+    if typed != [None, None, Some('é')] {
+        return Err("compose didn't use the layout");
+    }
+    Ok(())
+}
+
+fn rsdp_checksum() -> Result<(), &'static str> {
+    // Revision 2 RSDP as QEMU's firmware lays it out. Signature, checksum 0x79, oem id, revision,
+    // rsdt address, length, xsdt address and the extended checksum 0x10.
+    const RSDP: [u8; 36] = [
+        0x52, 0x53, 0x44, 0x20, 0x50, 0x54, 0x52, 0x20, 0x79, 0x42, 0x4F, 0x43, 0x48, 0x53, 0x20,
+        0x02, 0x2A, 0x4D, 0xE1, 0x7F, 0x24, 0x00, 0x00, 0x00, 0x1E, 0x4E, 0xE1, 0x7F, 0x00, 0x00,
+        0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+    ];
+
+    let read = |bytes: &[u8; 36]| unsafe { (bytes.as_ptr() as *const Rsdp).read_unaligned() };
+    let rsdp = read(&RSDP);
+    if &rsdp.signature != Rsdp::SIGNATURE || !rsdp.validate_checksum() {
+        return Err("known good RSDP rejected");
+    }
+    if !rsdp.has_xsdt() {
+        return Err("revision 2 RSDP without an XSDT");
+    }
+
+    // One byte in each checksummed range
+    for index in [15, 24] {
+        let mut bad = RSDP;
+        bad[index] ^= 1;
+        if read(&bad).validate_checksum() {
+            return Err("corrupt RSDP accepted");
+        }
+    }
+    Ok(())
+}
+
+fn xsdt_walk() -> Result<(), &'static str> {
+    const HEADER: usize = 36;
+
+    // Everything sits in one page so the tables are physically contiguous too
+    let layout = Layout::from_size_align(4096, 4096).unwrap();
+    let page = unsafe { alloc(layout) };
+    if page.is_null() {
+        return Err("unable to allocate tables");
+    }
+    let phys = match mem::virt_to_phys(VirtAddr::new(page as u64)) {
+        Some(phys) => phys.as_u64(),
+        None => return Err("table page not mapped"),
+    };
+
+    let table = |offset: usize, signature: &[u8; 4], data: &[u8]| unsafe {
+        let bytes = core::slice::from_raw_parts_mut(page.add(offset), HEADER + data.len());
+        bytes.fill(0);
+        bytes[..4].copy_from_slice(signature);
+        bytes[4..8].copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+        bytes[HEADER..].copy_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes[9] = 0u8.wrapping_sub(sum);
+    };
+    table(0x100, b"APIC", &[1, 2, 3, 4]);
+    table(0x200, b"HPET", &[5, 6, 7, 8]);
+    table(0x300, b"FACP", &[]);
+    // Broken after its checksum was set, so it has to be skipped
+    unsafe { *page.add(0x200 + HEADER) ^= 0xFF };
+
+    let entries: Vec<u8> = [0x100, 0x200, 0x300]
+        .iter()
+        .flat_map(|offset| (phys + offset).to_le_bytes())
+        .collect();
+    table(0, b"XSDT", &entries);
+
+    let xsdt = unsafe { Xsdt::parse(PhysAddr::new(phys), 8) };
+    unsafe { dealloc(page, layout) };
+    let xsdt = xsdt.ok_or("valid XSDT rejected")?;
+
+    if xsdt.address(Signature::MADT) != Some(PhysAddr::new(phys + 0x100)) {
+        return Err("MADT not found at its address");
+    }
+    if xsdt.address(Signature::HPET).is_some() || xsdt.iter().count() != 2 {
+        return Err("table with a bad checksum kept");
+    }
+    match xsdt.find(Signature::FADT) {
+        Some(fadt) if fadt.data().is_empty() => {}
+        _ => return Err("FADT not copied"),
+    }
+    Ok(())
+}
+
+fn madt_parsing() -> Result<(), &'static str> {
+    let mut bytes = vec![0u8; 36];
+    bytes[..4].copy_from_slice(b"APIC");
+    bytes.extend_from_slice(&0xFEE0_0000u32.to_le_bytes());
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+
+    // Two enabled cpus, an I/O APIC and irq 0 routed to gsi 2
+    bytes.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+    bytes.extend_from_slice(&[0, 8, 1, 3, 1, 0, 0, 0]);
+    bytes.extend_from_slice(&[1, 12, 4, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+    bytes.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+    let length = bytes.len() as u32;
+    bytes[4..8].copy_from_slice(&length.to_le_bytes());
+
+    let madt = unsafe { &*(bytes.as_ptr() as *const MADT) };
+    if madt.local_apic_address() != 0xFEE0_0000 {
+        return Err("wrong local APIC address");
+    }
+    if madt.apic_ids() != [0, 3] {
+        return Err("wrong cpu APIC ids");
+    }
+    if madt.io_apic_address() != Some(0xFEC0_0000) {
+        return Err("wrong I/O APIC address");
+    }
+
+    let overrides = madt.iter().filter(|entry| {
+        matches!(
+            entry,
+            Entry::InterruptSourceOverride {
+                source: 0,
+                global_system_interrupt: 2,
+                ..
+            }
+        )
+    });
+    if overrides.count() != 1 || madt.iter().count() != 4 {
+        return Err("entries not all parsed");
+    }
+    Ok(())
+}
+
+fn local_apic() -> Result<(), &'static str> {
+    if lapic::id() as usize != interrupts::current_cpu() {
+        return Err("apic id doesn't match cpuid");
+    }
+
+    let expected = [
+        (1, 0b1011),
+        (2, 0b0000),
+        (16, 0b0011),
+        (32, 0b1000),
+        (128, 0b1010),
+    ];
+    for &(divide, config) in &expected {
+        if lapic::divide_config(divide) != Some(config) {
+            return Err("wrong timer divide configuration");
+        }
+    }
+    if lapic::divide_config(3).is_some() || lapic::divide_config(0).is_some() {
+        return Err("invalid divisor accepted");
+    }
+    Ok(())
+}
+
+fn apic_timer() -> Result<(), &'static str> {
+    let ticks_per_ms = lapic::ticks_per_ms();
+    if ticks_per_ms == 0 {
+        return Err("timer not calibrated");
+    }
+
+    // The timer is running periodically, so the count may wrap once during the wait
+    let period = ticks_per_ms * 1000 / interrupts::TIMER_HZ;
+    let before = lapic::timer_count();
+    pit::wait_ms(2);
+    let after = lapic::timer_count();
+    let elapsed = (before + period - after) % period;
+
+    // Generous bounds, emulators don't keep the two clocks in step
+    if elapsed < ticks_per_ms || elapsed > ticks_per_ms * 3 {
+        return Err("timer disagrees with the PIT");
+    }
+    Ok(())
+}
+
+fn rtc_time() -> Result<(), &'static str> {
+    let cases = [(0x00, 0), (0x09, 9), (0x10, 10), (0x59, 59), (0x99, 99)];
+    for &(bcd, binary) in &cases {
+        if rtc::bcd_to_binary(bcd) != binary {
+            return Err("wrong BCD conversion");
+        }
+    }
+
+    let time = rtc::now();
+    let (year, month, day) = (time.year, time.month, time.day);
+    if year < 2000 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err("RTC date out of range");
+    }
+    if time.hour > 23 || time.minute > 59 || time.second > 59 {
+        return Err("RTC time out of range");
+    }
+    Ok(())
+}
+
+fn file_info() -> Result<(), &'static str> {
+    let name = "KERNEL.ELF";
+    let size = 80 + (name.len() + 1) * 2;
+
+    // Record as the firmware lays it out, with the times zeroed and the terminated name at the end
+    let mut bytes = vec![0u8; size + 6];
+    bytes[0..8].copy_from_slice(&(size as u64).to_le_bytes());
+    bytes[8..16].copy_from_slice(&0x1234u64.to_le_bytes());
+    bytes[16..24].copy_from_slice(&0x2000u64.to_le_bytes());
+    bytes[72..80].copy_from_slice(&1u64.to_le_bytes());
+    for (i, c) in name.encode_utf16().enumerate() {
+        bytes[80 + i * 2..82 + i * 2].copy_from_slice(&c.to_le_bytes());
+    }
+    // Past the record's size, mustn't end up in the name
+    bytes[size..].fill(b'x');
+
+    let info = FileInfo::from_bytes(&bytes).ok_or("record not parsed")?;
+    if info.size != size || info.file_size != 0x1234 || info.physical_size != 0x2000 {
+        return Err("wrong sizes");
+    }
+    if info.attribute != 1 || info.is_directory() {
+        return Err("wrong attributes");
+    }
+    if info.file_name().len() != name.len() || info.name() != name {
+        return Err("wrong name");
+    }
+
+    bytes[72] = FILE_DIRECTORY as u8;
+    if !FileInfo::from_bytes(&bytes).map_or(false, |info| info.is_directory()) {
+        return Err("directory not detected");
+    }
+    if FileInfo::from_bytes(&bytes[..40]).is_some() {
+        return Err("truncated record parsed");
+    }
+    if !FileInfo::default().file_name().is_empty() {
+        return Err("default has a name");
+    }
+    Ok(())
+}
+
+fn memory_map_stride() -> Result<(), &'static str> {
+    // Padded past our descriptor like firmware with a bigger descriptor version would
+    let stride = core::mem::size_of::<MemoryDescriptor>() + 16;
+    let types = [
+        MemoryType::LoaderCode,
+        MemoryType::Conventional,
+        MemoryType::RuntimeServicesData,
+    ];
+
+    let mut bytes = vec![0xAAu8; stride * types.len() + 8];
+    for (i, &memory_type) in types.iter().enumerate() {
+        let desc = MemoryDescriptor {
+            memory_type,
+            physical_address: i * 0x10000,
+            size: i + 1,
+            ..Default::default()
+        };
+        unsafe {
+            core::ptr::write_unaligned(bytes[i * stride..].as_mut_ptr() as *mut _, desc);
+        }
+    }
+
+    let map = MemoryMapIter::new(&bytes, stride).ok_or("padded stride rejected")?;
+    if map.len() != types.len() {
+        return Err("wrong descriptor count");
+    }
+    for (i, desc) in map.enumerate() {
+        if desc.memory_type != types[i] || desc.physical_address != i * 0x10000 {
+            return Err("descriptor read at the wrong offset");
+        }
+        if desc.size != i + 1 {
+            return Err("wrong descriptor size");
+        }
+    }
+
+    if MemoryMapIter::new(&bytes, stride - 24).is_some() {
+        return Err("stride smaller than a descriptor accepted");
+    }
+    Ok(())
+}
+
+fn slab_cache() -> Result<(), &'static str> {
+    let mut cache = SlabCache::<[u64; 5]>::new();
+    let per_slab = SlabCache::<[u64; 5]>::slots_per_slab();
+    // Enough to need a third slab
+    let count = per_slab * 2 + 3;
+
+    let mut objects = Vec::with_capacity(count);
+    for i in 0..count {
+        let ptr = cache.alloc();
+        if ptr.is_null() {
+            return Err("unable to allocate object");
+        }
+        if ptr as usize % core::mem::align_of::<[u64; 5]>() != 0 {
+            return Err("misaligned object");
+        }
+        unsafe { ptr.write([i as u64; 5]) };
+        objects.push(ptr);
+    }
+    if cache.capacity() != per_slab * 3 || cache.allocated() != count {
+        return Err("wrong slab count");
+    }
+    // Objects overlapping would have overwritten each other
+    for (i, &ptr) in objects.iter().enumerate() {
+        if unsafe { *ptr } != [i as u64; 5] {
+            return Err("objects overlap");
+        }
+    }
+
+    let freed = objects[per_slab];
+    unsafe { cache.free(freed) };
+    if cache.alloc() != freed {
+        return Err("freed slot wasn't reused");
+    }
+
+    for &ptr in &objects {
+        unsafe { cache.free(ptr) };
+    }
+    if cache.allocated() != 0 {
+        return Err("objects still allocated");
+    }
+    for _ in 0..count {
+        if cache.alloc().is_null() {
+            return Err("unable to reallocate object");
+        }
+    }
+    if cache.capacity() != per_slab * 3 {
+        return Err("freed slots weren't reused");
+    }
+    Ok(())
+}
+
+fn heap_stats() -> Result<(), &'static str> {
+    let before = allocator::stats();
+    let v: Vec<u8> = Vec::with_capacity(8192);
+    let during = allocator::stats();
+    drop(v);
+    let after = allocator::stats();
+
+    // Anything else allocating in between only adds to the counts
+    if during.allocated_bytes < before.allocated_bytes + 8192 {
+        return Err("allocation not counted");
+    }
+    if after.freed_bytes < before.freed_bytes + 8192 {
+        return Err("free not counted");
+    }
+    if during.peak_bytes < before.peak_bytes || after.peak_bytes < during.peak_bytes {
+        return Err("peak went down");
+    }
+    Ok(())
+}
+
+fn heap_sizing() -> Result<(), &'static str> {
+    if allocator::heap_size_for(size_gb!(4)) <= allocator::heap_size_for(size_gb!(1)) {
+        return Err("more memory didn't give a larger heap");
+    }
+
+    // A table of its own so nothing is mapped over the real heap
+    let mut table = Box::new(PageTable::new());
+    let mut mapper = unsafe { OffsetPageTable::new(&mut table, VirtAddr::new(PHYS_OFFSET)) };
+
+    let mut map = |start: u64, bytes: usize| {
+        let start = VirtAddr::new(start);
+        let mut frames = mem::allocator().lock();
+        let size =
+            allocator::map_heap_region(&mut mapper, &mut *frames, start, bytes, HeapAccess::Kernel)
+                .map_err(|_| "unable to map heap region")?;
+        drop(frames);
+        let pages = Page::<Size4KiB>::range(
+            Page::containing_address(start),
+            Page::containing_address(start + size as u64),
+        );
+        let mapped = pages
+            .clone()
+            .filter(|page| mapper.translate_page(*page).is_ok())
+            .count();
+        for page in pages {
+            if let Ok(frame) = mem::unmap(&mut mapper, page) {
+                mem::allocator().lock().free_frame(frame);
+            }
+        }
+        Ok::<_, &'static str>((size, mapped))
+    };
+
+    // Both round up to whole pages
+    let small = map(SCRATCH_PAGE, 4096 + 1)?;
+    let large = map(SCRATCH_PAGE + 0x100000, 3 * 4096 + 1)?;
+    if small != (2 * 4096, 2) || large != (4 * 4096, 4) {
+        return Err("wrong number of pages mapped");
+    }
+    Ok(())
+}
+
+fn compose() -> Result<(), &'static str> {
+    // Scroll lock as the compose key, then ' and e typed in either case
+    const COMPOSE_KEY: u8 = 0x46;
+    const QUOTE: u8 = 0x28;
+    const E: u8 = 0x12;
+
+    let mut keyboard = Keyboard::new();
+    keyboard.set_compose_key(Some(COMPOSE_KEY));
+    let typed = [COMPOSE_KEY, QUOTE, E].map(|code| keyboard.process(code));
+    if typed != [None, None, Some('é')] {
+        return Err("compose sequence didn't give é");
+    }
+
+    keyboard.process(COMPOSE_KEY);
+    keyboard.process(QUOTE);
+    keyboard.process(0x2A);
+    if keyboard.process(E) != Some('É') {
+        return Err("shifted compose sequence didn't give É");
+    }
+    keyboard.process(0xAA);
+
+    // x and y don't compose, so both come out as typed
+    keyboard.process(COMPOSE_KEY);
+    keyboard.process(0x2D);
+    if keyboard.process(0x15) != Some('x') || keyboard.take_pending() != Some('y') {
+        return Err("unmatched sequence didn't type both keys");
+    }
+    if keyboard.process(E) != Some('e') {
+        return Err("sequence didn't end");
+    }
+    Ok(())
+}
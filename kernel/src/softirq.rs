@@ -0,0 +1,89 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use common::x86_64::instructions::interrupts;
+use spin::Mutex;
+
+// Fixed size so work can be raised from interrupt context without touching the heap
+const QUEUE_SIZE: usize = 64;
+
+#[derive(Clone, Copy)]
+pub struct Work {
+    handler: fn(u64),
+    data: u64,
+}
+
+struct WorkQueue {
+    items: [Option<Work>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl WorkQueue {
+    const fn new() -> WorkQueue {
+        WorkQueue {
+            items: [None; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, work: Work) -> bool {
+        if self.len == QUEUE_SIZE {
+            return false;
+        }
+        self.items[(self.head + self.len) % QUEUE_SIZE] = Some(work);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<Work> {
+        if self.len == 0 {
+            return None;
+        }
+        let work = self.items[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        work
+    }
+}
+
+static QUEUE: Mutex<WorkQueue> = Mutex::new(WorkQueue::new());
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Queues `handler(data)` to run later with interrupts enabled. Returns false if the queue is
+/// full and the work was dropped.
+pub fn raise(handler: fn(u64), data: u64) -> bool {
+    interrupts::without_interrupts(|| QUEUE.lock().push(Work { handler, data }))
+}
+
+pub fn pending() -> bool {
+    interrupts::without_interrupts(|| QUEUE.lock().len > 0)
+}
+
+/// Drains the queue. Only one runner is active at a time, work raised while running is picked
+/// up by the same runner.
+pub fn run() {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    while let Some(work) = interrupts::without_interrupts(|| QUEUE.lock().pop()) {
+        (work.handler)(work.data);
+    }
+
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+/// One pass of the idle loop, runs queued work and then halts until the next interrupt. The queue
+/// is checked with interrupts off and `sti; hlt` only lets them in once the halt has started, so
+/// work raised right before halting wakes the cpu instead of waiting for the next timer tick.
+pub fn idle() {
+    run();
+
+    interrupts::disable();
+    if pending() {
+        interrupts::enable();
+        return;
+    }
+    interrupts::enable_and_hlt();
+}
@@ -1,18 +1,61 @@
 use core::{arch::asm, marker::PhantomData};
-use common::{process::{self, Process}, x86_64::{structures::paging::{OffsetPageTable, PageTable, PhysFrame, Size4KiB, Translate}, VirtAddr, registers::control::{Cr3, Cr3Flags}}};
+use common::{fd::{FdTable, FileObject}, kprintln, mem, memory_regions::PHYS_OFFSET, process::{self, Pid, Process, Signal, SIGSEGV, SIGTERM}, serial::SerialPort, x86_64::{structures::paging::{mapper::TranslateResult, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate}, VirtAddr, registers::control::{Cr3, Cr3Flags}}};
 
-use crate::{interrupt_begin, interrupt_end, interrupts::CpuSnapshot};
+use crate::{drivers::input, interrupt_begin, interrupt_end, interrupts::CpuSnapshot, process_manager};
+
+// Syscall ABI: the number goes in rax and up to three arguments in rdi, rsi and rdx (a fourth in
+// r10, like linux). The result comes back in rax. The syscall instruction itself overwrites rcx
+// and r11 with the return address and flags, every other register is preserved.
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_KILL: u64 = 2;
+pub const SYS_SIGNAL: u64 = 3;
+pub const SYS_SIGNAL_RETURN: u64 = 4;
+pub const SYS_YIELD: u64 = 5;
+pub const SYS_READ: u64 = 6;
+pub const SYS_GETPID: u64 = 7;
+
+// Returned in rax when a syscall fails
+const SYSCALL_ERROR: u64 = u64::MAX;
+// No syscall with that number, returned negated in rax like linux
+const ENOSYS: i64 = 38;
+
+// Processes only get to pass buffers below this
+const USER_END: u64 = 0x0000_8000_0000_0000;
+// Most bytes copied out of a write buffer at once
+const WRITE_CHUNK: usize = 256;
+
+// Exit code of a process killed by a signal, plus the signal like a shell reports it
+const EXIT_SIGNALED: u64 = 128;
+
+// Signal handlers start below the interrupted code's red zone
+const RED_ZONE: u64 = 128;
 
 enum SyscallType {
+    Exit,
     Write,
-    Unknown,
+    Kill,
+    Signal,
+    SignalReturn,
+    Yield,
+    Read,
+    GetPid,
+    Unknown(u64),
 }
 
 impl From<u64> for SyscallType {
     fn from(a: u64) -> Self {
         match a {
-            0 => SyscallType::Write,
-            _ => SyscallType::Unknown,
+            SYS_EXIT => SyscallType::Exit,
+            SYS_WRITE => SyscallType::Write,
+            SYS_KILL => SyscallType::Kill,
+            SYS_SIGNAL => SyscallType::Signal,
+            SYS_SIGNAL_RETURN => SyscallType::SignalReturn,
+            SYS_YIELD => SyscallType::Yield,
+            SYS_READ => SyscallType::Read,
+            SYS_GETPID => SyscallType::GetPid,
+            _ => SyscallType::Unknown(a),
         }
     }
 }
@@ -29,46 +72,48 @@ struct Four;
 
 impl<'a> Syscall<'a, SNone> {
     #[inline]
-    fn syscall(stype: SyscallType) {
-        unsafe { asm!("syscall", in("rax") stype as u64) }
+    fn syscall(number: u64) {
+        unsafe { asm!("syscall", in("rax") number, out("rcx") _, out("r11") _) }
     }
 }
 
 impl<'a> Syscall<'a, One> {
     #[inline]
-    fn syscall<A>(stype: SyscallType, a: A)
+    fn syscall<A>(number: u64, a: A)
     where
         A: Into<u64>,
     {
-        unsafe { asm!("syscall", in("rdi") stype as u64, in("r8") a.into()) }
+        unsafe { asm!("syscall", in("rax") number, in("rdi") a.into(), out("rcx") _, out("r11") _) }
     }
 }
 impl<'a> Syscall<'a, Two> {
     #[inline]
-    fn syscall<A, B>(stype: SyscallType, a: A, b: B)
+    fn syscall<A, B>(number: u64, a: A, b: B)
     where
         A: Into<u64>,
         B: Into<u64>,
     {
-        unsafe { asm!("syscall", in("rdi") stype as u64,in("r8") a.into(), in("r9") b.into()) }
+        unsafe {
+            asm!("syscall", in("rax") number, in("rdi") a.into(), in("rsi") b.into(), out("rcx") _, out("r11") _)
+        }
     }
 }
 impl<'a> Syscall<'a, Three> {
     #[inline]
-    fn syscall<A, B, C>(stype: SyscallType, a: A, b: B, c: C)
+    fn syscall<A, B, C>(number: u64, a: A, b: B, c: C)
     where
         A: Into<u64>,
         B: Into<u64>,
         C: Into<u64>,
     {
         unsafe {
-            asm!("syscall", in("rdi") stype as u64,in("r8") a.into(), in("r9") b.into(), in("r10") c.into())
+            asm!("syscall", in("rax") number, in("rdi") a.into(), in("rsi") b.into(), in("rdx") c.into(), out("rcx") _, out("r11") _)
         }
     }
 }
 impl<'a> Syscall<'a, Four> {
     #[inline]
-    fn syscall<A, B, C, D>(stype: SyscallType, a: A, b: B, c: C, d: D)
+    fn syscall<A, B, C, D>(number: u64, a: A, b: B, c: C, d: D)
     where
         A: Into<u64>,
         B: Into<u64>,
@@ -76,16 +121,17 @@ impl<'a> Syscall<'a, Four> {
         D: Into<u64>,
     {
         unsafe {
-            asm!("syscall", in("rdi") stype as u64,in("r8") a.into(), in("r9") b.into(), in("r10") c.into(), in("r12") d.into())
+            asm!("syscall", in("rax") number, in("rdi") a.into(), in("rsi") b.into(), in("rdx") c.into(), in("r10") d.into(), out("rcx") _, out("r11") _)
         }
     }
 }
 
 // #[naked]
 unsafe extern "C" fn syscall_entry_stub() {
+    // SFMASK (set in jump_usermode) clears IF on syscall
     interrupt_begin!();
 
-    let mut cpu: *const CpuSnapshot = core::ptr::null_mut();
+    let mut cpu: *mut CpuSnapshot = core::ptr::null_mut();
 
     asm!("mov rdx, rsp", options(nomem, nostack)); // Save old stack
     asm!("mov rsp, rax; mov rbp, rsp", in("rax") process::SYSCALL_SP, options(nostack)); // Load kernel stack
@@ -94,7 +140,7 @@ unsafe extern "C" fn syscall_entry_stub() {
 
     asm!("mov rax, cr3", out("rax") process::SYSCALL_UMAP, options(nostack)); // Save old address space
 
-    syscall_entry(&*cpu);
+    syscall_entry(&mut *cpu);
 
     asm!("mov cr3, rax", in("rax") process::SYSCALL_UMAP, options(nostack));
     asm!("mov rsp, rax", in("rax") process::SYSCALL_USP, options(nostack));
@@ -104,21 +150,259 @@ unsafe extern "C" fn syscall_entry_stub() {
     asm!("sysretq", options(noreturn));
 }
 
-fn syscall_entry(cpu: &CpuSnapshot) {
-    // kprint!(".")
-    // let syscall_type = cpu.rdi;
-    // let syscall_type = SyscallType::from(syscall_type);
-
-    // match syscall_type {
-    //     SyscallType::Write => {
-    //         let string = unsafe {
-    //             core::str::from_utf8(core::slice::from_raw_parts(cpu.r8 as *const u8, cpu.r9 as usize))
-    //         }
-    //         .unwrap();
-    //         kprint!("{}", string);
-    //     }
-    //     SyscallType::Unknown => (),
-    // }
+fn syscall_entry(cpu: &mut CpuSnapshot) {
+    // These replace the registers the stub returns with, so they get the whole snapshot
+    match SyscallType::from(cpu.rax) {
+        SyscallType::SignalReturn => {
+            sys_sigreturn();
+            return;
+        }
+        SyscallType::Yield => {
+            sys_yield(cpu);
+            return;
+        }
+        // Signals can't go to a process that's gone
+        SyscallType::Exit => {
+            cpu.rax = sys_exit(cpu.rdi);
+            return;
+        }
+        _ => cpu.rax = handle_syscall(cpu.rax, cpu.rdi, cpu.rsi, cpu.rdx),
+    }
+
+    deliver_signals(cpu);
+}
+
+/// Runs syscall `num` with its arguments and returns what goes back in rax. Signal return and
+/// yield only work through the entry stub and fail here.
+pub fn handle_syscall(num: u64, a: u64, b: u64, c: u64) -> u64 {
+    // Syscalls from outside a managed process still get the standard descriptors
+    let mut default_fds = FdTable::new();
+    let fds = match process_manager::current_process() {
+        Some(process) => &mut process.fds,
+        None => &mut default_fds,
+    };
+
+    match SyscallType::from(num) {
+        SyscallType::Exit => sys_exit(a),
+        SyscallType::Write => sys_write(fds, a as usize, b, c as usize),
+        SyscallType::Kill => sys_kill(Pid(a), b as Signal),
+        SyscallType::Signal => sys_signal(a as Signal, b),
+        SyscallType::Read => sys_read(fds, a as usize, b, c as usize),
+        SyscallType::GetPid => sys_getpid(),
+        SyscallType::SignalReturn | SyscallType::Yield => SYSCALL_ERROR,
+        SyscallType::Unknown(number) => {
+            if cfg!(not(feature = "release")) {
+                kprintln!("Unknown syscall {:#x}", number);
+            }
+            -ENOSYS as u64
+        }
+    }
+}
+
+// Only fails when called outside a process
+fn sys_exit(code: u64) -> u64 {
+    if process_manager::current().is_none() {
+        return SYSCALL_ERROR;
+    }
+    exit_process(code);
+    0
+}
+
+/// Ends the calling process. The stub then returns into whatever the schedular picked instead,
+/// so the caller never sees the syscall return. With nothing left to run the cpu halts here until
+/// an irq switches to a new process.
+fn exit_process(code: u64) {
+    match process_manager::exit_current(code) {
+        Some(next) => unsafe { next.resume_from_syscall() },
+        None => loop {
+            unsafe { asm!("sti; hlt") }
+        },
+    }
+}
+
+fn sys_getpid() -> u64 {
+    match process_manager::current_process() {
+        Some(process) => process.pid().0,
+        None => SYSCALL_ERROR,
+    }
+}
+
+fn sys_kill(pid: Pid, signal: Signal) -> u64 {
+    let raised = match process_manager::find(pid) {
+        Some(target) => target.process_mut().raise_signal(signal),
+        None => false,
+    };
+    if raised {
+        0
+    } else {
+        SYSCALL_ERROR
+    }
+}
+
+// A handler of 0 goes back to the default action. The handler is returned to with sysret, which
+// faults in ring 0 on a non canonical address, so only lower half addresses are taken.
+fn sys_signal(signal: Signal, handler: u64) -> u64 {
+    if handler >= USER_END {
+        return SYSCALL_ERROR;
+    }
+    let handler = if handler == 0 { None } else { Some(handler) };
+    let set = match process_manager::current_process() {
+        Some(process) => process.set_signal_handler(signal, handler),
+        None => false,
+    };
+    if set {
+        0
+    } else {
+        SYSCALL_ERROR
+    }
+}
+
+/// Puts the registers from before the handler ran back where the entry stub restores them from
+fn sys_sigreturn() {
+    let frame = process_manager::current().and_then(|p| p.take_signal_frame());
+    if let Some((user_sp, snapshot)) = frame {
+        unsafe {
+            *(user_sp as *mut CpuSnapshot) = snapshot;
+            process::SYSCALL_USP = user_sp;
+        }
+    }
+}
+
+/// Lets the schedular run the next process, the caller continues when it's picked again
+fn sys_yield(cpu: &mut CpuSnapshot) {
+    cpu.rax = 0;
+    if let Some(next) = process_manager::yield_current(cpu) {
+        unsafe { next.resume_from_syscall() }
+    }
+}
+
+/// Runs before going back to user mode. Handlers are entered by building a second register
+/// snapshot below the red zone that returns to the handler with the signal in rdi. The handler
+/// has to finish with the signal return syscall instead of returning.
+fn deliver_signals(cpu: &CpuSnapshot) {
+    let current = match process_manager::current() {
+        Some(current) => current,
+        None => return,
+    };
+    // Handlers don't nest, anything raised meanwhile waits for sigreturn
+    if current.in_signal_handler() {
+        return;
+    }
+
+    let signal = match current.process_mut().next_signal() {
+        Some(signal) => signal,
+        None => return,
+    };
+
+    match current.process().signal_handler(signal) {
+        Some(handler) => unsafe {
+            let size = core::mem::size_of::<CpuSnapshot>();
+            let user_sp = process::SYSCALL_USP;
+            let user_rsp = user_sp.wrapping_add(size as u64);
+
+            // Aligned like after a call. Wraps on a bogus stack pointer, which the check catches.
+            let handler_rsp = (user_rsp.wrapping_sub(RED_ZONE) & !0xF).wrapping_sub(8);
+            let handler_sp = handler_rsp.wrapping_sub(size as u64);
+            // The process's stack pointer can be anything, the frame only goes where it may write
+            if !valid_buffer(handler_sp, size, true) {
+                kprintln!("No room for a signal frame at {:#x}", handler_sp);
+                exit_process(EXIT_SIGNALED + SIGSEGV as u64);
+                return;
+            }
+            current.save_signal_frame(user_sp, *cpu);
+
+            let frame = &mut *(handler_sp as *mut CpuSnapshot);
+            *frame = *cpu;
+            frame.rcx = handler;
+            frame.rdi = signal as u64;
+            process::SYSCALL_USP = handler_sp;
+        },
+        None if signal == SIGTERM => exit_process(EXIT_SIGNALED + signal as u64),
+        None => kprintln!("Ignoring signal {}", signal),
+    }
+}
+
+/// Whether the caller may use the `len` bytes at `buffer`, and write to them if `write` is set. A
+/// process only gets lower half pages mapped user accessible, the kernel calling in directly
+/// just needs them mapped.
+fn valid_buffer(buffer: u64, len: usize, write: bool) -> bool {
+    if len == 0 {
+        return true;
+    }
+    let end = match buffer.checked_add(len as u64 - 1) {
+        Some(end) => end,
+        None => return false,
+    };
+    let user = process_manager::current().is_some();
+    if user && end >= USER_END {
+        return false;
+    }
+
+    // The syscall runs in the caller's address space
+    let mapper = mem::active_offset_page_table(PHYS_OFFSET);
+    let mut page = buffer & !0xFFF;
+    while page <= end {
+        let flags = match VirtAddr::try_new(page).map(|addr| mapper.translate(addr)) {
+            Ok(TranslateResult::Mapped { flags, .. }) => flags,
+            _ => return false,
+        };
+        if (user && !flags.contains(PageTableFlags::USER_ACCESSIBLE))
+            || (write && !flags.contains(PageTableFlags::WRITABLE))
+        {
+            return false;
+        }
+        page = match page.checked_add(4096) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    true
+}
+
+fn sys_write(fds: &FdTable, fd: usize, buffer: u64, len: usize) -> u64 {
+    let port = match fds.get(fd) {
+        Some(FileObject::Serial(port)) => SerialPort::from(*port),
+        None => return SYSCALL_ERROR,
+    };
+    if !valid_buffer(buffer, len, false) {
+        return SYSCALL_ERROR;
+    }
+
+    // Copied out first so the process can't change the bytes while they're being written
+    let mut chunk = [0u8; WRITE_CHUNK];
+    for offset in (0..len).step_by(WRITE_CHUNK) {
+        let count = WRITE_CHUNK.min(len - offset);
+        unsafe {
+            let from = (buffer + offset as u64) as *const u8;
+            core::ptr::copy_nonoverlapping(from, chunk.as_mut_ptr(), count);
+        }
+        if port.write(&chunk[..count]).is_err() {
+            return SYSCALL_ERROR;
+        }
+    }
+    len as u64
+}
+
+// Doesn't block, returns how many bytes were available
+fn sys_read(fds: &FdTable, fd: usize, buffer: u64, len: usize) -> u64 {
+    if !valid_buffer(buffer, len, true) {
+        return SYSCALL_ERROR;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts_mut(buffer as *mut u8, len) };
+    match fds.get(fd) {
+        Some(FileObject::Serial(port)) => {
+            let mut count = 0;
+            while count < len {
+                match input::serial_byte(*port) {
+                    Some(b) => bytes[count] = b,
+                    None => break,
+                }
+                count += 1;
+            }
+            count as u64
+        }
+        None => SYSCALL_ERROR,
+    }
 }
 
 #[inline(never)]
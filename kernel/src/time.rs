@@ -0,0 +1,380 @@
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    cmp::Ordering as Compare,
+    fmt,
+    future::Future,
+    ops::Add,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use alloc::collections::BinaryHeap;
+use common::{
+    efi, kassert, kprintln,
+    x86_64::instructions::{self, interrupts as cpu_interrupts},
+};
+use spin::Mutex;
+
+use crate::{
+    acpi::{self, PmTimer},
+    drivers::{pit, rtc},
+    interrupts::{self, CpuSnapshot, InterruptStackFrame},
+};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// How long the PIT and PM timer calibrations measure for
+const PIT_CALIBRATION_MS: u64 = 10;
+
+// Set once by init
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+// Unix time in nanoseconds when the wall clock was seeded and the monotonic time it was seeded at
+static SEED_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+static SEED_INSTANT: AtomicU64 = AtomicU64::new(0);
+
+/// Nanoseconds since boot from the TSC. Never goes backwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration.as_nanos() as u64))
+    }
+}
+
+/// Calendar time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+impl DateTime {
+    pub fn from_unix_nanos(nanos: u64) -> DateTime {
+        let seconds = nanos / NANOS_PER_SEC;
+        let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+        let in_day = seconds % 86400;
+
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (in_day / 3600) as u8,
+            minute: (in_day / 60 % 60) as u8,
+            second: (in_day % 60) as u8,
+            nanosecond: (nanos % NANOS_PER_SEC) as u32,
+        }
+    }
+
+    pub fn unix_nanos(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day).max(0) as u64;
+        let seconds =
+            days * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+        seconds * NANOS_PER_SEC + self.nanosecond as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            self.nanosecond / 1_000_000
+        )
+    }
+}
+
+// Days since 1970-01-01, from Howard Hinnant's date algorithms
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let month = month as i64;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// TSC ticks per second. Uses the frequency cpuid reports when it has one, otherwise measures it
+/// against the ACPI PM timer, or the PIT when there's no FADT.
+fn measure_tsc_frequency() -> u64 {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+
+    if max_leaf >= 0x15 {
+        let leaf = unsafe { __cpuid(0x15) };
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64;
+        }
+    }
+
+    if max_leaf >= 0x16 {
+        let mhz = unsafe { __cpuid(0x16) }.eax & 0xFFFF;
+        if mhz != 0 {
+            return mhz as u64 * 1_000_000;
+        }
+    }
+
+    match acpi::pm_timer() {
+        Some(timer) => pm_calibrate(timer),
+        None => pit_calibrate(),
+    }
+}
+
+// Counts TSC ticks over PIT_CALIBRATION_MS of the PM timer. That's far less than one wrap of
+// even the 24 bit counter.
+fn pm_calibrate(timer: PmTimer) -> u64 {
+    let ticks = (PmTimer::FREQUENCY * PIT_CALIBRATION_MS / 1000) as u32;
+
+    let start = timer.read();
+    let tsc_start = unsafe { _rdtsc() };
+    let mut elapsed = 0;
+    while elapsed < ticks {
+        core::hint::spin_loop();
+        elapsed = timer.ticks_between(start, timer.read());
+    }
+    let tsc_end = unsafe { _rdtsc() };
+
+    (tsc_end - tsc_start) * PmTimer::FREQUENCY / elapsed as u64
+}
+
+// Counts TSC ticks while PIT channel 2 counts down PIT_CALIBRATION_MS
+fn pit_calibrate() -> u64 {
+    let start = unsafe { _rdtsc() };
+    pit::wait_ms(PIT_CALIBRATION_MS as u32);
+    let end = unsafe { _rdtsc() };
+
+    (end - start) * 1000 / PIT_CALIBRATION_MS
+}
+
+// Converts firmware or RTC time to UTC
+fn utc(time: efi::Time) -> DateTime {
+    let local = DateTime {
+        year: time.year,
+        month: time.month,
+        day: time.day,
+        hour: time.hour,
+        minute: time.minute,
+        second: time.second,
+        nanosecond: time.nanosecond,
+    };
+
+    // Minutes from UTC, 0x7FF means unspecified
+    let zone = time.time_zone;
+    if zone == 0x7FF || zone == 0 {
+        local
+    } else {
+        let offset = zone as i64 * 60 * NANOS_PER_SEC as i64;
+        DateTime::from_unix_nanos((local.unix_nanos() as i64 - offset).max(0) as u64)
+    }
+}
+
+/// Seeds the wall clock from EFI GetTime, falling back to the RTC
+fn read_wall_clock() -> DateTime {
+    let time = efi::get_system_table()
+        .runtime_services()
+        .get_time()
+        .unwrap_or_else(|_| rtc::now());
+    utc(time)
+}
+
+pub fn init() {
+    BOOT_TSC.store(unsafe { _rdtsc() }, Ordering::SeqCst);
+    TSC_FREQUENCY.store(measure_tsc_frequency(), Ordering::SeqCst);
+
+    let wall = read_wall_clock();
+    SEED_INSTANT.store(now().as_nanos(), Ordering::SeqCst);
+    SEED_UNIX_NANOS.store(wall.unix_nanos(), Ordering::SeqCst);
+
+    kprintln!(
+        "Time: tsc {} MHz, wall clock {}",
+        tsc_frequency() / 1_000_000,
+        wall
+    );
+}
+
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::SeqCst)
+}
+
+/// Monotonic time since `init`. Zero before then.
+pub fn now() -> Instant {
+    let frequency = tsc_frequency();
+    if frequency == 0 {
+        return Instant(0);
+    }
+
+    let ticks = unsafe { _rdtsc() }.saturating_sub(BOOT_TSC.load(Ordering::SeqCst));
+    Instant((ticks as u128 * NANOS_PER_SEC as u128 / frequency as u128) as u64)
+}
+
+/// The wall clock read at boot advanced by the monotonic clock, so it never jumps
+pub fn wall_clock() -> DateTime {
+    let since_seed = now()
+        .as_nanos()
+        .saturating_sub(SEED_INSTANT.load(Ordering::SeqCst));
+    DateTime::from_unix_nanos(SEED_UNIX_NANOS.load(Ordering::SeqCst) + since_seed)
+}
+
+// A task waiting in `sleep`. Ordered so the earliest deadline is at the top of the max heap.
+struct Sleeper {
+    deadline: Instant,
+    waker: Waker,
+}
+
+impl PartialEq for Sleeper {
+    fn eq(&self, other: &Sleeper) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Sleeper {}
+
+impl PartialOrd for Sleeper {
+    fn partial_cmp(&self, other: &Sleeper) -> Option<Compare> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Sleeper {
+    fn cmp(&self, other: &Sleeper) -> Compare {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+// Drained by the timer irq, only locked with interrupts off. Created by `init_sleep`.
+static SLEEPERS: Mutex<Option<BinaryHeap<Sleeper>>> = Mutex::new(None);
+
+/// Wakes sleepers from the timer irq. Needs `init` to have run and the apic timer going.
+pub fn init_sleep() {
+    cpu_interrupts::without_interrupts(|| *SLEEPERS.lock() = Some(BinaryHeap::new()));
+    interrupts::register_handler(interrupts::TIMER_VECTOR, wake_sleepers);
+}
+
+// Only looks at sleepers that are due, so a tick costs O(log n) per wake no matter how many wait
+fn wake_sleepers(_frame: &mut InterruptStackFrame, _snapshot: &CpuSnapshot) {
+    let now = now();
+    let mut sleepers = SLEEPERS.lock();
+    let sleepers = match sleepers.as_mut() {
+        Some(sleepers) => sleepers,
+        None => return,
+    };
+    while sleepers.peek().map_or(false, |s| s.deadline <= now) {
+        if let Some(sleeper) = sleepers.pop() {
+            sleeper.waker.wake();
+        }
+    }
+}
+
+/// Future returned by `sleep`
+pub struct Sleep {
+    deadline: Instant,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        // Polling again before the deadline adds another entry, the stale one just wakes early
+        let sleeper = Sleeper {
+            deadline: self.deadline,
+            waker: cx.waker().clone(),
+        };
+        cpu_interrupts::without_interrupts(|| {
+            SLEEPERS
+                .lock()
+                .as_mut()
+                .expect("Sleep used before time::init_sleep!")
+                .push(sleeper)
+        });
+        Poll::Pending
+    }
+}
+
+/// Completes once `duration` has passed, to within a timer tick
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: now() + duration,
+    }
+}
+
+/// Ticks at `hz` covering at least `ms` milliseconds
+pub fn ms_to_ticks(ms: u64, hz: u32) -> u64 {
+    (ms * hz as u64 + 999) / 1000
+}
+
+/// Ticks between two reads of the tick counter. Wrapping, so a counter that overflowed in between
+/// still gives the right distance.
+pub fn ticks_between(start: u64, end: u64) -> u64 {
+    end.wrapping_sub(start)
+}
+
+/// Halts until `n` PIT ticks have passed. For delays in init code before there's anything to
+/// `sleep` on. Needs `pit::init` and interrupts enabled, otherwise the count never moves.
+pub fn sleep_ticks(n: u64) {
+    kassert!(pit::frequency() != 0, "sleep_ticks used before pit::init!");
+    kassert!(
+        cpu_interrupts::are_enabled(),
+        "sleep_ticks with interrupts disabled never wakes!"
+    );
+
+    let start = pit::ticks();
+    while ticks_between(start, pit::ticks()) < n {
+        instructions::hlt();
+    }
+}
+
+/// Halts for at least `ms` milliseconds, rounded up to the PIT's tick length
+pub fn sleep_ms(ms: u64) {
+    if ms == 0 {
+        return;
+    }
+    // The tick in progress may be almost over, so it doesn't count
+    sleep_ticks(ms_to_ticks(ms, pit::frequency()) + 1);
+}
@@ -1,24 +1,182 @@
 // use linked_list_allocator::LockedHeap;
 
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ops::Deref,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spinning_top::Spinlock;
+
 use crate::{
-    linked_list_allocator::{Heap, LockedHeap},
-    memory_regions::{HEAP_SIZE, HEAP_START},
+    kassert, mem,
+    memory_regions::{HEAP_MAX_SIZE, HEAP_REGION_END, HEAP_SIZE, HEAP_START, PHYS_OFFSET},
+    util,
 };
 
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, Mapper, Page, PageTableFlags,
-        Size4KiB,
+        mapper::MapToError, page::PageRangeInclusive, FrameAllocator, Mapper, OffsetPageTable,
+        Page, PageSize, PageTableFlags, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
+// The heap backend is picked with the `heap_bump` and `heap_slab` features, the linked list one is
+// the default. The loader hands its heap to the kernel as is, so both have to be built with the
+// same backend.
+#[cfg(feature = "heap_bump")]
+pub use crate::bump_allocator::Heap;
+#[cfg(not(any(feature = "heap_bump", feature = "heap_slab")))]
+pub use crate::linked_list_allocator::Heap;
+#[cfg(all(feature = "heap_slab", not(feature = "heap_bump")))]
+pub use crate::slab_allocator::Heap;
+
+// Fill patterns with the `heap_poison` feature. Reading either back means uninitialized memory or
+// a use after free.
+pub const ALLOC_POISON: u8 = 0xAA;
+pub const FREE_POISON: u8 = 0xDE;
+
+pub struct LockedHeap(Spinlock<Heap>);
+
+impl LockedHeap {
+    /// Creates an empty heap. All allocate calls will return `None`.
+    pub const fn empty() -> LockedHeap {
+        LockedHeap(Spinlock::new(Heap::empty()))
+    }
+
+    pub fn heap(&self) -> Heap {
+        self.0.lock().clone()
+    }
+}
+
+impl Deref for LockedHeap {
+    type Target = Spinlock<Heap>;
+
+    fn deref(&self) -> &Spinlock<Heap> {
+        &self.0
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self
+            .0
+            .lock()
+            .allocate_first_fit(layout)
+            .ok()
+            .map_or(0 as *mut u8, |allocation| allocation.as_ptr());
+
+        if cfg!(feature = "heap_poison") && !ptr.is_null() {
+            util::memset(ptr, ALLOC_POISON as i32, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if cfg!(feature = "heap_poison") {
+            util::memset(ptr, FREE_POISON as i32, layout.size());
+        }
+        self.0
+            .lock()
+            .deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+/// Counts the bytes that go through `A`. Each image counts from when it started, so the kernel
+/// also counts frees of what the loader allocated and freed can end up ahead of allocated.
+pub struct TrackingAllocator<A> {
+    inner: A,
+    allocated: AtomicUsize,
+    freed: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl<A> TrackingAllocator<A> {
+    pub const fn new(inner: A) -> TrackingAllocator<A> {
+        TrackingAllocator {
+            inner,
+            allocated: AtomicUsize::new(0),
+            freed: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated.load(Ordering::SeqCst)
+    }
+
+    pub fn freed_bytes(&self) -> usize {
+        self.freed.load(Ordering::SeqCst)
+    }
+
+    /// Most bytes live at once. Only updated on allocation, so with allocations racing it can be
+    /// off by whatever was freed in between.
+    pub fn peak_bytes(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+
+    pub fn live_bytes(&self) -> usize {
+        self.allocated_bytes().saturating_sub(self.freed_bytes())
+    }
+}
+
+impl<A> Deref for TrackingAllocator<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = self.allocated.fetch_add(layout.size(), Ordering::SeqCst);
+            let live = (allocated + layout.size()).saturating_sub(self.freed_bytes());
+            self.peak.fetch_max(live, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.freed.fetch_add(layout.size(), Ordering::SeqCst);
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: TrackingAllocator<LockedHeap> = TrackingAllocator::new(LockedHeap::empty());
 
+// Region of a lazily backed heap, pages are only mapped when first touched
+static LAZY_START: AtomicUsize = AtomicUsize::new(0);
+static LAZY_END: AtomicUsize = AtomicUsize::new(0);
+static LAZY_COMMITTED: AtomicUsize = AtomicUsize::new(0);
+static LAZY_COMMIT_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// Offset of the heap from HEAP_START, usize::MAX until it has been picked
+static HEAP_SLIDE: AtomicUsize = AtomicUsize::new(usize::MAX);
+const HEAP_SLIDE_ALIGN: usize = size_mb!(2);
+
+// Bytes mapped for the heap at HEAP_START + the slide
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(HEAP_SIZE);
+// Share of usable memory `heap_size_for` gives the heap
+const HEAP_MEMORY_FRACTION: usize = 16;
+
+/// Who can touch the heap's pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapAccess {
+    Kernel,
+    /// Also USER_ACCESSIBLE, for a heap user code allocates from directly
+    User,
+}
+
+// The heap's lock isn't held by the time this runs, so the stats can still be read
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    panic!("allocation error: {:?} {:?}", layout, stats())
 }
 
 pub fn init_heap(heap: &Heap) {
@@ -27,41 +185,278 @@ pub fn init_heap(heap: &Heap) {
     }
 }
 
+/// Offset to place the heap at from HEAP_START. With the `kaslr` feature this is a random 2MB
+/// aligned offset that keeps the heap below HEAP_REGION_END, otherwise it is always 0. It is
+/// picked on the first call and stays the same after.
+pub fn heap_slide() -> usize {
+    let slide = HEAP_SLIDE.load(Ordering::SeqCst);
+    if slide != usize::MAX {
+        return slide;
+    }
+
+    let slide = if cfg!(feature = "kaslr") {
+        let slots = (HEAP_REGION_END - HEAP_START - HEAP_MAX_SIZE) / HEAP_SLIDE_ALIGN;
+        util::rng::random_below(slots as u64) as usize * HEAP_SLIDE_ALIGN
+    } else {
+        0
+    };
+
+    match HEAP_SLIDE.compare_exchange(usize::MAX, slide, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => slide,
+        Err(existing) => existing,
+    }
+}
+
+/// Pages of the heap mapped by `init_heap_new` or `init_heap_sized` at HEAP_START + `offset`
 pub fn heap_range(offset: usize) -> PageRangeInclusive {
-    let heap_start = VirtAddr::new((HEAP_START + offset) as u64);
-    let heap_end = heap_start + (HEAP_SIZE + offset) - 1u64;
-    let heap_start_page = Page::<Size4KiB>::containing_address(heap_start);
-    let heap_end_page = Page::containing_address(heap_end);
+    region_range(VirtAddr::new((HEAP_START + offset) as u64), heap_size())
+}
+
+/// Bytes mapped for the heap, HEAP_SIZE unless `init_heap_sized` picked something else
+pub fn heap_size() -> usize {
+    HEAP_MAPPED.load(Ordering::SeqCst)
+}
+
+/// A sixteenth of `usable_memory`, kept between HEAP_SIZE and HEAP_MAX_SIZE
+pub fn heap_size_for(usable_memory: usize) -> usize {
+    (usable_memory / HEAP_MEMORY_FRACTION)
+        .max(HEAP_SIZE)
+        .min(HEAP_MAX_SIZE)
+}
+
+pub fn region_range(start: VirtAddr, size: usize) -> PageRangeInclusive {
+    let heap_start_page = Page::<Size4KiB>::containing_address(start);
+    let heap_end_page = Page::containing_address(start + size - 1u64);
     Page::range_inclusive(heap_start_page, heap_end_page)
 }
 
-pub fn init_heap_new(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+/// Maps a HEAP_SIZE heap at HEAP_START + `offset`, see `init_heap_region`
+pub fn init_heap_new<M, A>(
+    mapper: &mut M,
+    frame_allocator: &mut A,
     offset: usize,
-    user: bool,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = heap_range(offset);
+    access: HeapAccess,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
+{
+    init_heap_region(
+        mapper,
+        frame_allocator,
+        VirtAddr::new((HEAP_START + offset) as u64),
+        HEAP_SIZE,
+        access,
+    )?;
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::SeqCst);
+    Ok(())
+}
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
+/// Maps a kernel heap of `bytes` rounded up to pages at HEAP_START + `heap_slide`, see
+/// `heap_size_for` for picking a size. Panics if `bytes` is over HEAP_MAX_SIZE.
+pub fn init_heap_sized<M, A>(
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    bytes: usize,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
+{
+    kassert!(bytes <= HEAP_MAX_SIZE, "Heap too large! {:x}", bytes);
+    let size = init_heap_region(
+        mapper,
+        frame_allocator,
+        VirtAddr::new((HEAP_START + heap_slide()) as u64),
+        bytes,
+        HeapAccess::Kernel,
+    )?;
+    HEAP_MAPPED.store(size, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Maps `size` bytes (rounded up to pages) at `start` with `map_heap_region` and hands them to the
+/// allocator. The allocator is left untouched if the frame allocator runs out part way through.
+/// Gives the size that was mapped.
+pub fn init_heap_region<M, A>(
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    start: VirtAddr,
+    size: usize,
+    access: HeapAccess,
+) -> Result<usize, MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
+{
+    let size = map_heap_region(mapper, frame_allocator, start, size, access)?;
+
+    unsafe {
+        ALLOCATOR.lock().init(start.as_u64() as usize, size);
+    }
+
+    Ok(size)
+}
+
+/// Maps `size` bytes (rounded up to pages) at `start` with fresh frames and gives the size that
+/// was mapped. Whole 2MiB pages of the region are backed with 2MiB frames when the frame allocator
+/// can find them, which takes far fewer page tables.
+pub fn map_heap_region<M, A>(
+    mapper: &mut M,
+    frame_allocator: &mut A,
+    start: VirtAddr,
+    size: usize,
+    access: HeapAccess,
+) -> Result<usize, MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
+{
+    let page_range = region_range(start, size);
+    let size = page_range.count() * 4096;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | match access {
+            HeapAccess::Kernel => PageTableFlags::empty(),
+            HeapAccess::User => PageTableFlags::USER_ACCESSIBLE,
+        };
+
+    let end = start + size as u64;
+    let mut addr = start.align_down(4096u64);
+    while addr < end {
+        if addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE {
+            if let Some(frame) = <A as FrameAllocator<Size2MiB>>::allocate_frame(frame_allocator) {
+                mem::map_phys_huge(
+                    mapper,
+                    frame_allocator,
+                    frame.start_address(),
+                    addr,
+                    Size2MiB::SIZE as usize,
+                    flags,
+                )?;
+                addr += Size2MiB::SIZE;
+                continue;
+            }
+        }
+
+        let frame = <A as FrameAllocator<Size4KiB>>::allocate_frame(frame_allocator)
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT
-            | PageTableFlags::WRITABLE
-            | if user {
-                PageTableFlags::USER_ACCESSIBLE
-            } else {
-                PageTableFlags::empty()
-            };
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+        unsafe {
+            <M as Mapper<Size4KiB>>::map_to(
+                mapper,
+                Page::containing_address(addr),
+                frame,
+                flags,
+                frame_allocator,
+            )?
+            .flush()
+        };
+        addr += Size4KiB::SIZE;
     }
 
+    Ok(size)
+}
+
+/// Grows the heap over the rest of its HEAP_MAX_SIZE reservation without mapping any of it. Pages
+/// are backed with zeroed frames by `handle_heap_fault` as the allocator touches them, up to
+/// `commit_limit` bytes. The page fault handler and the frame allocator have to be set up before
+/// calling this. Gives the bytes added.
+pub fn init_heap_lazy(commit_limit: usize) -> usize {
+    let mut heap = ALLOCATOR.lock();
+    // The mapped part is whole pages, so the lazy part starts on one
+    let start = heap.top();
+    let end = heap.bottom() + HEAP_MAX_SIZE;
+    if start >= end {
+        return 0;
+    }
+
+    LAZY_START.store(start, Ordering::SeqCst);
+    LAZY_END.store(end, Ordering::SeqCst);
+    LAZY_COMMITTED.store(0, Ordering::SeqCst);
+    LAZY_COMMIT_LIMIT.store(commit_limit, Ordering::SeqCst);
+
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START + offset, HEAP_SIZE);
+        heap.extend(end - start);
     }
+    end - start
+}
 
-    Ok(())
+pub fn set_heap_commit_limit(limit: usize) {
+    LAZY_COMMIT_LIMIT.store(limit, Ordering::SeqCst);
+}
+
+pub fn heap_committed() -> usize {
+    LAZY_COMMITTED.load(Ordering::SeqCst)
+}
+
+/// Maps a fresh zeroed frame for a fault inside the lazy heap region. Returns false if the
+/// address isn't in the heap or the commit limit has been reached so the fault is a real one.
+/// Takes the frame allocator's lock, so nothing may allocate from the heap while holding it. The
+/// frame allocator itself never does, its free list is kept in the frames and its bitmap is sized
+/// once.
+pub fn handle_heap_fault(addr: VirtAddr) -> bool {
+    let addr = addr.as_u64() as usize;
+    if addr < LAZY_START.load(Ordering::SeqCst) || addr >= LAZY_END.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    let committed = LAZY_COMMITTED.load(Ordering::SeqCst);
+    if committed + 4096 > LAZY_COMMIT_LIMIT.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr as u64));
+    let mut frame_allocator = mem::allocator().lock();
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    let mut pt = mem::active_offset_page_table(PHYS_OFFSET);
+    match unsafe {
+        <OffsetPageTable as Mapper<Size4KiB>>::map_to(
+            &mut pt,
+            page,
+            frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            &mut *frame_allocator,
+        )
+    } {
+        Ok(flush) => flush.flush(),
+        Err(_) => return false,
+    }
+
+    unsafe {
+        util::memset(page.start_address().as_mut_ptr(), 0, 4096);
+    }
+    LAZY_COMMITTED.fetch_add(4096, Ordering::SeqCst);
+    true
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+    // Bytes requested through the global allocator, see TrackingAllocator
+    pub allocated_bytes: usize,
+    pub freed_bytes: usize,
+    pub peak_bytes: usize,
+    // Whether freed memory is being overwritten, see the `heap_poison` feature
+    pub poisoned: bool,
+}
+
+pub fn stats() -> HeapStats {
+    let heap = ALLOCATOR.lock();
+    HeapStats {
+        size: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+        allocated_bytes: ALLOCATOR.allocated_bytes(),
+        freed_bytes: ALLOCATOR.freed_bytes(),
+        peak_bytes: ALLOCATOR.peak_bytes(),
+        poisoned: cfg!(feature = "heap_poison"),
+    }
 }
 
 pub fn heap_top() -> usize {
@@ -69,5 +464,5 @@ pub fn heap_top() -> usize {
 }
 
 pub fn heap() -> Heap {
-    unsafe { ALLOCATOR.heap() }
+    ALLOCATOR.heap()
 }
@@ -0,0 +1,43 @@
+use crate::util;
+
+// POST code port, shows up on debug cards without needing a working console
+const POST_PORT: u16 = 0x80;
+
+/// Major init steps in the order they happen. The loader's are 0x1X and the kernel's 0x2X so the
+/// POST code alone says which binary got stuck.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Phase {
+    Loader = 0x10,
+    BootServicesExited = 0x11,
+    Gdt = 0x12,
+    Paging = 0x13,
+    LoaderHeap = 0x14,
+    KernelJump = 0x15,
+
+    Kernel = 0x20,
+    Heap = 0x21,
+    Memory = 0x22,
+    Acpi = 0x23,
+    Idt = 0x24,
+    Drivers = 0x25,
+    Processes = 0x26,
+    Usermode = 0x27,
+}
+
+static mut CURRENT: Option<Phase> = None;
+
+/// Records that init reached `phase`. Goes straight to serial and the POST port so the last one
+/// is still visible when the next step triple faults.
+pub fn phase(phase: Phase) {
+    unsafe {
+        CURRENT = Some(phase);
+        util::out8(POST_PORT, phase as u8);
+    }
+    crate::serial_println!("Boot phase {:#04x} {:?}", phase as u8, phase);
+}
+
+/// The last phase reached, for panic messages
+pub fn current_phase() -> Option<Phase> {
+    unsafe { CURRENT }
+}
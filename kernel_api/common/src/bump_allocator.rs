@@ -0,0 +1,79 @@
+use core::{alloc::Layout, ptr::NonNull};
+
+use crate::linked_list_allocator::align_up;
+
+/// Hands out memory by moving a pointer up the heap. Freeing only counts allocations, the whole
+/// heap is reclaimed once the last one is freed. Fastest of the backends but anything long lived
+/// pins everything allocated after it.
+#[derive(Clone)]
+pub struct Heap {
+    bottom: usize,
+    size: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl Heap {
+    pub const fn empty() -> Heap {
+        Heap {
+            bottom: 0,
+            size: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+
+    pub fn update(&mut self, other: &Heap) {
+        *self = other.clone();
+    }
+
+    /// # Unsafety
+    ///
+    /// The memory in `[heap_bottom, heap_bottom + heap_size)` must be unused
+    pub unsafe fn init(&mut self, heap_bottom: usize, heap_size: usize) {
+        self.bottom = heap_bottom;
+        self.size = heap_size;
+        self.next = heap_bottom;
+        self.allocations = 0;
+    }
+
+    pub fn allocate_first_fit(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        let start = align_up(self.next, layout.align());
+        let end = start.checked_add(layout.size()).ok_or(())?;
+        if end > self.top() {
+            return Err(());
+        }
+
+        self.next = end;
+        self.allocations += 1;
+        NonNull::new(start as *mut u8).ok_or(())
+    }
+
+    pub unsafe fn deallocate(&mut self, _ptr: NonNull<u8>, _layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.bottom;
+        }
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.bottom
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn top(&self) -> usize {
+        self.bottom + self.size
+    }
+
+    /// Includes memory that was freed but can't be reused yet
+    pub fn used(&self) -> usize {
+        self.next - self.bottom
+    }
+
+    pub fn free(&self) -> usize {
+        self.size - self.used()
+    }
+}
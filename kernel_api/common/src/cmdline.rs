@@ -0,0 +1,39 @@
+/// A space separated command line of `key=value` options and boolean flags, e.g.
+/// `loglevel=debug nosmp`
+#[derive(Debug, Clone, Copy)]
+pub struct CommandLine<'a> {
+    text: &'a str,
+}
+
+impl<'a> CommandLine<'a> {
+    pub const fn new(text: &'a str) -> CommandLine<'a> {
+        CommandLine { text }
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        self.text
+    }
+
+    /// Every option with its value, flags have none
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> {
+        self.text
+            .split_ascii_whitespace()
+            .map(|option| match option.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (option, None),
+            })
+    }
+
+    /// The value of the last `key=value`, later options override earlier ones
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.iter()
+            .filter(|(k, _)| *k == key)
+            .filter_map(|(_, v)| v)
+            .last()
+    }
+
+    /// Whether `key` was passed as a flag
+    pub fn flag(&self, key: &str) -> bool {
+        self.iter().any(|(k, v)| k == key && v.is_none())
+    }
+}
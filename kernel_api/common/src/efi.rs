@@ -1,13 +1,118 @@
-use core::{fmt::Debug, ptr::null, sync::atomic::AtomicPtr};
-
-use crate::{kprint, kprintln};
+use core::{fmt::Debug, marker::PhantomData, ptr::null, sync::atomic::AtomicPtr};
+
+use alloc::{string::String, vec::Vec};
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    framebuffer::{Framebuffer, PixelFormat},
+    kassert, kprint, kprintln,
+    util::{out16, out8},
+};
 
 pub type Char16 = u16;
 pub type Handle = usize;
 
 const EMPTY_HANDLE: Handle = 0;
 
-const BUFFER_TOO_SMALL: usize = 5 | (1 << 63);
+// Set in every error code, non-zero codes without it are warnings
+const ERROR_BIT: usize = 1 << 63;
+
+/// What every EFI call returns. Warnings mean the call did something, so only errors are failures.
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct EfiStatus(pub usize);
+
+impl EfiStatus {
+    pub const SUCCESS: EfiStatus = EfiStatus(0);
+    pub const LOAD_ERROR: EfiStatus = EfiStatus(ERROR_BIT | 1);
+    pub const INVALID_PARAMETER: EfiStatus = EfiStatus(ERROR_BIT | 2);
+    pub const UNSUPPORTED: EfiStatus = EfiStatus(ERROR_BIT | 3);
+    pub const BAD_BUFFER_SIZE: EfiStatus = EfiStatus(ERROR_BIT | 4);
+    pub const BUFFER_TOO_SMALL: EfiStatus = EfiStatus(ERROR_BIT | 5);
+    pub const NOT_READY: EfiStatus = EfiStatus(ERROR_BIT | 6);
+    pub const DEVICE_ERROR: EfiStatus = EfiStatus(ERROR_BIT | 7);
+    pub const WRITE_PROTECTED: EfiStatus = EfiStatus(ERROR_BIT | 8);
+    pub const OUT_OF_RESOURCES: EfiStatus = EfiStatus(ERROR_BIT | 9);
+    pub const VOLUME_CORRUPTED: EfiStatus = EfiStatus(ERROR_BIT | 10);
+    pub const VOLUME_FULL: EfiStatus = EfiStatus(ERROR_BIT | 11);
+    pub const NO_MEDIA: EfiStatus = EfiStatus(ERROR_BIT | 12);
+    pub const MEDIA_CHANGED: EfiStatus = EfiStatus(ERROR_BIT | 13);
+    pub const NOT_FOUND: EfiStatus = EfiStatus(ERROR_BIT | 14);
+    pub const ACCESS_DENIED: EfiStatus = EfiStatus(ERROR_BIT | 15);
+    pub const NO_RESPONSE: EfiStatus = EfiStatus(ERROR_BIT | 16);
+    pub const NO_MAPPING: EfiStatus = EfiStatus(ERROR_BIT | 17);
+    pub const TIMEOUT: EfiStatus = EfiStatus(ERROR_BIT | 18);
+    pub const NOT_STARTED: EfiStatus = EfiStatus(ERROR_BIT | 19);
+    pub const ALREADY_STARTED: EfiStatus = EfiStatus(ERROR_BIT | 20);
+    pub const ABORTED: EfiStatus = EfiStatus(ERROR_BIT | 21);
+    pub const END_OF_FILE: EfiStatus = EfiStatus(ERROR_BIT | 31);
+
+    const NAMES: &'static [(EfiStatus, &'static str)] = &[
+        (EfiStatus::SUCCESS, "SUCCESS"),
+        (EfiStatus::LOAD_ERROR, "LOAD_ERROR"),
+        (EfiStatus::INVALID_PARAMETER, "INVALID_PARAMETER"),
+        (EfiStatus::UNSUPPORTED, "UNSUPPORTED"),
+        (EfiStatus::BAD_BUFFER_SIZE, "BAD_BUFFER_SIZE"),
+        (EfiStatus::BUFFER_TOO_SMALL, "BUFFER_TOO_SMALL"),
+        (EfiStatus::NOT_READY, "NOT_READY"),
+        (EfiStatus::DEVICE_ERROR, "DEVICE_ERROR"),
+        (EfiStatus::WRITE_PROTECTED, "WRITE_PROTECTED"),
+        (EfiStatus::OUT_OF_RESOURCES, "OUT_OF_RESOURCES"),
+        (EfiStatus::VOLUME_CORRUPTED, "VOLUME_CORRUPTED"),
+        (EfiStatus::VOLUME_FULL, "VOLUME_FULL"),
+        (EfiStatus::NO_MEDIA, "NO_MEDIA"),
+        (EfiStatus::MEDIA_CHANGED, "MEDIA_CHANGED"),
+        (EfiStatus::NOT_FOUND, "NOT_FOUND"),
+        (EfiStatus::ACCESS_DENIED, "ACCESS_DENIED"),
+        (EfiStatus::NO_RESPONSE, "NO_RESPONSE"),
+        (EfiStatus::NO_MAPPING, "NO_MAPPING"),
+        (EfiStatus::TIMEOUT, "TIMEOUT"),
+        (EfiStatus::NOT_STARTED, "NOT_STARTED"),
+        (EfiStatus::ALREADY_STARTED, "ALREADY_STARTED"),
+        (EfiStatus::ABORTED, "ABORTED"),
+        (EfiStatus::END_OF_FILE, "END_OF_FILE"),
+    ];
+
+    pub fn is_success(self) -> bool {
+        self == EfiStatus::SUCCESS
+    }
+
+    pub fn is_error(self) -> bool {
+        self.0 & ERROR_BIT != 0
+    }
+
+    /// Err for errors, warnings count as Ok
+    pub fn ok(self) -> Result<(), EfiStatus> {
+        if self.is_error() {
+            Err(self)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Name of the code from the spec, None for codes this doesn't know
+    pub fn name(self) -> Option<&'static str> {
+        EfiStatus::NAMES
+            .iter()
+            .find(|(status, _)| *status == self)
+            .map(|(_, name)| *name)
+    }
+}
+
+impl Debug for EfiStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None if self.is_error() => write!(f, "ERROR({:#x})", self.0 & !ERROR_BIT),
+            None => write!(f, "WARNING({:#x})", self.0),
+        }
+    }
+}
 
 #[repr(C)]
 struct TableHeader {
@@ -95,7 +200,7 @@ pub struct RuntimeServices {
     /*
     Time services
     */
-    get_time: Handle,
+    get_time: extern "efiapi" fn(*mut Time, *mut ()) -> EfiStatus,
     set_time: Handle,
     get_wakeup_time: Handle,
     set_wakeup_time: Handle,
@@ -104,17 +209,195 @@ pub struct RuntimeServices {
     Virtual Memory services
     */
     set_virtual_address_map:
-        extern "efiapi" fn(usize, usize, u32, *const MemoryDescriptor) -> usize,
-    convert_pointer: extern "efiapi" fn() -> usize,
+        extern "efiapi" fn(usize, usize, u32, *const MemoryDescriptor) -> EfiStatus,
+    convert_pointer: extern "efiapi" fn() -> EfiStatus,
+
+    /*
+    Variable services
+    */
+    get_variable: Handle,
+    get_next_variable_name: Handle,
+    set_variable: Handle,
+
+    /*
+    Miscellaneous services
+    */
+    get_next_high_monotonic_count: Handle,
+    // Doesn't return when it works
+    reset_system: extern "efiapi" fn(ResetType, EfiStatus, usize, *const u8),
+}
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResetType {
+    /// Every circuit is reset, like a power cycle
+    Cold = 0,
+    /// Only the processors are reset, memory may be kept
+    Warm = 1,
+    Shutdown = 2,
+    PlatformSpecific = 3,
 }
 
 impl RuntimeServices {
-    pub fn set_virtual_address_map(&self, map: MemoryMap<'_>, version: u32) -> usize {
+    pub fn set_virtual_address_map(&self, map: MemoryMap<'_>, version: u32) -> EfiStatus {
         let map_size = core::mem::size_of_val(map);
         let entry_size = core::mem::size_of::<MemoryDescriptor>();
         let map_ptr = map.as_ptr();
         (self.set_virtual_address_map)(map_size, entry_size, version, map_ptr)
     }
+
+    /// Asks the firmware to reset or power off with `status` as the reason. Only returns if the
+    /// firmware couldn't do it.
+    pub fn reset_system(&self, kind: ResetType, status: EfiStatus) {
+        (self.reset_system)(kind, status, 0, core::ptr::null())
+    }
+
+    pub fn get_time(&self) -> Result<Time, EfiStatus> {
+        let mut time = Time::default();
+        (self.get_time)(&mut time, core::ptr::null_mut()).ok()?;
+        Ok(time)
+    }
+}
+
+// Reset control register, bit 1 picks a hard reset and bit 2 performs it. Bit 3 cycles power
+// too, which makes it a cold reset.
+const RESET_CONTROL: u16 = 0xCF9;
+// PM1a control block on QEMU's q35 and Bochs/older i440fx machines, SLP_EN with sleep type 0 (S5)
+const QEMU_PM1A_CONTROL: u16 = 0x604;
+const BOCHS_PM1A_CONTROL: u16 = 0xB004;
+const PM1_SLEEP_S5: u16 = 0x2000;
+
+fn firmware_reset(kind: ResetType) {
+    let table = GLOBAL_SYSTEM_TABLE.load(core::sync::atomic::Ordering::SeqCst);
+    if !table.is_null() {
+        get_system_table()
+            .runtime_services()
+            .reset_system(kind, EfiStatus::SUCCESS);
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { core::arch::asm!("cli; hlt") }
+    }
+}
+
+/// Resets the machine. ResetSystem is a runtime service, so the firmware does it as long as a
+/// system table is registered, before boot services exit or after from an address space with the
+/// runtime mappings (see `setup_runtime_virtual_map`). When there's no table or the firmware
+/// returns, the reset control register at 0xCF9 is used, then the keyboard controller's reset
+/// line.
+pub fn reset(kind: ResetType) -> ! {
+    firmware_reset(kind);
+
+    let reset = match kind {
+        ResetType::Cold => 0x0E,
+        _ => 0x06,
+    };
+    unsafe {
+        out8(RESET_CONTROL, 0x02);
+        out8(RESET_CONTROL, reset);
+        out8(0x64, 0xFE);
+    }
+    halt()
+}
+
+/// Powers the machine off. Goes through the firmware like `reset` does, falling back to putting
+/// QEMU's (or Bochs') ACPI PM1a control block into S5. Real hardware needs the sleep type from the
+/// DSDT's _S5 object for that, so without firmware it halts instead.
+pub fn shutdown() -> ! {
+    firmware_reset(ResetType::Shutdown);
+
+    unsafe {
+        out16(QEMU_PM1A_CONTROL, PM1_SLEEP_S5);
+        out16(BOCHS_PM1A_CONTROL, PM1_SLEEP_S5);
+    }
+    kprintln!("Unable to power off, halting");
+    halt()
+}
+
+// Wall clock when the loader started, so dates can be worked out before there's an RTC driver
+static mut BOOT_TIME: Option<Time> = None;
+
+/// Reads the firmware clock and keeps it as the boot time. Has to be called before the runtime
+/// services are moved by `setup_runtime_virtual_map`.
+pub fn read_boot_time() -> Option<Time> {
+    match get_system_table().runtime_services().get_time() {
+        Ok(time) => {
+            set_boot_time(time);
+            Some(time)
+        }
+        Err(res) => {
+            kprintln!("Unable to get time! {:?}", res);
+            None
+        }
+    }
+}
+
+/// Keeps `time` as the boot time, for the kernel to take over what the loader read
+pub fn set_boot_time(time: Time) {
+    unsafe {
+        BOOT_TIME.replace(time);
+    }
+}
+
+/// Wall clock from when the loader started, None if the firmware couldn't tell
+pub fn boot_time() -> Option<Time> {
+    unsafe { BOOT_TIME }
+}
+
+/// Maps every runtime region at `physical + offset` in `mapper` and hands the new addresses to
+/// the firmware. Returns a copy of `map` with the virtual addresses filled in.
+///
+/// Runtime services can only be called from an address space with these mappings afterwards and
+/// the system table has to be accessed at its physical address + `offset` as well. Has to be
+/// called after boot services have exited and can only succeed once.
+pub fn setup_runtime_virtual_map(
+    map: MemoryMap<'_>,
+    version: u32,
+    offset: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Vec<MemoryDescriptor>, EfiStatus> {
+    let map: Vec<MemoryDescriptor> = map
+        .iter()
+        .map(|desc| {
+            if !desc.is_runtime() {
+                return *desc;
+            }
+
+            let flags = match desc.memory_type {
+                MemoryType::RuntimeServicesCode => PageTableFlags::PRESENT,
+                _ => PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            };
+
+            for i in 0..desc.size {
+                let phys = (desc.physical_address + i * 4096) as u64;
+                let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(phys));
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(phys + offset));
+                // The mapper isn't necessarily the active table so there's nothing to flush
+                match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                    Ok(flush) => flush.ignore(),
+                    Err(MapToError::PageAlreadyMapped(_)) => (),
+                    Err(e) => panic!("Unable to map runtime services! {:?}", e),
+                }
+            }
+
+            MemoryDescriptor {
+                virtual_address: desc.physical_address + offset as usize,
+                ..*desc
+            }
+        })
+        .collect();
+
+    let runtime: Vec<MemoryDescriptor> = map.iter().filter(|d| d.is_runtime()).copied().collect();
+
+    get_system_table()
+        .runtime_services()
+        .set_virtual_address_map(runtime.as_ref(), version)
+        .ok()?;
+
+    Ok(map)
 }
 
 #[repr(C)]
@@ -133,10 +416,10 @@ pub struct BootServices {
     allocate_pages: Handle,
     free_pages: Handle,
     get_memory_map:
-        extern "efiapi" fn(&mut usize, *mut u8, &mut usize, &mut usize, &mut u32) -> usize,
-    // extern "efiapi" fn(&mut usize, &mut [MemoryDescriptor], &mut usize, &mut usize, &mut u32) -> usize,
-    allocate_pool: extern "efiapi" fn(MemoryType, usize, *mut *mut ()) -> usize,
-    free_pool: extern "efiapi" fn(*mut ()) -> usize,
+        extern "efiapi" fn(&mut usize, *mut u8, &mut usize, &mut usize, &mut u32) -> EfiStatus,
+    // extern "efiapi" fn(&mut usize, &mut [MemoryDescriptor], &mut usize, &mut usize, &mut u32) -> EfiStatus,
+    allocate_pool: extern "efiapi" fn(MemoryType, usize, *mut *mut ()) -> EfiStatus,
+    free_pool: extern "efiapi" fn(*mut ()) -> EfiStatus,
 
     /*
     Event & Timer Services
@@ -154,7 +437,7 @@ pub struct BootServices {
     install_protocol_interface: Handle,
     reinstall_protocol_interface: Handle,
     uninstall_protocol_interface: Handle,
-    handle_protocol: extern "efiapi" fn(Handle, *const guid::GUID, *mut *const ()) -> usize,
+    handle_protocol: extern "efiapi" fn(Handle, *const guid::GUID, *mut *const ()) -> EfiStatus,
     reserved: usize,
     register_protocol_notify: Handle,
     locate_handle: Handle,
@@ -168,14 +451,14 @@ pub struct BootServices {
     start_image: Handle,
     exit: Handle,
     image_unload: Handle,
-    exit_boot_services: extern "efiapi" fn(Handle, usize) -> usize,
+    exit_boot_services: extern "efiapi" fn(Handle, usize) -> EfiStatus,
 
     /*
     Miscellaneaous Services
     */
     get_next_monotonic_count: Handle,
     stall: Handle,
-    set_watchdog_timer: extern "efiapi" fn(usize, u64, usize, *const Char16) -> usize,
+    set_watchdog_timer: extern "efiapi" fn(usize, u64, usize, *const Char16) -> EfiStatus,
 
     /*
     Driver Support Services
@@ -183,14 +466,20 @@ pub struct BootServices {
     connect_controller: Handle,
     disconnect_controller: Handle,
 
-    open_protocol:
-        extern "efiapi" fn(Handle, *const guid::GUID, *mut *const (), Handle, Handle, u32) -> usize,
+    open_protocol: extern "efiapi" fn(
+        Handle,
+        *const guid::GUID,
+        *mut *const (),
+        Handle,
+        Handle,
+        u32,
+    ) -> EfiStatus,
     close_protocol: Handle,
     open_protocol_info: Handle,
 
     protocols_per_handle: Handle,
     locate_handle_buffer: Handle,
-    locate_protocol: extern "efiapi" fn(*const guid::GUID, *const (), *mut *const ()) -> usize,
+    locate_protocol: extern "efiapi" fn(*const guid::GUID, *const (), *mut *const ()) -> EfiStatus,
 }
 
 const OPEN_PROTOCOL_BY_HANDLE_PROTOCOL: u32 = 0x01;
@@ -201,7 +490,7 @@ impl BootServices {
         handle: Handle,
         guid: &guid::GUID,
         protocol: &mut *const T,
-    ) -> usize {
+    ) -> EfiStatus {
         unsafe {
             let ptr = protocol as *mut *const T;
             (self.handle_protocol)(handle, guid, ptr as *mut *const ())
@@ -216,7 +505,7 @@ impl BootServices {
         agent_handle: Handle,
         controller_handle: Handle,
         attributes: u32,
-    ) -> usize {
+    ) -> EfiStatus {
         unsafe {
             let ptr = interface as *mut *const T;
             (self.open_protocol)(
@@ -230,7 +519,14 @@ impl BootServices {
         }
     }
 
-    pub fn allocate_pool<T>(&self, size: usize, ptr: &mut *mut T) -> usize {
+    fn locate_protocol<T>(&self, guid: &guid::GUID, interface: &mut *const T) -> EfiStatus {
+        unsafe {
+            let ptr = interface as *mut *const T;
+            (self.locate_protocol)(guid, core::ptr::null(), ptr as *mut *const ())
+        }
+    }
+
+    pub fn allocate_pool<T>(&self, size: usize, ptr: &mut *mut T) -> EfiStatus {
         let ptr = ptr as *mut *mut T;
         (self.allocate_pool)(
             MemoryType::LoaderData,
@@ -239,12 +535,12 @@ impl BootServices {
         )
     }
 
-    pub fn free_pool<T: ?Sized>(&self, ptr: &mut T) -> usize {
+    pub fn free_pool<T: ?Sized>(&self, ptr: &mut T) -> EfiStatus {
         let ptr = ptr as *mut T;
         (self.free_pool)(ptr as *mut ())
     }
 
-    pub fn set_watchdog_timer(&self, timeout: usize, watchdog_code: u64) -> usize {
+    pub fn set_watchdog_timer(&self, timeout: usize, watchdog_code: u64) -> EfiStatus {
         (self.set_watchdog_timer)(timeout, watchdog_code, 0, core::ptr::null())
     }
 }
@@ -273,7 +569,8 @@ pub struct LoadedImage {
 #[repr(C, packed)]
 pub struct FileIOInterface {
     revision: u64,
-    pub open_volume: extern "efiapi" fn(*const FileIOInterface, *mut *const FileProtocol) -> usize,
+    pub open_volume:
+        extern "efiapi" fn(*const FileIOInterface, *mut *const FileProtocol) -> EfiStatus,
 }
 
 #[repr(C)]
@@ -285,34 +582,39 @@ pub struct FileProtocol {
         *const Char16,
         u64,
         u64,
-    ) -> usize,
-    pub close: extern "efiapi" fn(*const FileProtocol) -> usize,
-    pub delete: extern "efiapi" fn(*const FileProtocol) -> usize,
-    pub read: extern "efiapi" fn(*const FileProtocol, *mut usize, *mut u8) -> usize,
-    pub write: extern "efiapi" fn(*const FileProtocol) -> usize,
-    pub get_position: extern "efiapi" fn(*const FileProtocol) -> usize,
-    pub set_position: extern "efiapi" fn(*const FileProtocol, usize) -> usize,
-    pub get_info:
-        extern "efiapi" fn(*const FileProtocol, *const guid::GUID, *mut usize, *mut FileInfo) -> usize,
+    ) -> EfiStatus,
+    pub close: extern "efiapi" fn(*const FileProtocol) -> EfiStatus,
+    pub delete: extern "efiapi" fn(*const FileProtocol) -> EfiStatus,
+    pub read: extern "efiapi" fn(*const FileProtocol, *mut usize, *mut u8) -> EfiStatus,
+    pub write: extern "efiapi" fn(*const FileProtocol) -> EfiStatus,
+    pub get_position: extern "efiapi" fn(*const FileProtocol) -> EfiStatus,
+    pub set_position: extern "efiapi" fn(*const FileProtocol, usize) -> EfiStatus,
+    pub get_info: extern "efiapi" fn(
+        *const FileProtocol,
+        *const guid::GUID,
+        *mut usize,
+        *mut u8,
+    ) -> EfiStatus,
 }
 
 #[repr(C, packed)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy)]
 pub struct Time {
-    year: u16,
-    month: u8,
-    day: u8,
-    hour: u8,
-    minute: u8,
-    second: u8,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
     pad1: u8,
-    nanosecond: u32,
-    time_zone: i16,
-    daylight: u8,
+    pub nanosecond: u32,
+    pub time_zone: i16,
+    pub daylight: u8,
     pad2: u8,
 }
 
-#[repr(C, packed)]
+/// An EFI_FILE_INFO record, what GetInfo with `guid::FILE_INFO` and reading a directory return.
+/// The firmware's record ends with the name, so it's copied out instead of being read in place.
 #[derive(Debug)]
 pub struct FileInfo {
     pub size: usize,
@@ -322,18 +624,125 @@ pub struct FileInfo {
     pub last_access_time: Time,
     pub modification_time: Time,
     pub attribute: u64,
-    pub file_name: [u16; 10],
+    // Without the null terminator
+    file_name: Vec<u16>,
 }
 
 impl Default for FileInfo {
     fn default() -> Self {
         FileInfo {
-            file_name: [0; 10],
-            ..Default::default()
+            size: 0,
+            file_size: 0,
+            physical_size: 0,
+            create_time: Time::default(),
+            last_access_time: Time::default(),
+            modification_time: Time::default(),
+            attribute: 0,
+            file_name: Vec::new(),
+        }
+    }
+}
+
+impl FileInfo {
+    // Size of the fields before the name
+    const NAME_OFFSET: usize = 80;
+
+    /// Parses a record from the firmware. The name runs to its null terminator or the record's
+    /// size, whichever comes first. None if `bytes` is too short for the fixed fields.
+    pub fn from_bytes(bytes: &[u8]) -> Option<FileInfo> {
+        if bytes.len() < FileInfo::NAME_OFFSET {
+            return None;
         }
+        let usize_at = |offset: usize| {
+            u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap()) as usize
+        };
+        let time_at = |offset: usize| unsafe {
+            core::ptr::read_unaligned(bytes[offset..].as_ptr() as *const Time)
+        };
+
+        let size = usize_at(0).min(bytes.len()).max(FileInfo::NAME_OFFSET);
+        let file_name = bytes[FileInfo::NAME_OFFSET..size]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+
+        Some(FileInfo {
+            size: usize_at(0),
+            file_size: usize_at(8),
+            physical_size: usize_at(16),
+            create_time: time_at(24),
+            last_access_time: time_at(40),
+            modification_time: time_at(56),
+            attribute: usize_at(72) as u64,
+            file_name,
+        })
+    }
+
+    /// The name as UTF-16, without the null terminator
+    pub fn file_name(&self) -> &[u16] {
+        &self.file_name
+    }
+
+    /// The name decoded from UTF-16, with anything invalid replaced
+    pub fn name(&self) -> String {
+        core::char::decode_utf16(self.file_name.iter().copied())
+            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .collect()
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.attribute & FILE_DIRECTORY != 0
     }
 }
 
+/// GetInfo for `file` read into `buffer`, which is grown to whatever size the firmware asks for
+/// when the name doesn't fit. Keeping the buffer around saves allocating it for every file.
+pub fn get_file_info(file: &FileProtocol, buffer: &mut Vec<u8>) -> Option<FileInfo> {
+    if buffer.len() < FileInfo::NAME_OFFSET {
+        buffer.resize(FileInfo::NAME_OFFSET, 0);
+    }
+
+    loop {
+        let mut size = buffer.len();
+        let res = (file.get_info)(file, &guid::FILE_INFO, &mut size, buffer.as_mut_ptr());
+        if res == EfiStatus::BUFFER_TOO_SMALL {
+            buffer.resize(size, 0);
+            continue;
+        }
+        if res.is_error() {
+            kprintln!("An error occured! {:?} GETINFO(SFSP)", res);
+            return None;
+        }
+        return FileInfo::from_bytes(&buffer[..size]);
+    }
+}
+
+/// Entries of an opened directory from its current position, a freshly opened one starts at the
+/// first. Each read of a directory returns one FileInfo record and an empty read marks the end.
+/// `.` and `..` are listed like any other entry.
+pub fn read_dir(dir: &FileProtocol) -> impl Iterator<Item = FileInfo> + '_ {
+    // Grown when an entry's name doesn't fit
+    let mut buffer = alloc::vec![0u8; FileInfo::NAME_OFFSET + 256];
+
+    core::iter::from_fn(move || loop {
+        let mut size = buffer.len();
+        let res = (dir.read)(dir, &mut size, buffer.as_mut_ptr());
+        if res == EfiStatus::BUFFER_TOO_SMALL {
+            buffer.resize(size, 0);
+            continue;
+        }
+        if res.is_error() {
+            kprintln!("An error occured! {:?} READ(DIR)", res);
+            return None;
+        }
+        if size == 0 {
+            return None;
+        }
+        return FileInfo::from_bytes(&buffer[..size]);
+    })
+}
+
 pub fn io_volume(image_handle: Handle) -> *const FileIOInterface {
     let table = get_system_table();
 
@@ -349,8 +758,8 @@ pub fn io_volume(image_handle: Handle) -> *const FileIOInterface {
             EMPTY_HANDLE,
             OPEN_PROTOCOL_BY_HANDLE_PROTOCOL,
         );
-        if res != 0 {
-            kprintln!("An error occured! {:x} HandleProtocol(LIP)", res);
+        if res.is_error() {
+            kprintln!("An error occured! {:?} HandleProtocol(LIP)", res);
         }
 
         kprintln!("{:x?}", *loaded_image);
@@ -364,19 +773,19 @@ pub fn io_volume(image_handle: Handle) -> *const FileIOInterface {
             OPEN_PROTOCOL_BY_HANDLE_PROTOCOL,
         );
 
-        if res != 0 {
-            kprintln!("An error occured! {:x} HandleProtocol(SFSP)", res);
+        if res.is_error() {
+            kprintln!("An error occured! {:?} HandleProtocol(SFSP)", res);
         }
         io_volume
     }
 }
 
-pub fn read_fixed(file: &FileProtocol, offset: usize, size: usize, buffer: &mut [u8]) -> usize {
+pub fn read_fixed(file: &FileProtocol, offset: usize, size: usize, buffer: &mut [u8]) -> EfiStatus {
     let mut read = 0usize;
 
     // let status = (file.set_position)(file, offset + read);
     // if status != 0 {
-    //     kprintln!("An error occured! {:x} SETPOSTIOIN(SFSP)", status);
+    //     kprintln!("An error occured! {:?} SETPOSTIOIN(SFSP)", status);
     //     return status;
     // }
 
@@ -386,7 +795,7 @@ pub fn read_fixed(file: &FileProtocol, offset: usize, size: usize, buffer: &mut
     (file.read)(file, &mut remain, buffer.as_mut_ptr())
     // if status != 0 {
     //     kprintln!(
-    //         "An error occured! {:x} READ(SFSP) {} {} {:p}",
+    //         "An error occured! {:?} READ(SFSP) {} {} {:p}",
     //         status,
     //         remain,
     //         read,
@@ -401,10 +810,72 @@ pub fn read_fixed(file: &FileProtocol, offset: usize, size: usize, buffer: &mut
     // 0
 }
 
+/// Reads all of the file at `path` on the volume the image was loaded from. `/` separators are
+/// accepted as well as `\`. Has to be called before boot services exit.
+pub fn read_file(image_handle: Handle, path: &str) -> Option<Vec<u8>> {
+    let volume = io_volume(image_handle);
+    if volume.is_null() {
+        return None;
+    }
+    let volume = unsafe { &*volume };
+
+    let mut root: *const FileProtocol = core::ptr::null();
+    let res = (volume.open_volume)(volume, &mut root);
+    if res.is_error() {
+        kprintln!("An error occured! {:?} OpenVolume(SFSP)", res);
+        return None;
+    }
+    let root = unsafe { &*root };
+
+    // Null terminated UTF-16 with the separators EFI expects
+    let path: Vec<Char16> = path
+        .encode_utf16()
+        .map(|c| if c == b'/' as u16 { b'\\' as u16 } else { c })
+        .chain(core::iter::once(0))
+        .collect();
+
+    let mut file: *const FileProtocol = core::ptr::null();
+    let res = (root.open)(root, &mut file, path.as_ptr(), FILE_MODE_READ, 0);
+    (root.close)(root);
+    if res.is_error() {
+        kprintln!("An error occured! {:?} OPEN(SFSP)", res);
+        return None;
+    }
+    let file = unsafe { &*file };
+
+    let data = read_all(file);
+    (file.close)(file);
+    data
+}
+
+// Reads `file` from its current position to the size GetInfo reports
+fn read_all(file: &FileProtocol) -> Option<Vec<u8>> {
+    let info = get_file_info(file, &mut Vec::new())?;
+    let mut data = alloc::vec![0u8; info.file_size];
+
+    // Reads can come back short, so keep going until the file's all there
+    let mut read = 0;
+    while read < data.len() {
+        let mut size = data.len() - read;
+        let res = (file.read)(file, &mut size, data[read..].as_mut_ptr());
+        if res.is_error() {
+            kprintln!("An error occured! {:?} READ(SFSP)", res);
+            return None;
+        }
+        if size == 0 {
+            break;
+        }
+        read += size;
+    }
+    data.truncate(read);
+    Some(data)
+}
+
 pub const FILE_MODE_READ: u64 = 1;
 pub const FILE_READ_ONLY: u64 = 1;
 pub const FILE_HIDDEN: u64 = 2;
 pub const FILE_SYSTEM: u64 = 4;
+pub const FILE_DIRECTORY: u64 = 0x10;
 
 #[repr(C, packed)]
 pub struct FileHandle {}
@@ -498,6 +969,53 @@ impl MemoryDescriptor {
 
 pub type MemoryMap<'a> = &'a [MemoryDescriptor];
 
+/// Walks a memory map as the firmware returns it, one descriptor every `descriptor_size` bytes.
+/// The firmware's descriptors can be larger than ours, whatever follows our fields is skipped.
+#[derive(Clone)]
+pub struct MemoryMapIter<'a> {
+    buffer: *const u8,
+    descriptor_size: usize,
+    remaining: usize,
+    _buffer: PhantomData<&'a [u8]>,
+}
+
+impl<'a> MemoryMapIter<'a> {
+    /// None if `descriptor_size` is smaller than a MemoryDescriptor. Bytes after the last whole
+    /// descriptor are ignored.
+    pub fn new(buffer: &'a [u8], descriptor_size: usize) -> Option<MemoryMapIter<'a>> {
+        if descriptor_size < core::mem::size_of::<MemoryDescriptor>() {
+            return None;
+        }
+        Some(MemoryMapIter {
+            buffer: buffer.as_ptr(),
+            descriptor_size,
+            remaining: buffer.len() / descriptor_size,
+            _buffer: PhantomData,
+        })
+    }
+}
+
+impl<'a> Iterator for MemoryMapIter<'a> {
+    type Item = MemoryDescriptor;
+
+    fn next(&mut self) -> Option<MemoryDescriptor> {
+        if self.remaining == 0 {
+            return None;
+        }
+        // Pool memory is 8 byte aligned but the stride doesn't have to keep it that way
+        let desc = unsafe { core::ptr::read_unaligned(self.buffer as *const MemoryDescriptor) };
+        self.buffer = self.buffer.wrapping_add(self.descriptor_size);
+        self.remaining -= 1;
+        Some(desc)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for MemoryMapIter<'a> {}
+
 // #[repr(C)]
 // pub struct SimpleTextOutputProtocol {
 //     reset: extern "efiapi" fn(*mut Self),
@@ -539,51 +1057,201 @@ pub unsafe fn register_global_system_table(
 //         ((*out).output_string)(out, buff.as_ptr());
 //     }
 // }
-pub static mut DESCRIPTORS: [MemoryDescriptor; 1024] = [MemoryDescriptor {
-    attributes: 0,
-    memory_type: MemoryType::Reserved,
-    physical_address: 0,
-    r1: 0,
-    size: 0,
-    virtual_address: 0,
-}; 1024];
-
-pub fn get_memory_map(image_handle: Handle) -> (MemoryMap<'static>, u32) {
-    let table = GLOBAL_SYSTEM_TABLE.load(core::sync::atomic::Ordering::SeqCst);
+static mut MEMORY_MAP: MemoryMap<'static> = &[];
 
-    unsafe {
-        let mut size = core::mem::size_of_val(&DESCRIPTORS);
+/// The memory map from when boot services were exited
+pub fn memory_map() -> MemoryMap<'static> {
+    unsafe { MEMORY_MAP }
+}
+
+/// Gets the memory map and exits boot services with it. The buffer is pool allocated and grown
+/// whenever the firmware says it's too small, so there's no limit on the number of descriptors.
+/// Once exiting has been tried it can't be grown, a map that outgrows it then is an error.
+/// Errors from allocating, getting the map or exiting are returned, after which boot services
+/// are still up (only partly, if exiting was tried). Gives the descriptors as the firmware laid them out and the descriptor version,
+/// `pack_memory_map` turns them into a MemoryMap. The count comes from the size the firmware
+/// reported, there's no terminating descriptor to look for.
+pub fn get_memory_map(image_handle: Handle) -> Result<(MemoryMapIter<'static>, u32), EfiStatus> {
+    let boot_services = get_system_table().boot_services();
+
+    let mut buffer: *mut u8 = core::ptr::null_mut();
+    let mut capacity = 0;
+    let mut size;
+    let mut mdesc_size = 0;
+    let mut mdesc_version = 0;
+    // Once ExitBootServices has been called, even if it failed, only GetMemoryMap and
+    // ExitBootServices may be used so the buffer can't be freed or grown anymore
+    let mut exiting = false;
+
+    loop {
+        size = capacity;
         let mut key = 0;
-        let mut mdesc_size = 0;
-        let mut mdesc_version = 0;
 
-        let result = ((*(*table).boot_services).get_memory_map)(
+        // The first call has no buffer and only tells us how big the map is
+        let result = (boot_services.get_memory_map)(
             &mut size,
-            DESCRIPTORS.as_mut_ptr() as *mut u8,
+            buffer,
             &mut key,
             &mut mdesc_size,
             &mut mdesc_version,
         );
 
-        assert!(result == 0, " {:x?} {:x}", result, BUFFER_TOO_SMALL);
+        if result == EfiStatus::BUFFER_TOO_SMALL {
+            if exiting {
+                return Err(result);
+            }
+            if !buffer.is_null() {
+                (boot_services.free_pool)(buffer as *mut ());
+            }
+            // Allocating the buffer can split a region and add descriptors, so leave some slack
+            capacity = size + 4096;
+            boot_services.allocate_pool(capacity, &mut buffer).ok()?;
+            continue;
+        }
 
-        // print_memory_map(&DESCRIPTORS);
+        let result = match result.ok() {
+            Ok(()) => {
+                exiting = true;
+                match (boot_services.exit_boot_services)(image_handle, key) {
+                    // The map changed since we got it, the spec says to get it again and retry.
+                    // The slack left when allocating should cover the change.
+                    EfiStatus::INVALID_PARAMETER => continue,
+                    result => result.ok(),
+                }
+            }
+            Err(status) => Err(status),
+        };
+        if let Err(status) = result {
+            if !buffer.is_null() && !exiting {
+                (boot_services.free_pool)(buffer as *mut ());
+            }
+            return Err(status);
+        }
+        break;
+    }
+    kprintln!("Exited boot services!");
+
+    kassert!(
+        mdesc_size >= core::mem::size_of::<MemoryDescriptor>(),
+        "Memory descriptor too small! {}",
+        mdesc_size
+    );
+    Ok((
+        MemoryMapIter {
+            buffer,
+            descriptor_size: mdesc_size,
+            remaining: size / mdesc_size,
+            _buffer: PhantomData,
+        },
+        mdesc_version,
+    ))
+}
 
-        let result = ((*(*table).boot_services).exit_boot_services)(image_handle, key);
-        assert!(result == 0, "Unable to exit boot services! {:x}", result);
-        kprintln!("Exited boot services!");
-        return (&DESCRIPTORS, mdesc_version);
+/// Moves the descriptors to the start of their buffer so they can be used as a slice and makes
+/// that the map `memory_map` returns. Each descriptor is read before the next write, which never
+/// lands past it since the stride is at least a descriptor.
+///
+/// # Safety
+/// The buffer behind `map` has to be writable and nothing else may be using it, like the map
+/// `get_memory_map` returns.
+pub unsafe fn pack_memory_map(map: MemoryMapIter<'static>) -> MemoryMap<'static> {
+    let descriptors = map.buffer as *mut MemoryDescriptor;
+    let count = map.len();
+    for (i, desc) in map.enumerate() {
+        core::ptr::write(descriptors.add(i), desc);
     }
+
+    MEMORY_MAP = core::slice::from_raw_parts(descriptors, count);
+    MEMORY_MAP
+}
+
+pub fn print_config_tables() {
+    for (guid, ptr) in get_system_table().config_tables() {
+        match guid::name(guid) {
+            Some(name) => kprintln!("{:p} {}", ptr, name),
+            None => kprintln!("{:p} {}", ptr, guid),
+        }
+    }
+}
+
+/// Root System Description Pointer, where the ACPI tables are found from
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_address: u32,
+    // Revision 2+
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+impl Rsdp {
+    pub const SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+    // Size of the revision 0 structure covered by `checksum`
+    const V1_LENGTH: usize = 20;
+
+    /// Copies the RSDP at `ptr`. A revision 0 RSDP is only `V1_LENGTH` bytes long, so nothing past
+    /// that is read and the revision 2 fields are left zero.
+    ///
+    /// # Unsafety
+    ///
+    /// `ptr` must point to a readable RSDP
+    pub unsafe fn read(ptr: *const Rsdp) -> Rsdp {
+        let mut rsdp = core::mem::zeroed::<Rsdp>();
+        let length = match (*ptr).revision {
+            0 => Self::V1_LENGTH,
+            _ => core::mem::size_of::<Rsdp>(),
+        };
+        core::ptr::copy_nonoverlapping(ptr as *const u8, &mut rsdp as *mut _ as *mut u8, length);
+        rsdp
+    }
+
+    /// Revision 2+ RSDPs point to a 64 bit XSDT, older ones only have the 32 bit RSDT
+    pub fn has_xsdt(&self) -> bool {
+        self.revision >= 2 && self.xsdt_address != 0
+    }
+
+    /// `checksum` covers the revision 0 fields, revision 2+ also has `extended_checksum` over
+    /// `length` bytes. Both have to sum to zero.
+    pub fn validate_checksum(&self) -> bool {
+        let length = match self.revision {
+            0 => Self::V1_LENGTH,
+            _ => core::mem::size_of::<Rsdp>(),
+        };
+        let bytes = unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, length) };
+        if !checksum(&bytes[..Self::V1_LENGTH]) {
+            return false;
+        }
+        self.revision < 2 || checksum(&bytes[..(self.length as usize).min(bytes.len())])
+    }
+}
+
+fn checksum(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// The RSDP from the configuration table, the ACPI 2.0 one if the firmware has it and the ACPI 1.0
+/// one otherwise. The pointer is the physical address the firmware put there, so it can only be
+/// read directly while memory is identity mapped.
+pub fn find_rsdp() -> Option<*const Rsdp> {
+    let find = |wanted: &guid::GUID| {
+        get_system_table()
+            .config_tables()
+            .find(|(guid, _)| *guid == wanted)
+            .map(|(_, ptr)| ptr as *const Rsdp)
+    };
+    find(&guid::RSDP).or_else(|| find(&guid::RSDP_V1))
 }
 
 pub fn print_memory_map(map: MemoryMap<'_>) {
     let mut conventional = 0;
     let mut all = 0;
     for desc in map {
-        if desc.physical_address == 0 && desc.virtual_address == 0 && desc.size == 0 {
-            break;
-        }
-
         all += desc.size * 4096;
         // if desc.memory_type.is_usable() {
         // }
@@ -606,15 +1274,23 @@ pub fn print_memory_map(map: MemoryMap<'_>) {
 pub fn get_mem_size(map: MemoryMap<'_>) -> usize {
     let mut all = 0;
     for desc in map {
-        if desc.physical_address == 0 && desc.virtual_address == 0 && desc.size == 0 {
-            break;
-        }
         all += desc.size * 4096;
     }
     all
 }
 
-pub fn get_image_base(image_handle: Handle) -> usize {
+/// Bytes of memory the frame allocator can hand out, see `MemoryType::is_usable`
+pub fn get_usable_mem_size(map: MemoryMap<'_>) -> usize {
+    let mut usable = 0;
+    for desc in map {
+        if desc.memory_type.is_usable() {
+            usable += desc.size * 4096;
+        }
+    }
+    usable
+}
+
+fn loaded_image(image_handle: Handle) -> Option<&'static LoadedImage> {
     let table = GLOBAL_SYSTEM_TABLE.load(core::sync::atomic::Ordering::SeqCst);
 
     let mut loaded_image: *const LoadedImage = core::ptr::null();
@@ -624,12 +1300,123 @@ pub fn get_image_base(image_handle: Handle) -> usize {
             &guid::LOADED_IMAGE_PROTOCOL,
             &mut loaded_image,
         );
-        if res != 0 {
-            kprintln!("An error occured! {:x}", res);
+        if res.is_error() {
+            kprintln!("An error occured! {:?}", res);
+            return None;
+        }
+        loaded_image.as_ref()
+    }
+}
+
+pub fn get_image_base(image_handle: Handle) -> usize {
+    let loaded_image = loaded_image(image_handle).expect("Unable to get loaded image!");
+    kprintln!("{:p}", loaded_image);
+    loaded_image.image_base as _
+}
+
+/// The image as it was loaded into memory, from the image base to base + image size. Has to be
+/// called before boot services exit but the loader's memory stays valid after.
+pub fn loaded_image_bytes(image_handle: Handle) -> &'static [u8] {
+    let loaded_image = loaded_image(image_handle).expect("Unable to get loaded image!");
+    unsafe {
+        core::slice::from_raw_parts(loaded_image.image_base as *const u8, loaded_image.image_size)
+    }
+}
+
+// Decoded load options, they have to outlive boot services
+const COMMAND_LINE_SIZE: usize = 512;
+static mut COMMAND_LINE: [u8; COMMAND_LINE_SIZE] = [0; COMMAND_LINE_SIZE];
+
+/// The image's load options decoded from UTF-16, which is where boot managers and the shell put
+/// the command line. Characters that don't fit in the buffer are dropped. Has to be called before
+/// boot services exit.
+pub fn command_line(image_handle: Handle) -> Option<&'static str> {
+    let loaded_image = loaded_image(image_handle)?;
+    if loaded_image.load_options.is_null() || loaded_image.load_options_size < 2 {
+        return None;
+    }
+
+    let options = unsafe {
+        core::slice::from_raw_parts(
+            loaded_image.load_options as *const u16,
+            loaded_image.load_options_size as usize / 2,
+        )
+    };
+
+    let buffer = unsafe { &mut COMMAND_LINE };
+    let mut len = 0;
+    for c in core::char::decode_utf16(options.iter().copied().take_while(|c| *c != 0)) {
+        let c = c.unwrap_or(core::char::REPLACEMENT_CHARACTER);
+        if len + c.len_utf8() > buffer.len() {
+            break;
         }
-        kprintln!("{:p}", loaded_image);
-        (*loaded_image).image_base as _
+        len += c.encode_utf8(&mut buffer[len..]).len();
     }
+
+    core::str::from_utf8(&buffer[..len]).ok()
+}
+
+#[repr(C)]
+pub struct GraphicsOutputProtocol {
+    query_mode: Handle,
+    set_mode: Handle,
+    blt: Handle,
+    mode: *const GraphicsOutputMode,
+}
+
+#[repr(C)]
+struct GraphicsOutputMode {
+    max_mode: u32,
+    mode: u32,
+    info: *const GraphicsOutputModeInfo,
+    size_of_info: usize,
+    framebuffer_base: u64,
+    framebuffer_size: usize,
+}
+
+#[repr(C)]
+struct GraphicsOutputModeInfo {
+    version: u32,
+    horizontal_resolution: u32,
+    vertical_resolution: u32,
+    pixel_format: u32,
+    // Red, green, blue and reserved masks, only used by the bitmask format
+    pixel_information: [u32; 4],
+    pixels_per_scan_line: u32,
+}
+
+/// The framebuffer of the GOP's current mode, None if there's no GOP or the mode can only be
+/// drawn with Blt. Has to be called before boot services exit, the framebuffer itself stays put
+/// after so the result is what the kernel draws to.
+pub fn get_gop() -> Option<Framebuffer> {
+    let boot_services = get_system_table().boot_services();
+
+    let mut gop: *const GraphicsOutputProtocol = core::ptr::null();
+    let res = boot_services.locate_protocol(&guid::GRAPHICS_OUTPUT_PROTOCOL, &mut gop);
+    if res.is_error() {
+        kprintln!("Unable to locate GOP! {:?}", res);
+        return None;
+    }
+
+    let mode = unsafe { gop.as_ref()?.mode.as_ref()? };
+    let info = unsafe { mode.info.as_ref()? };
+    let format = match info.pixel_format {
+        0 => PixelFormat::Rgb,
+        1 => PixelFormat::Bgr,
+        2 => PixelFormat::Bitmask,
+        _ => PixelFormat::BltOnly,
+    };
+    if format == PixelFormat::BltOnly || mode.framebuffer_base == 0 {
+        return None;
+    }
+
+    Some(Framebuffer::new(
+        mode.framebuffer_base as *mut u32,
+        info.horizontal_resolution as usize,
+        info.vertical_resolution as usize,
+        info.pixels_per_scan_line as usize,
+        format,
+    ))
 }
 
 pub fn get_system_table() -> &'static SystemTable {
@@ -693,5 +1480,39 @@ pub mod guid {
 
     pub const RSDP: GUID = create_guid!(8868E871-E4F1-11D3-BC22-0080C73C8881);
 
+    pub const RSDP_V1: GUID = create_guid!(eb9d2d30-2d88-11d3-9a16-0090273fc14d);
+
+    pub const SMBIOS: GUID = create_guid!(eb9d2d31-2d88-11d3-9a16-0090273fc14d);
+
+    // f2fd1544-9794-4a2c-992e-e5bbcf20e394, "992e-" doesn't lex so it can't go through create_guid!
+    pub const SMBIOS3: GUID = GUID {
+        a: 0xf2fd1544,
+        b: 0x9794,
+        c: 0x4a2c,
+        d: [0x99, 0x2e, 0xe5, 0xbb, 0xcf, 0x20, 0xe3, 0x94],
+    };
+
     pub const FILE_INFO: GUID = create_guid!(09576e92-6d3f-11d2-8e39-00a0c969723b);
+
+    pub const GRAPHICS_OUTPUT_PROTOCOL: GUID = create_guid!(9042a9de-23dc-4a38-96fb-7aded080516a);
+
+    const NAMES: &[(&GUID, &str)] = &[
+        (&LOADED_IMAGE_PROTOCOL, "Loaded Image Protocol"),
+        (&RAM_DISK_PROTOCOL, "RAM Disk Protocol"),
+        (&SIMPLE_FILE_SYSTEM_PROTOCOL, "Simple File System Protocol"),
+        (&RSDP, "ACPI 2.0 RSDP"),
+        (&RSDP_V1, "ACPI 1.0 RSDP"),
+        (&SMBIOS, "SMBIOS Table"),
+        (&SMBIOS3, "SMBIOS 3 Table"),
+        (&FILE_INFO, "File Info"),
+        (&GRAPHICS_OUTPUT_PROTOCOL, "Graphics Output Protocol"),
+    ];
+
+    /// Readable name of a GUID this crate knows about
+    pub fn name(guid: &GUID) -> Option<&'static str> {
+        NAMES
+            .iter()
+            .find(|(known, _)| *known == guid)
+            .map(|(_, name)| *name)
+    }
 }
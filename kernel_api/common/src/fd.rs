@@ -0,0 +1,54 @@
+pub type Fd = usize;
+
+// Fixed so opening and closing never touches the heap
+pub const MAX_FDS: usize = 16;
+
+pub const STDIN: Fd = 0;
+pub const STDOUT: Fd = 1;
+pub const STDERR: Fd = 2;
+
+const COM1: u16 = 0x3F8;
+
+/// Kernel object a file descriptor refers to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileObject {
+    // Serial port base address
+    Serial(u16),
+}
+
+#[derive(Debug)]
+pub struct FdTable {
+    entries: [Option<FileObject>; MAX_FDS],
+}
+
+impl FdTable {
+    pub const fn empty() -> FdTable {
+        FdTable {
+            entries: [None; MAX_FDS],
+        }
+    }
+
+    /// Table with stdin, stdout and stderr all on the first serial port
+    pub fn new() -> FdTable {
+        let mut table = FdTable::empty();
+        table.entries[STDIN] = Some(FileObject::Serial(COM1));
+        table.entries[STDOUT] = Some(FileObject::Serial(COM1));
+        table.entries[STDERR] = Some(FileObject::Serial(COM1));
+        table
+    }
+
+    /// Puts the object in the lowest free slot. Returns None if the table is full.
+    pub fn open(&mut self, object: FileObject) -> Option<Fd> {
+        let fd = self.entries.iter().position(|e| e.is_none())?;
+        self.entries[fd] = Some(object);
+        Some(fd)
+    }
+
+    pub fn get(&self, fd: Fd) -> Option<&FileObject> {
+        self.entries.get(fd)?.as_ref()
+    }
+
+    pub fn close(&mut self, fd: Fd) -> Option<FileObject> {
+        self.entries.get_mut(fd)?.take()
+    }
+}
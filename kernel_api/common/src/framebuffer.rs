@@ -0,0 +1,109 @@
+use alloc::vec::Vec;
+
+use crate::util;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+    Bitmask,
+    BltOnly,
+}
+
+pub struct Framebuffer {
+    base: *mut u32,
+    width: usize,
+    height: usize,
+    // Pixels per scanline, can be larger than width
+    stride: usize,
+    format: PixelFormat,
+    back: Option<Vec<u32>>,
+}
+
+impl Framebuffer {
+    pub fn new(
+        base: *mut u32,
+        width: usize,
+        height: usize,
+        stride: usize,
+        format: PixelFormat,
+    ) -> Framebuffer {
+        Framebuffer {
+            base,
+            width,
+            height,
+            stride,
+            format,
+            back: None,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn base(&self) -> *mut u32 {
+        self.base
+    }
+
+    /// Size of the visible framebuffer in bytes
+    pub fn size(&self) -> usize {
+        self.stride * self.height * core::mem::size_of::<u32>()
+    }
+
+    /// Allocates a backbuffer on the heap. After this all drawing goes to the backbuffer and is
+    /// only visible once `present` is called.
+    pub fn enable_double_buffering(&mut self) {
+        if self.back.is_none() {
+            let mut back = Vec::with_capacity(self.stride * self.height);
+            back.resize(self.stride * self.height, 0);
+            self.back = Some(back);
+        }
+    }
+
+    pub fn is_double_buffered(&self) -> bool {
+        self.back.is_some()
+    }
+
+    /// The buffer drawing should go to (the backbuffer if there is one)
+    pub fn buffer_mut(&mut self) -> &mut [u32] {
+        match self.back.as_mut() {
+            Some(back) => back.as_mut_slice(),
+            None => unsafe { core::slice::from_raw_parts_mut(self.base, self.stride * self.height) },
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: u32) {
+        if x < self.width && y < self.height {
+            let stride = self.stride;
+            self.buffer_mut()[y * stride + x] = color;
+        }
+    }
+
+    /// Sets every pixel, including the padding past the width of each scanline
+    pub fn fill(&mut self, color: u32) {
+        self.buffer_mut().fill(color);
+    }
+
+    /// Copies the backbuffer to the visible framebuffer
+    pub fn present(&mut self) {
+        let size = self.size();
+        if let Some(back) = self.back.as_ref() {
+            unsafe {
+                util::memcpy(self.base as *mut u8, back.as_ptr() as *const u8, size);
+            }
+        }
+    }
+}
@@ -13,11 +13,23 @@ pub mod mem;
 pub mod allocator;
 pub mod process;
 pub mod memory_regions;
+pub mod framebuffer;
+pub mod smbios;
+pub mod fd;
+pub mod boot;
+pub mod cmdline;
+pub mod output;
+pub mod slab_cache;
 mod linked_list_allocator;
+#[cfg(feature = "heap_bump")]
+mod bump_allocator;
+#[cfg(feature = "heap_slab")]
+mod slab_allocator;
 
 use core::fmt::Debug;
 
 use efi::SystemTable;
+use framebuffer::Framebuffer;
 use mem::PageTableFrameAllocator;
 pub use x86_64;
 use x86_64::structures::paging::PageTable;
@@ -31,7 +43,13 @@ pub struct KernelParameters<'a> {
     pub frame_allocator: PageTableFrameAllocator<'a>,
     pub system_table: *mut SystemTable,
     // pub heap_top: usize,
-    pub heap: linked_list_allocator::Heap,
+    pub heap: allocator::Heap,
+    // Load options of the loader image, empty if there were none
+    pub command_line: &'a str,
+    // Found through GOP before boot services exited, the base is a physical address
+    pub framebuffer: Option<Framebuffer>,
+    // Firmware wall clock from when the loader started
+    pub boot_time: Option<efi::Time>,
     // pub page_table: PageTable,
 }
 
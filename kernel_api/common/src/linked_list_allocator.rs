@@ -1,12 +1,6 @@
-use core::{
-    alloc::{GlobalAlloc, Layout},
-    mem::MaybeUninit,
-    ptr::NonNull,
-};
+use core::{alloc::Layout, mem::MaybeUninit, ptr::NonNull};
 
-use core::ops::Deref;
 use linked_list_allocator::hole::HoleList;
-use spinning_top::{Spinlock, lock_api::RawMutex};
 
 #[derive(Clone)]
 pub struct Heap {
@@ -167,82 +161,6 @@ impl Heap {
     }
 }
 
-#[cfg(all(feature = "alloc_ref", feature = "use_spin"))]
-unsafe impl Allocator for LockedHeap {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        if layout.size() == 0 {
-            return Ok(NonNull::slice_from_raw_parts(layout.dangling(), 0));
-        }
-        match self.0.lock().allocate_first_fit(layout) {
-            Ok(ptr) => Ok(NonNull::slice_from_raw_parts(ptr, layout.size())),
-            Err(()) => Err(AllocError),
-        }
-    }
-
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        if layout.size() != 0 {
-            self.0.lock().deallocate(ptr, layout);
-        }
-    }
-}
-
-// #[derive(Clone)]
-pub struct LockedHeap(Spinlock<Heap>);
-
-impl LockedHeap {
-    /// Creates an empty heap. All allocate calls will return `None`.
-    pub const fn empty() -> LockedHeap {
-        LockedHeap(Spinlock::new(Heap::empty()))
-    }
-
-    /// Creates a new heap with the given `bottom` and `size`. The bottom address must be valid
-    /// and the memory in the `[heap_bottom, heap_bottom + heap_size)` range must not be used for
-    /// anything else. This function is unsafe because it can cause undefined behavior if the
-    /// given address is invalid.
-    pub unsafe fn new(heap_bottom: usize, heap_size: usize) -> LockedHeap {
-        LockedHeap(Spinlock::new(Heap {
-            bottom: heap_bottom,
-            size: heap_size,
-            used: 0,
-            holes: HoleList::new(heap_bottom, heap_size),
-        }))
-    }
-
-    pub fn heap(&self) -> Heap {
-        self.0.lock().clone()
-    }
-
-    pub fn update(&mut self, heap: &Heap) {
-        self.0.lock().update(heap) ;
-    }
-}
-
-impl Deref for LockedHeap {
-    type Target = Spinlock<Heap>;
-
-    fn deref(&self) -> &Spinlock<Heap> {
-        &self.0
-    }
-}
-
-unsafe impl GlobalAlloc for LockedHeap {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // kprintln!("Allocation: {:?}", layout);
-        self.0
-            .lock()
-            .allocate_first_fit(layout)
-            .ok()
-            .map_or(0 as *mut u8, |allocation| allocation.as_ptr())
-    }
-
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // kprintln!("Deallocation: {:?}", layout);
-        self.0
-            .lock()
-            .deallocate(NonNull::new_unchecked(ptr), layout)
-    }
-}
-
 /// Align downwards. Returns the greatest x with alignment `align`
 /// so that x <= addr. The alignment must be a power of 2.
 pub fn align_down(addr: usize, align: usize) -> usize {
@@ -1,39 +1,54 @@
 use core::{
+    arch::x86_64::__cpuid,
+    fmt,
     iter::{Filter, FlatMap, Map, StepBy},
     mem::{size_of, size_of_val},
     ops::Range,
     slice::Iter,
 };
 
+use alloc::{vec, vec::Vec};
 use spinning_top::{lock_api::MutexGuard, RawSpinlock, Spinlock};
 use x86_64::{
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
     structures::paging::{
-        mapper::{MapToError, MapperFlush, MapperFlushAll},
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
-        PhysFrame, Size4KiB,
+        mapper::{MapToError, MapperFlush, MapperFlushAll, TranslateResult, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size2MiB, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
-use crate::{efi::{self, MemoryDescriptor}, memory_regions::PAGE_TABLE_OFFSET};
+use crate::{
+    efi::{self, MemoryDescriptor},
+    memory_regions::{PHYS_OFFSET, PROCESS_STACK_ADDRESS},
+};
 
 pub const STACK_SIZE: usize = 4096 * 5;
+// Process stacks are this many pages below `PROCESS_STACK_ADDRESS`, with a guard page under them
+pub const PROCESS_STACK_PAGES: usize = 256;
 
+// Physical address of the kernel's level 4 table
 pub static mut KERNEL_MAP: u64 = 0x0;
 
 static mut ALLOCATOR: Option<Spinlock<PageTableFrameAllocator<'static>>> = None;
 
+// Invalidates a page on every other online cpu, returns once they have all acknowledged
+static mut SHOOTDOWN: Option<fn(VirtAddr)> = None;
+
 pub fn allocator() -> &'static mut Spinlock<PageTableFrameAllocator<'static>> {
     unsafe { ALLOCATOR.as_mut().unwrap() }
 }
 
-pub unsafe fn active_level_4_table() -> &'static mut PageTable {
+/// The active level 4 table through a mapping of physical memory at `offset`. The loader runs on
+/// the firmware's identity map (0), the kernel on PHYS_OFFSET.
+pub unsafe fn active_level_4_table(offset: u64) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
 
     let (level_4_table_frame, _) = Cr3::read();
 
     let phys = level_4_table_frame.start_address();
-    let virt = VirtAddr::new(0) + phys.as_u64();
+    let virt = VirtAddr::new(offset) + phys.as_u64();
     let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
 
     &mut *page_table_ptr
@@ -41,19 +56,91 @@ pub unsafe fn active_level_4_table() -> &'static mut PageTable {
 
 pub fn active_offset_page_table(offset: u64) -> OffsetPageTable<'static> {
     unsafe {
-        let level_4_table = active_level_4_table();
+        let level_4_table = active_level_4_table(offset);
         OffsetPageTable::new(level_4_table, VirtAddr::new(offset))
     }
 }
 
+/// Loads `table` into CR3 (flushing all non global TLB entries) and records it as the kernel's
+/// address space. The table has to be accessible at its physical address to be validated.
+pub fn switch_address_space(table: PhysFrame) {
+    let phys = table.start_address();
+    kassert!(
+        phys.is_aligned(4096u64),
+        "Page table {:x} isn't page aligned!",
+        phys.as_u64()
+    );
+
+    let level_4_table: &PageTable = unsafe { &*(VirtAddr::new(0) + phys.as_u64()).as_ptr() };
+    kassert!(
+        level_4_table
+            .iter()
+            .any(|entry| entry.flags().contains(PageTableFlags::PRESENT)),
+        "Page table {:x} has nothing mapped!",
+        phys.as_u64()
+    );
+
+    unsafe {
+        KERNEL_MAP = phys.as_u64();
+        Cr3::write(table, Cr3Flags::empty());
+    }
+}
+
+pub fn kernel_map() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(unsafe { KERNEL_MAP }))
+}
+
+/// The kernel's level 4 table through a mapping of physical memory at `offset`
+pub unsafe fn kernel_level_4_table(offset: u64) -> &'static mut PageTable {
+    let virt = VirtAddr::new(offset) + KERNEL_MAP;
+    &mut *virt.as_mut_ptr()
+}
+
 pub fn init(alloc: PageTableFrameAllocator<'static>, offset: u64) -> OffsetPageTable<'static> {
     unsafe {
         ALLOCATOR.replace(Spinlock::new(alloc));
+        KERNEL_MAP = Cr3::read().0.start_address().as_u64();
     }
 
     active_offset_page_table(offset)
 }
 
+pub fn register_shootdown(handler: fn(VirtAddr)) {
+    unsafe {
+        SHOOTDOWN.replace(handler);
+    }
+}
+
+/// Removes the mapping of `page` from `pgtbl` and flushes it from this cpu's TLB. The frame it
+/// pointed to is returned for the caller to free, it isn't freed here since it may not have come
+/// from the frame allocator. Other cpus aren't told, see `unmap_global` for that.
+pub fn unmap(pgtbl: &mut OffsetPageTable, page: Page<Size4KiB>) -> Result<PhysFrame, UnmapError> {
+    let (frame, flush) = pgtbl.unmap(page)?;
+    flush.flush();
+    Ok(frame)
+}
+
+/// Unmaps the page containing `addr` from the active address space, makes sure no other cpu still
+/// holds a stale translation for it and gives its frame back to the frame allocator. The frame is
+/// only freed once every cpu has acknowledged the shootdown, so the page has to be backed by a
+/// frame from the frame allocator. The frame is returned to show what was mapped, it's already
+/// free so it mustn't be used.
+pub fn unmap_global(addr: VirtAddr) -> Result<PhysFrame, UnmapError> {
+    let mut pt = active_offset_page_table(PHYS_OFFSET);
+    let page = Page::<Size4KiB>::containing_address(addr);
+
+    let frame = unmap(&mut pt, page)?;
+
+    if let Some(shootdown) = unsafe { SHOOTDOWN } {
+        shootdown(page.start_address());
+    }
+
+    // Freeing links the frame into the free list through the physical map, it never touches the
+    // heap so a heap fault can't come back for this lock
+    allocator().lock().free_frame(frame);
+    Ok(frame)
+}
+
 pub fn map_virt<'a, S>(
     phys: PhysAddr,
     virt: VirtAddr,
@@ -62,7 +149,7 @@ pub fn map_virt<'a, S>(
 where
     S: PageSize, OffsetPageTable<'a>: Mapper<S>
 {
-    let mut pt = active_offset_page_table(PAGE_TABLE_OFFSET);
+    let mut pt = active_offset_page_table(PHYS_OFFSET);
     let start = PhysFrame::containing_address(phys);
     let end = PhysFrame::containing_address(phys + size);
 
@@ -88,26 +175,151 @@ where
     Ok(())
 }
 
-pub fn map_phys(phys: PhysAddr, size: usize) -> Result<(), MapToError<Size4KiB>> {
-    let mut pt = active_offset_page_table(PAGE_TABLE_OFFSET);
+/// Why a checked mapping failed
+#[derive(Debug)]
+pub enum MapError {
+    /// Something already mapped `virt`, usually two subsystems claiming the same region
+    AlreadyMapped {
+        virt: VirtAddr,
+        existing: PhysAddr,
+        flags: PageTableFlags,
+    },
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for MapError {
+    fn from(error: MapToError<Size4KiB>) -> MapError {
+        MapError::Map(error)
+    }
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::AlreadyMapped {
+                virt,
+                existing,
+                flags,
+            } => write!(
+                f,
+                "{:#x} is already mapped to {:#x} with {:?}",
+                virt.as_u64(),
+                existing.as_u64(),
+                flags
+            ),
+            MapError::Map(error) => write!(f, "{:?}", error),
+        }
+    }
+}
+
+/// Maps `page` to `frame` in the active address space. Unlike `map_to` an existing mapping of any
+/// page size is reported with where it points and its flags.
+pub fn map_checked(
+    page: Page<Size4KiB>,
+    frame: PhysFrame<Size4KiB>,
+    flags: PageTableFlags,
+) -> Result<(), MapError> {
+    let mut pt = active_offset_page_table(PHYS_OFFSET);
+    if let TranslateResult::Mapped {
+        frame: existing,
+        flags: existing_flags,
+        ..
+    } = pt.translate(page.start_address())
+    {
+        return Err(MapError::AlreadyMapped {
+            virt: page.start_address(),
+            existing: existing.start_address(),
+            flags: existing_flags,
+        });
+    }
+
+    unsafe {
+        <OffsetPageTable as Mapper<Size4KiB>>::map_to(
+            &mut pt,
+            page,
+            frame,
+            flags,
+            allocator().get_mut(),
+        )?
+        .flush();
+    }
+    Ok(())
+}
+
+/// Identity maps `size` bytes at `phys`. Fails on the first page that's already mapped.
+pub fn map_phys(phys: PhysAddr, size: usize) -> Result<(), MapError> {
     let start = PhysFrame::containing_address(phys);
     let end = PhysFrame::containing_address(phys + size);
+    for frame in PhysFrame::<Size4KiB>::range_inclusive(start, end) {
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        map_checked(
+            page,
+            frame,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        )?;
+    }
+    Ok(())
+}
+
+/// Identity maps device registers with caching disabled. Pages that are already mapped have
+/// their flags replaced since a cached mapping of MMIO is never right.
+pub fn map_mmio(phys: PhysAddr, size: usize) -> Result<(), MapToError<Size4KiB>> {
+    let mut pt = active_offset_page_table(PHYS_OFFSET);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+
+    let start = PhysFrame::containing_address(phys);
+    let end = PhysFrame::containing_address(phys + size - 1u64);
     for frame in PhysFrame::<Size4KiB>::range_inclusive(start, end) {
         match unsafe {
             <OffsetPageTable as Mapper<Size4KiB>>::identity_map(
                 &mut pt,
                 frame,
-                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                flags,
                 allocator().get_mut(),
             )
         } {
             Ok(o) => o.flush(),
+            Err(MapToError::PageAlreadyMapped(_)) => {
+                let page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+                    frame.start_address().as_u64(),
+                ));
+                match unsafe { pt.update_flags(page, flags) } {
+                    Ok(o) => o.flush(),
+                    Err(_) => return Err(MapToError::ParentEntryHugePage),
+                }
+            }
             Err(e) => return Err(e),
         }
     }
     Ok(())
 }
 
+/// Whether `addr` is mapped in the active address space with caching disabled
+pub fn is_uncacheable(addr: VirtAddr) -> bool {
+    let pt = active_offset_page_table(PHYS_OFFSET);
+    match pt.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags.contains(PageTableFlags::NO_CACHE),
+        _ => false,
+    }
+}
+
+/// Physical address `virt` maps to in the active address space, for any page size
+pub fn virt_to_phys(virt: VirtAddr) -> Option<PhysAddr> {
+    let pt = active_offset_page_table(PHYS_OFFSET);
+    match pt.translate(virt) {
+        TranslateResult::Mapped { frame, offset, .. } => Some(frame.start_address() + offset),
+        _ => None,
+    }
+}
+
+/// Where `phys` can be accessed through the mapping of all physical memory
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYS_OFFSET + phys.as_u64())
+}
+
 pub fn map_phys_table(
     pgtbl: &mut OffsetPageTable<'_>,
     phys: PhysAddr,
@@ -135,28 +347,28 @@ pub fn map_phys_table(
 //     map_phys(PhysAddr::new(ptr as u64), size_of::<T>())
 // }
 
-pub fn map_ref<T: ?Sized>(ptr: &T) -> Result<(), MapToError<Size4KiB>> {
+pub fn map_ref<T: ?Sized>(ptr: &T) -> Result<(), MapError> {
     map_phys(
         PhysAddr::new(ptr as *const T as *const () as u64),
         size_of_val(ptr),
     )
 }
 
-pub fn map_ref_len<T: ?Sized>(ptr: &T, size: usize) -> Result<(), MapToError<Size4KiB>> {
+pub fn map_ref_len<T: ?Sized>(ptr: &T, size: usize) -> Result<(), MapError> {
     map_phys(
         PhysAddr::new(ptr as *const T as *const () as u64),
         size_of_val(ptr) * size,
     )
 }
 
-pub fn map_arr<T>(ptr: &[T]) -> Result<(), MapToError<Size4KiB>> {
+pub fn map_arr<T>(ptr: &[T]) -> Result<(), MapError> {
     map_phys(
         PhysAddr::new(&ptr[0] as *const T as u64),
         size_of::<T>() * ptr.len(),
     )
 }
 
-pub fn map_arr_len<T>(ptr: &[T], len: usize) -> Result<(), MapToError<Size4KiB>> {
+pub fn map_arr_len<T>(ptr: &[T], len: usize) -> Result<(), MapError> {
     map_phys(
         PhysAddr::new(&ptr[0] as *const T as u64),
         size_of::<T>() * len,
@@ -181,6 +393,265 @@ pub fn map_arr_table<T>(
     )
 }
 
+/// A physical range of one memory type, possibly made of several descriptors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub start: PhysAddr,
+    // In bytes
+    pub size: usize,
+    pub memory_type: efi::MemoryType,
+}
+
+impl Region {
+    pub fn end(&self) -> PhysAddr {
+        self.start + self.size
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let start = PhysFrame::containing_address(self.start);
+        let end = PhysFrame::containing_address(self.end() - 1u64);
+        PhysFrame::range_inclusive(start, end)
+    }
+}
+
+/// Merges descriptors of the same type that touch into maximal regions, sorted by address.
+/// Attributes aren't carried over so the map itself is still needed to look those up.
+pub fn coalesce_map(map: efi::MemoryMap<'_>) -> Vec<Region> {
+    let mut descriptors: Vec<&MemoryDescriptor> = map.iter().filter(|d| d.size > 0).collect();
+    descriptors.sort_unstable_by_key(|d| d.physical_address);
+
+    let mut regions: Vec<Region> = Vec::with_capacity(descriptors.len());
+    for desc in descriptors {
+        let region = Region {
+            start: PhysAddr::new(desc.physical_address as u64),
+            size: desc.size * 4096,
+            memory_type: desc.memory_type,
+        };
+
+        match regions.last_mut() {
+            Some(last) if last.memory_type == region.memory_type && last.end() == region.start => {
+                last.size += region.size
+            }
+            _ => regions.push(region),
+        }
+    }
+    regions
+}
+
+/// Sets CR4.PGE so GLOBAL pages stay in the TLB across CR3 switches. Returns false and leaves CR4
+/// alone when the cpu doesn't support it, the GLOBAL bit is ignored then.
+pub fn enable_global_pages() -> bool {
+    let supported = unsafe { __cpuid(1) }.edx & (1 << 13) != 0;
+    if supported {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PAGE_GLOBAL)) };
+    }
+    supported
+}
+
+/// Maps everything in `map` at PHYS_OFFSET + its physical address. 2MiB pages are used wherever a
+/// whole aligned 2MiB fits and 4KiB pages around them. Pages that are already mapped are left
+/// alone. The firmware doesn't guarantee an identity map once our own tables are loaded, so
+/// anything the kernel reads by physical address has to go through this mapping.
+pub fn map_physical_memory<M>(
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    map: efi::MemoryMap<'_>,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+{
+    // The same in every address space, so it doesn't need flushing on a switch
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL;
+
+    // The type doesn't matter here, joining neighbours leaves room for more huge pages
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for region in coalesce_map(map) {
+        let (start, end) = (region.start.as_u64(), region.end().as_u64());
+        match ranges.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+
+    for (start, end) in ranges {
+        let mut addr = start;
+        while addr < end {
+            let virt = VirtAddr::new(PHYS_OFFSET + addr);
+
+            let failed = if addr % Size2MiB::SIZE == 0 && end - addr >= Size2MiB::SIZE {
+                let result = unsafe {
+                    <M as Mapper<Size2MiB>>::map_to(
+                        mapper,
+                        Page::containing_address(virt),
+                        PhysFrame::containing_address(PhysAddr::new(addr)),
+                        flags,
+                        frame_allocator,
+                    )
+                };
+                addr += Size2MiB::SIZE;
+                match result {
+                    Ok(flush) => {
+                        flush.ignore();
+                        false
+                    }
+                    Err(e) => matches!(e, MapToError::FrameAllocationFailed),
+                }
+            } else {
+                let result = unsafe {
+                    <M as Mapper<Size4KiB>>::map_to(
+                        mapper,
+                        Page::containing_address(virt),
+                        PhysFrame::containing_address(PhysAddr::new(addr)),
+                        flags,
+                        frame_allocator,
+                    )
+                };
+                addr += Size4KiB::SIZE;
+                match result {
+                    Ok(flush) => {
+                        flush.ignore();
+                        false
+                    }
+                    Err(e) => matches!(e, MapToError::FrameAllocationFailed),
+                }
+            };
+
+            if failed {
+                return Err(MapToError::FrameAllocationFailed);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps `size` bytes at `phys` to `virt` using 2MiB pages wherever both addresses are 2MiB aligned
+/// with at least 2MiB left, and 4KiB pages for the unaligned head and tail. Only saves anything
+/// when `phys` and `virt` are the same distance from a 2MiB boundary, otherwise every page is
+/// 4KiB. Fails on the first page that's already mapped.
+pub fn map_phys_huge<M>(
+    mapper: &mut M,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: usize,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+{
+    let size = (size as u64 + Size4KiB::SIZE - 1) / Size4KiB::SIZE * Size4KiB::SIZE;
+    let mut offset = 0;
+    while offset < size {
+        let (phys, virt) = (phys + offset, virt + offset);
+
+        if phys.is_aligned(Size2MiB::SIZE)
+            && virt.is_aligned(Size2MiB::SIZE)
+            && size - offset >= Size2MiB::SIZE
+        {
+            unsafe {
+                <M as Mapper<Size2MiB>>::map_to(
+                    mapper,
+                    Page::containing_address(virt),
+                    PhysFrame::containing_address(phys),
+                    flags,
+                    frame_allocator,
+                )
+                .map_err(huge_map_error)?
+                .flush();
+            }
+            offset += Size2MiB::SIZE;
+        } else {
+            unsafe {
+                <M as Mapper<Size4KiB>>::map_to(
+                    mapper,
+                    Page::containing_address(virt),
+                    PhysFrame::containing_address(phys),
+                    flags,
+                    frame_allocator,
+                )?
+                .flush();
+            }
+            offset += Size4KiB::SIZE;
+        }
+    }
+    Ok(())
+}
+
+// Errors from mapping a 2MiB page in terms of 4KiB ones, so mixed mappings have one error type
+fn huge_map_error(error: MapToError<Size2MiB>) -> MapToError<Size4KiB> {
+    match error {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
+
+/// Maps `pages` fresh frames as a process stack ending at `PROCESS_STACK_ADDRESS` and returns the
+/// top of it. The page right below is left unmapped so an overflow page faults there (see
+/// `is_stack_guard`) instead of writing over whatever would be mapped below.
+pub fn map_stack_with_guard(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    pages: usize,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let top = VirtAddr::new(PROCESS_STACK_ADDRESS as u64);
+    let bottom = Page::<Size4KiB>::containing_address(top - pages as u64 * 4096);
+
+    // Something already there would defeat the point
+    if let Ok(frame) = mapper.translate_page(stack_guard_page(pages)) {
+        return Err(MapToError::PageAlreadyMapped(frame));
+    }
+
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    for page in Page::range(bottom, bottom + pages as u64) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(top)
+}
+
+/// Undoes `map_stack_with_guard`, every mapped page of the stack is unmapped and its frame given
+/// back to `frame_allocator`. Returns how many frames were freed.
+pub fn unmap_stack(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    pages: usize,
+) -> usize {
+    let top = VirtAddr::new(PROCESS_STACK_ADDRESS as u64);
+    let bottom = Page::<Size4KiB>::containing_address(top - pages as u64 * 4096);
+
+    let mut freed = 0;
+    for page in Page::range(bottom, bottom + pages as u64) {
+        // Pages that were never mapped are skipped
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            freed += 1;
+        }
+    }
+    freed
+}
+
+/// The unmapped page under a process stack of `pages` pages
+pub fn stack_guard_page(pages: usize) -> Page {
+    let top = VirtAddr::new(PROCESS_STACK_ADDRESS as u64);
+    Page::containing_address(top - (pages as u64 + 1) * 4096)
+}
+
+/// Whether `addr` is in the guard page of a process stack, i.e. a fault on it is a stack overflow
+pub fn is_stack_guard(addr: VirtAddr) -> bool {
+    Page::containing_address(addr) == stack_guard_page(PROCESS_STACK_PAGES)
+}
+
+// Ends the free list, frame addresses are page aligned
+const NO_FRAME: u64 = u64::MAX;
+
 #[derive(Clone)]
 pub struct PageTableFrameAllocator<'a> {
     memory_map: efi::MemoryMap<'a>,
@@ -195,11 +666,68 @@ pub struct PageTableFrameAllocator<'a> {
         >,
         fn(usize) -> PhysFrame<Size4KiB>,
     >,
+    // Frames handed back after boot, used before the memory map. Each one holds the address of
+    // the next in its first 8 bytes through the physical map, so freeing never needs the heap,
+    // whose fault handler takes this allocator's lock. Clones share the list, only one may free.
+    free: Option<PhysFrame>,
+    // Takes over from the iterator and free list once `use_bitmap` is called
+    bitmap: Option<BitmapFrameAllocator>,
+    // Frames handed out and not yet freed, for `stats`
+    allocated: usize,
+}
+
+/// Physical memory totals from the frame allocator's point of view
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    /// Everything in the memory map, including memory that can never be allocated
+    pub total_bytes: usize,
+    /// Memory the allocator hands frames out of
+    pub usable_bytes: usize,
+    /// Frames currently allocated, counting the loader's allocations
+    pub allocated_bytes: usize,
+}
+
+impl MemoryStats {
+    pub fn free_bytes(&self) -> usize {
+        self.usable_bytes.saturating_sub(self.allocated_bytes)
+    }
+}
+
+// Every memory type in a map, each listed once
+struct PresentTypes<'a>(efi::MemoryMap<'a>);
+
+impl fmt::Debug for PresentTypes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut list = f.debug_list();
+        for (i, d) in self.0.iter().enumerate() {
+            if self.0[..i].iter().all(|p| p.memory_type != d.memory_type) {
+                list.entry(&d.memory_type);
+            }
+        }
+        list.finish()
+    }
+}
+
+/// Panics naming the types that were present when `memory_map` has nothing `is_usable`, otherwise
+/// every allocation just returns `None` and the failure shows up somewhere unrelated
+fn check_usable(memory_map: efi::MemoryMap) {
+    if !memory_map.iter().any(|d| d.memory_type.is_usable()) {
+        panic!(
+            "no usable memory found; usable types = {:?}",
+            PresentTypes(memory_map)
+        );
+    }
 }
 
 impl<'a> PageTableFrameAllocator<'a> {
     pub fn swap_map(&mut self, memory_map: efi::MemoryMap<'a>) {
-        let curr_frame = self.allocate_frame();
+        check_usable(memory_map);
+        // The bitmap doesn't depend on where the map lives
+        if self.bitmap.is_some() {
+            self.memory_map = memory_map;
+            return;
+        }
+        let curr_frame: Option<PhysFrame> = self.allocate_frame();
         kprintln!("Frame {:?}", curr_frame);
         let iter = memory_map.iter();
         let usable: Filter<Iter<MemoryDescriptor>, fn(&&MemoryDescriptor) -> bool> =
@@ -267,10 +795,15 @@ impl<'a> PageTableFrameAllocator<'a> {
             fn(usize) -> PhysFrame<Size4KiB>,
         > = addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr as u64)));
 
-        PageTableFrameAllocator {
+        let allocator = PageTableFrameAllocator {
             memory_map,
             addresses: amap,
-        }
+            free: None,
+            bitmap: None,
+            allocated: 0,
+        };
+        check_usable(allocator.memory_map);
+        allocator
     }
 
     pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + 'a {
@@ -283,6 +816,139 @@ impl<'a> PageTableFrameAllocator<'a> {
         addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr as u64)))
     }
 
+    // Descriptors the allocator hands frames out of
+    fn usable_regions(&self) -> impl Iterator<Item = &MemoryDescriptor> + '_ {
+        self.memory_map.iter().filter(|d| d.memory_type.is_usable())
+    }
+
+    /// Frames in usable regions of the map, whether or not they've been allocated
+    pub fn usable_frame_count(&self) -> usize {
+        self.usable_regions().map(|d| d.size).sum()
+    }
+
+    pub fn total_usable_bytes(&self) -> usize {
+        self.usable_frame_count() * 4096
+    }
+
+    /// One past the last byte of usable memory, None when there isn't any
+    pub fn highest_usable_address(&self) -> Option<PhysAddr> {
+        self.usable_regions()
+            .map(|d| d.physical_address + d.size * 4096)
+            .max()
+            .map(|end| PhysAddr::new(end as u64))
+    }
+
+    /// Switches to a `BitmapFrameAllocator` for every allocation after this. Frames already handed
+    /// out stay allocated. Needs the heap, and a frame's worth of it per 128MiB of memory.
+    pub fn use_bitmap(&mut self) {
+        if self.bitmap.is_some() {
+            return;
+        }
+        let end = self
+            .highest_usable_address()
+            .map_or(0, |addr| addr.as_u64() as usize);
+        let addresses = self.addresses.clone();
+        let free = addresses.chain(core::iter::from_fn(|| self.pop_free()));
+        let bitmap = BitmapFrameAllocator::with_free(end, free);
+        self.bitmap = Some(bitmap);
+    }
+
+    fn push_free(&mut self, frame: PhysFrame) {
+        let next = match self.free {
+            Some(next) => next.start_address().as_u64(),
+            None => NO_FRAME,
+        };
+        let link = phys_to_virt(frame.start_address()).as_mut_ptr::<u64>();
+        unsafe { link.write(next) };
+        self.free = Some(frame);
+    }
+
+    fn pop_free(&mut self) -> Option<PhysFrame> {
+        let frame = self.free?;
+        let next = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+        self.free = match next {
+            NO_FRAME => None,
+            next => Some(PhysFrame::containing_address(PhysAddr::new(next))),
+        };
+        Some(frame)
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            total_bytes: efi::get_mem_size(self.memory_map),
+            usable_bytes: self.total_usable_bytes(),
+            allocated_bytes: self.allocated * 4096,
+        }
+    }
+
+    pub fn bitmap(&self) -> Option<&BitmapFrameAllocator> {
+        self.bitmap.as_ref()
+    }
+
+    /// Makes `frame` available for allocation
+    pub fn free_frame(&mut self, frame: PhysFrame) {
+        unsafe { self.deallocate_frame(frame) }
+    }
+
+    /// `count` physically adjacent frames, returning the first. Needed for DMA buffers, unlike
+    /// `allocate_size` whose frames can be anywhere. Only memory the allocator hasn't reached yet
+    /// is searched, not the free list. Frames passed over on the way to a long enough run go on
+    /// the free list, and nothing is taken if there is no such run.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        self.allocate_aligned(count, 1)
+    }
+
+    /// `allocate_contiguous` with the first frame's number a multiple of `align`
+    pub fn allocate_aligned(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        if count == 0 || align == 0 {
+            return None;
+        }
+        if let Some(bitmap) = &mut self.bitmap {
+            let start = bitmap.allocate_aligned(count, align);
+            if start.is_some() {
+                self.allocated += count;
+            }
+            return start;
+        }
+
+        // Find the run on a copy so a failed search leaves the allocator alone
+        let mut skip = 0;
+        let mut run = 0;
+        let mut last: Option<PhysFrame> = None;
+        for frame in self.addresses.clone() {
+            if run > 0 && last.map_or(false, |last| last + 1 == frame) {
+                run += 1;
+            } else {
+                skip += run;
+                // A run can only start on an aligned frame
+                if (frame.start_address().as_u64() / 4096) as usize % align == 0 {
+                    run = 1;
+                } else {
+                    run = 0;
+                    skip += 1;
+                }
+            }
+            last = Some(frame);
+            if run == count {
+                break;
+            }
+        }
+        if run < count {
+            return None;
+        }
+
+        for _ in 0..skip {
+            let frame = self.addresses.next()?;
+            self.push_free(frame);
+        }
+        let start = self.addresses.next();
+        for _ in 1..count {
+            self.addresses.next();
+        }
+        self.allocated += count;
+        start
+    }
+
     pub fn allocate_size(&mut self, size: usize) -> Option<(PhysFrame<Size4KiB>, usize)> {
         let n = size / 4096;
         let mut ret_frame = PhysFrame::containing_address(PhysAddr::new(0));
@@ -301,7 +967,177 @@ impl<'a> PageTableFrameAllocator<'a> {
 
 unsafe impl<'a> FrameAllocator<Size4KiB> for PageTableFrameAllocator<'a> {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.addresses.next();
+        let frame = if let Some(bitmap) = &mut self.bitmap {
+            bitmap.allocate_frame()
+        } else if let Some(frame) = self.pop_free() {
+            Some(frame)
+        } else {
+            self.addresses.next()
+        };
+        if frame.is_some() {
+            self.allocated += 1;
+        }
         frame
     }
 }
+
+unsafe impl<'a> FrameAllocator<Size2MiB> for PageTableFrameAllocator<'a> {
+    /// 512 contiguous frames starting on a 2MiB boundary
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frames = (Size2MiB::SIZE / Size4KiB::SIZE) as usize;
+        let start = self.allocate_aligned(frames, frames)?;
+        PhysFrame::from_start_address(start.start_address()).ok()
+    }
+}
+
+impl<'a> FrameDeallocator<Size4KiB> for PageTableFrameAllocator<'a> {
+    /// Frees go on a list that `allocate_frame` takes from, most recently freed first, before
+    /// moving on through the memory map. The list is kept in the freed frames, so this needs the
+    /// physical map but not the heap.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        match &mut self.bitmap {
+            Some(bitmap) => {
+                // Frees of frames that are already free don't count
+                let free = bitmap.free_frames();
+                bitmap.deallocate_frame(frame);
+                if bitmap.free_frames() == free {
+                    return;
+                }
+            }
+            None => self.push_free(frame),
+        }
+        // Reclaimed frames were never allocated, they shouldn't take the count below zero
+        self.allocated = self.allocated.saturating_sub(1);
+    }
+}
+
+/// One bit for every frame below the highest usable address, set while the frame is allocated or
+/// isn't usable memory at all. Allocation searches from the lowest word that might have a clear
+/// bit, which moves forward as frames are handed out and back when one is freed, so allocating
+/// and freeing are both O(1) amortized instead of skipping over used frames one by one.
+#[derive(Clone)]
+pub struct BitmapFrameAllocator {
+    bitmap: Vec<u64>,
+    frames: usize,
+    // No word before this one has a clear bit
+    next: usize,
+    free: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Every usable frame in `memory_map` starts out free. The bitmap is allocated on the heap.
+    pub fn new(memory_map: efi::MemoryMap) -> BitmapFrameAllocator {
+        check_usable(memory_map);
+        let usable = memory_map.iter().filter(|d| d.memory_type.is_usable());
+        let end = usable
+            .clone()
+            .map(|d| d.physical_address + d.size * 4096)
+            .max()
+            .unwrap_or(0);
+        let frames = usable.flat_map(|d| {
+            (d.physical_address..d.physical_address + d.size * 4096)
+                .step_by(4096)
+                .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr as u64)))
+        });
+        BitmapFrameAllocator::with_free(end, frames)
+    }
+
+    /// Covers the memory below `end` with only `free` available, everything else is used
+    pub fn with_free(end: usize, free: impl Iterator<Item = PhysFrame>) -> BitmapFrameAllocator {
+        let frames = end / 4096;
+        let mut allocator = BitmapFrameAllocator {
+            bitmap: vec![u64::MAX; (frames + 63) / 64],
+            frames,
+            next: 0,
+            free: 0,
+        };
+        for frame in free {
+            unsafe { allocator.deallocate_frame(frame) };
+        }
+        allocator.next = 0;
+        allocator
+    }
+
+    /// Frames that can still be allocated
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Frames covered by the bitmap, usable or not
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    /// The lowest run of `count` free frames, all marked allocated, returning the first
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        self.allocate_aligned(count, 1)
+    }
+
+    /// `allocate_contiguous` with the first frame's number a multiple of `align`
+    pub fn allocate_aligned(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        if count == 0 || align == 0 {
+            return None;
+        }
+
+        let align_up = |index: usize| (index + align - 1) / align * align;
+        let mut start = align_up(self.next * 64);
+        let mut index = start;
+        while index < self.frames {
+            if self.bitmap[index / 64] & (1 << (index % 64)) != 0 {
+                start = align_up(index + 1);
+                index = start;
+                continue;
+            }
+            if index + 1 - start == count {
+                for i in start..=index {
+                    self.bitmap[i / 64] |= 1 << (i % 64);
+                }
+                self.free -= count;
+                let addr = start * 4096;
+                return Some(PhysFrame::containing_address(PhysAddr::new(addr as u64)));
+            }
+            index += 1;
+        }
+        None
+    }
+
+    pub fn is_allocated(&self, frame: PhysFrame) -> bool {
+        let index = (frame.start_address().as_u64() / 4096) as usize;
+        index >= self.frames || self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        while self.next < self.bitmap.len() {
+            let word = &mut self.bitmap[self.next];
+            if *word != u64::MAX {
+                let bit = (!*word).trailing_zeros() as usize;
+                *word |= 1 << bit;
+                self.free -= 1;
+                let addr = (self.next * 64 + bit) * 4096;
+                return Some(PhysFrame::containing_address(PhysAddr::new(addr as u64)));
+            }
+            self.next += 1;
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    /// Frames past the end of the bitmap or already free are ignored
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / 4096) as usize;
+        if index >= self.frames {
+            return;
+        }
+
+        let word = &mut self.bitmap[index / 64];
+        let mask = 1 << (index % 64);
+        if *word & mask != 0 {
+            *word &= !mask;
+            self.free += 1;
+            self.next = self.next.min(index / 64);
+        }
+    }
+}
@@ -1,8 +1,16 @@
 
-pub const PAGE_TABLE_OFFSET: u64 = size_tb!(10); // 10 TB
+// All of physical memory is mapped here in the kernel's address space (start of the higher half)
+pub const PHYS_OFFSET: u64 = 0xFFFF_8000_0000_0000;
 pub const PROCESS_STACK_ADDRESS: usize = size_gb!(5); // 5GB
 
 pub const HEAP_START: usize = size_tb!(3);
 pub const HEAP_SIZE: usize = size_mb!(10);
+// Largest heap init_heap_sized maps, the heap slide always leaves room for it
+pub const HEAP_MAX_SIZE: usize = size_gb!(1);
+// Virtual space heaps can be placed in
+pub const HEAP_REGION_END: usize = size_tb!(4);
 
-pub const KERNEL_CODE: u64 = size_tb!(2);
\ No newline at end of file
+pub const KERNEL_CODE: u64 = size_tb!(2);
+
+// Runtime services are mapped at physical + offset, they stay identity mapped for now
+pub const RUNTIME_SERVICES_OFFSET: u64 = 0;
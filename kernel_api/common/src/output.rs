@@ -0,0 +1,110 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU16, AtomicU8, Ordering},
+};
+
+use crate::serial::SerialPort;
+
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+
+/// Where `kprint!` and `kprintln!` send their output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OutputTarget {
+    Serial = 0,
+    Framebuffer = 1,
+    Both = 2,
+}
+
+impl From<u8> for OutputTarget {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => OutputTarget::Framebuffer,
+            2 => OutputTarget::Both,
+            _ => OutputTarget::Serial,
+        }
+    }
+}
+
+static TARGET: AtomicU8 = AtomicU8::new(OutputTarget::Serial as u8);
+
+// Where the panic handler prints, kept apart from COM1 when debugging so crash output doesn't
+// interleave with normal logs
+static PANIC_PORT: AtomicU16 = AtomicU16::new(COM1);
+
+// Draws text on the screen, registered by the framebuffer console once it's up
+static mut FRAMEBUFFER: Option<fn(&[u8])> = None;
+
+pub fn set_target(target: OutputTarget) {
+    TARGET.store(target as u8, Ordering::SeqCst);
+}
+
+pub fn target() -> OutputTarget {
+    OutputTarget::from(TARGET.load(Ordering::SeqCst))
+}
+
+pub fn set_panic_port(port: u16) {
+    PANIC_PORT.store(port, Ordering::SeqCst);
+}
+
+pub fn panic_port() -> u16 {
+    PANIC_PORT.load(Ordering::SeqCst)
+}
+
+pub fn register_framebuffer(writer: fn(&[u8])) {
+    unsafe {
+        FRAMEBUFFER.replace(writer);
+    }
+}
+
+struct Writer {
+    serial: Option<SerialPort>,
+    framebuffer: Option<fn(&[u8])>,
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // A wedged UART shouldn't keep the text off the framebuffer
+        if let Some(serial) = &mut self.serial {
+            let _ = serial.write(s.as_bytes());
+        }
+        if let Some(framebuffer) = self.framebuffer {
+            framebuffer(s.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// Prints to the current target. Until a framebuffer is registered everything goes to serial so
+/// nothing is lost by picking `Framebuffer` too early.
+pub fn print(args: fmt::Arguments) {
+    let target = target();
+    let framebuffer = match target {
+        OutputTarget::Serial => None,
+        _ => unsafe { FRAMEBUFFER },
+    };
+    let serial = target != OutputTarget::Framebuffer || framebuffer.is_none();
+
+    let mut writer = Writer {
+        serial: if serial {
+            Some(SerialPort::from(COM1))
+        } else {
+            None
+        },
+        framebuffer,
+    };
+    // Nothing sensible to do when printing fails, panicking here would just print again
+    let _ = fmt::write(&mut writer, args);
+}
+
+/// Always prints to serial, for the panic path where the framebuffer may be broken or not set up
+pub fn print_serial(args: fmt::Arguments) {
+    print_to(COM1, args);
+}
+
+/// Prints to the UART at `port` only, ignoring the target
+pub fn print_to(port: u16, args: fmt::Arguments) {
+    let mut serial = SerialPort::from(port);
+    let _ = fmt::write(&mut serial, args);
+}
@@ -1,9 +1,13 @@
-use core::{arch::asm, sync::atomic::AtomicU32};
+use core::{
+    arch::asm,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use alloc::boxed::Box;
 use x86_64::{
     structures::paging::{
-        page::PageRangeInclusive, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
         PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, mapper::MapToError,
     },
     PhysAddr, VirtAddr,
@@ -12,7 +16,8 @@ use x86_64::{
 use crate::{
     efi,
     elf::{self, SegmentType},
-    mem, memory_regions::{self, PROCESS_STACK_ADDRESS},
+    fd::FdTable,
+    mem, memory_regions,
 };
 
 
@@ -25,86 +30,109 @@ pub fn set_syscall_sp() {
     unsafe { asm!("mov {}, rsp", out(reg) SYSCALL_SP) }
 }
 
-pub type ProcessId = u32;
+pub type Signal = u8;
+
+pub const SIGNAL_COUNT: usize = 32;
+// Terminates the process unless it has a handler, other signals are ignored by default
+pub const SIGTERM: Signal = 15;
+// Sent by the kernel when it can't use a process's memory, always terminates it
+pub const SIGSEGV: Signal = 11;
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies a process. Handed out in increasing order and never reused, so a stale pid can't
+/// end up pointing at a newer process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub u64);
+
+impl Pid {
+    fn next() -> Pid {
+        Pid(NEXT_PID.fetch_add(1, Ordering::SeqCst))
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Forwarded so width and alignment apply
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+pub const MAX_NAME: usize = 32;
 
-static IDINDEX: AtomicU32 = AtomicU32::new(0);
+/// Bit n set means the process may run on the cpu with apic id n
+pub type CpuMask = u64;
+pub const ALL_CPUS: CpuMask = u64::MAX;
+
+/// Fixed size so naming a process doesn't need the heap. Longer names are truncated.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessName {
+    bytes: [u8; MAX_NAME],
+    len: usize,
+}
+
+impl ProcessName {
+    pub fn new(name: &str) -> ProcessName {
+        // Boot image names are null padded
+        let name = name.trim_end_matches('\0');
+        let mut len = name.len().min(MAX_NAME);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        let mut bytes = [0; MAX_NAME];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        ProcessName { bytes, len }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever cut at a char boundary in new
+        unsafe { core::str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+}
 
 #[derive(Debug)]
 pub struct Process {
-    pub id: ProcessId,
+    pid: Pid,
+    name: ProcessName,
     pub address_space: Box<PageTable>,
     pub stack_base: *mut u64,
     pub entry: fn(),
+    pub fds: FdTable,
+    pending_signals: u32,
+    // User addresses of the handlers
+    signal_handlers: [Option<u64>; SIGNAL_COUNT],
+    // Time spent running in tsc cycles
+    cpu_time: u64,
+    affinity: CpuMask,
 }
 
 impl Process {
-    pub fn get_stack() -> PageRangeInclusive {
-        let stack_page_start =
-            Page::containing_address(VirtAddr::new(PROCESS_STACK_ADDRESS as u64));
-
-        let stack_page_end =
-            Page::containing_address(VirtAddr::new(PROCESS_STACK_ADDRESS as u64 - size_mb!(1)));
-        Page::range_inclusive(stack_page_end, stack_page_start)
-    }
-
     pub fn kernel_from_elf(
+        name: &str,
         elf: &elf::ElfFile<'_>,
         kernel_stack_start: u64,
         kernel_stack_end: u64,
-        mem: usize,
         current_mapper: &mut impl Mapper<Size4KiB>,
         frame_allocator: &mut impl FrameAllocator<Size4KiB>,
     ) -> Process {
         let mut new_page_table = Box::new(PageTable::new());
         let mut mapper = unsafe { OffsetPageTable::new(&mut new_page_table, VirtAddr::new(0)) };
 
-        let phys_mem_start = PhysFrame::containing_address(PhysAddr::zero());
-        let phys_mem_end = PhysFrame::containing_address(PhysAddr::new(mem as _));
-        let phys_frames = PhysFrame::<Size1GiB>::range_inclusive(phys_mem_start, phys_mem_end);
-
-        unsafe {
-            for frame in phys_frames {
-                kprintln!("Frame {:x?}", frame);
-                let page = Page::containing_address(
-                    VirtAddr::new(memory_regions::PAGE_TABLE_OFFSET) + frame.start_address().as_u64(),
-                );
-                mapper
-                    .map_to(
-                        page,
-                        frame,
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-                        frame_allocator,
-                    )
-                    .expect("Unable to identity map!")
-                    .ignore();
-            }
-        }
+        // The kernel finds everything by physical address through this
+        crate::mem::map_physical_memory(&mut mapper, frame_allocator, efi::memory_map())
+            .expect("Unable to map physical memory!");
 
         // Setup stack
-        let stack_pages = Process::get_stack();
-        let stack_flags =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
-
-        for page in stack_pages {
-            kprintln!("Process Stack {:?}", page);
-            let stack_frame = frame_allocator
-                .allocate_frame()
-                .expect("Unable to allocate page for process stack!");
-            unsafe {
-                mapper
-                    .map_to(page, stack_frame, stack_flags, frame_allocator)
-                    .expect("Unable to map page for process stack!")
-                // .flush()
-            };
-        }
+        let stack_top =
+            mem::map_stack_with_guard(&mut mapper, frame_allocator, mem::PROCESS_STACK_PAGES)
+                .expect("Unable to map process stack!");
 
         /* Map kernel crap for syscalls and interrupts */
-        let kernel_code_descriptor = unsafe {
-            efi::DESCRIPTORS
-                .iter()
-                .find(|d| matches!(d.memory_type, efi::MemoryType::LoaderCode))
-                .expect("Unable to find loader code!")
-        };
+        let kernel_code_descriptor = efi::memory_map()
+            .iter()
+            .find(|d| matches!(d.memory_type, efi::MemoryType::LoaderCode))
+            .expect("Unable to find loader code!");
         let kernel_code_start = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(
             kernel_code_descriptor.physical_address as u64,
         ));
@@ -231,18 +259,22 @@ impl Process {
             }
         }
 
-        let id = IDINDEX.load(core::sync::atomic::Ordering::SeqCst);
-        IDINDEX.store(id, core::sync::atomic::Ordering::SeqCst);
-
         Process {
-            id,
+            pid: Pid::next(),
+            name: ProcessName::new(name),
             address_space: new_page_table,
-            stack_base: PROCESS_STACK_ADDRESS as *mut u64,
+            stack_base: stack_top.as_mut_ptr(),
             entry: unsafe { core::mem::transmute(header.entry as *const ()) },
+            fds: FdTable::new(),
+            pending_signals: 0,
+            signal_handlers: [None; SIGNAL_COUNT],
+            cpu_time: 0,
+            affinity: ALL_CPUS,
         }
     }
 
     pub fn from_elf(
+        name: &str,
         elf: &elf::ElfFile<'_>,
         kernel: &elf::ElfFile<'_>,
         kernel_stack_start: u64,
@@ -255,7 +287,7 @@ impl Process {
         #[cfg(feature = "bootloader")]
         let mut mapper = unsafe { OffsetPageTable::new(&mut new_page_table, VirtAddr::new(0)) };
         #[cfg(feature = "kernel")]
-        let mut mapper = unsafe { OffsetPageTable::new(&mut new_page_table, VirtAddr::new(memory_regions::PAGE_TABLE_OFFSET)) };
+        let mut mapper = unsafe { OffsetPageTable::new(&mut new_page_table, VirtAddr::new(memory_regions::PHYS_OFFSET)) };
 
         let phys_mem_start = PhysFrame::containing_address(PhysAddr::zero());
         let phys_mem_end = PhysFrame::containing_address(PhysAddr::new(mem as _));
@@ -265,7 +297,7 @@ impl Process {
             for frame in phys_frames {
                 kprintln!("Frame {:x?}", frame);
                 let page = Page::containing_address(
-                    VirtAddr::new(memory_regions::PAGE_TABLE_OFFSET) + frame.start_address().as_u64(),
+                    VirtAddr::new(memory_regions::PHYS_OFFSET) + frame.start_address().as_u64(),
                 );
                 mapper
                     .map_to(
@@ -280,22 +312,9 @@ impl Process {
         }
 
         // Setup stack
-        let stack_pages = Process::get_stack();
-        let stack_flags =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
-
-        for page in stack_pages {
-            kprintln!("Process Stack {:?}", page);
-            let stack_frame = frame_allocator
-                .allocate_frame()
-                .expect("Unable to allocate page for process stack!");
-            unsafe {
-                mapper
-                    .map_to(page, stack_frame, stack_flags, frame_allocator)
-                    .expect("Unable to map page for process stack!")
-                // .flush()
-            };
-        }
+        let stack_top =
+            mem::map_stack_with_guard(&mut mapper, frame_allocator, mem::PROCESS_STACK_PAGES)
+                .expect("Unable to map process stack!");
 
         /* Map kernel crap for syscalls and interrupts */
         // let kernel_code_start =
@@ -462,14 +481,107 @@ impl Process {
             }
         }
 
-        let id = IDINDEX.load(core::sync::atomic::Ordering::SeqCst);
-        IDINDEX.store(id, core::sync::atomic::Ordering::SeqCst);
-
         Process {
-            id,
+            pid: Pid::next(),
+            name: ProcessName::new(name),
             address_space: new_page_table,
-            stack_base: PROCESS_STACK_ADDRESS as *mut u64,
+            stack_base: stack_top.as_mut_ptr(),
             entry: unsafe { core::mem::transmute(header.entry as *const ()) },
+            fds: FdTable::new(),
+            pending_signals: 0,
+            signal_handlers: [None; SIGNAL_COUNT],
+            cpu_time: 0,
+            affinity: ALL_CPUS,
+        }
+    }
+
+    /// A process with an empty address space and no code, it must never be run. Gives the
+    /// schedular something to rotate through in the selftest.
+    pub fn placeholder(name: &str) -> Process {
+        Process {
+            pid: Pid::next(),
+            name: ProcessName::new(name),
+            address_space: Box::new(PageTable::new()),
+            stack_base: core::ptr::null_mut(),
+            entry: || {},
+            fds: FdTable::new(),
+            pending_signals: 0,
+            signal_handlers: [None; SIGNAL_COUNT],
+            cpu_time: 0,
+            affinity: ALL_CPUS,
+        }
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    /// Marks `signal` pending, it is delivered the next time the process returns to user mode
+    pub fn raise_signal(&mut self, signal: Signal) -> bool {
+        if signal as usize >= SIGNAL_COUNT {
+            return false;
+        }
+        self.pending_signals |= 1 << signal;
+        true
+    }
+
+    /// Takes the lowest pending signal
+    pub fn next_signal(&mut self) -> Option<Signal> {
+        if self.pending_signals == 0 {
+            return None;
+        }
+        let signal = self.pending_signals.trailing_zeros();
+        self.pending_signals &= !(1 << signal);
+        Some(signal as Signal)
+    }
+
+    /// `handler` isn't checked, the syscall only lets a process register lower half addresses
+    pub fn set_signal_handler(&mut self, signal: Signal, handler: Option<u64>) -> bool {
+        match self.signal_handlers.get_mut(signal as usize) {
+            Some(slot) => {
+                *slot = handler;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn signal_handler(&self, signal: Signal) -> Option<u64> {
+        *self.signal_handlers.get(signal as usize)?
+    }
+
+    pub fn cpu_time(&self) -> u64 {
+        self.cpu_time
+    }
+
+    pub fn charge(&mut self, cycles: u64) {
+        self.cpu_time += cycles;
+    }
+
+    /// Restricts which cpus the schedular runs the process on. An empty mask would never run so
+    /// it's refused.
+    pub fn set_affinity(&mut self, mask: CpuMask) -> bool {
+        if mask == 0 {
+            return false;
+        }
+        self.affinity = mask;
+        true
+    }
+
+    pub fn affinity(&self) -> CpuMask {
+        self.affinity
+    }
+
+    pub fn can_run_on(&self, cpu: usize) -> bool {
+        // Cpus past the mask are only allowed by the default
+        if cpu < 64 {
+            self.affinity & (1 << cpu) != 0
+        } else {
+            self.affinity == ALL_CPUS
         }
     }
 
@@ -1,72 +1,228 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use crate::util;
 
+// Divisor 1 runs at this rate
+const UART_CLOCK: u32 = 115200;
+
+// Register offsets from the base address
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+// Gates the UART's interrupt line onto the bus
+const MCR_OUT2: u8 = 0x08;
+const MCR_LOOPBACK: u8 = 0x10;
+
+const IER_RECEIVED_DATA: u8 = 0x01;
+
+const LCR_8N1: u8 = 0x03;
+// Divisor latch access, the first two registers become the divisor while set
+const LCR_DLAB: u8 = 0x80;
+
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_TRANSMIT_EMPTY: u8 = 0x20;
+const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
+
+const MSR_CTS: u8 = 0x10;
+const MSR_DCD: u8 = 0x80;
+
+// Bytes sent through the loopback, with alternating bits so stuck lines show up
+const LOOPBACK_PATTERN: [u8; 4] = [0x55, 0xAA, 0x00, 0xFF];
+// Reads of the line status before giving up on a looped back byte
+const LOOPBACK_TIMEOUT: usize = 100_000;
+
+// Line status polls before giving up on the transmit register, far longer than a byte takes at
+// any baud rate
+const TRANSMIT_TIMEOUT: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteError {
+    /// The transmit register never emptied, the UART is wedged. The port is disabled after this.
+    Timeout,
+    /// Flow control is on and the other end never asserted CTS
+    NotClearToSend,
+}
+
+// Ports are recreated with `from` on every print so this has to live outside the struct
+static FLOW_CONTROL: AtomicBool = AtomicBool::new(false);
+
+// Standard COM port addresses and whether `probe` found a UART there. Ports are assumed to be
+// present until probed.
+const COM_PORTS: [u16; 4] = [0x3F8, 0x2F8, 0x3E8, 0x2E8];
+static PRESENT: [AtomicBool; 4] = [
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+    AtomicBool::new(true),
+];
+
+fn present_flag(address: u16) -> Option<&'static AtomicBool> {
+    COM_PORTS
+        .iter()
+        .position(|&port| port == address)
+        .map(|index| &PRESENT[index])
+}
+
+/// Checks for a working UART at `address` with the loopback test and remembers the result.
+/// Ports that fail are disabled so writes to them are dropped instead of hanging or faulting.
+pub fn probe(address: u16) -> bool {
+    let present = SerialPort {
+        address,
+        enabled: true,
+    }
+    .self_test();
+
+    if let Some(flag) = present_flag(address) {
+        flag.store(present, Ordering::SeqCst);
+    }
+    present
+}
+
+pub fn is_present(address: u16) -> bool {
+    present_flag(address).map_or(true, |flag| flag.load(Ordering::SeqCst))
+}
+
 pub struct SerialPort {
     address: u16,
     enabled: bool,
 }
 
 impl SerialPort {
+    /// Programs the UART at `address` for 115200 baud
     pub fn new(address: u16) -> Self {
+        SerialPort::with_baud(address, UART_CLOCK)
+    }
+
+    /// Programs the UART at `address` for `baud`, 8 data bits, no parity and one stop bit, with
+    /// the fifos enabled and interrupts off. The UART can only run at 115200 / divisor, so other
+    /// rates are rounded to the nearest one it can do (56000 runs at 57600). See `divisor`.
+    pub fn with_baud(address: u16, baud: u32) -> Self {
+        let divisor = SerialPort::divisor(baud);
         unsafe {
-            // util::out8(address + 1, 0x00); // Disable interrupts
-            // util::out8(address + 3, 0x80); // Enable DLAB
-            // util::out8(address + 0, 0x01); // Set divisor to 3 (low byte) 38400 baud
-            // util::out8(address + 1, 0x00); //                  (high byte) 38400 baud
-            // util::out8(address + 3, 0x03); // 8 bits, no parity, one stop bit
-            //                                // util::out8(address + 2, 0xC7); // Enable FIFO, clear them, with 14-byte threshold
-            // util::out8(address + 4, 0x03); // IRQs enabled, RTS/DSR set
-            //                                // util::out8(address + 4, 0x1E); // Set in loopback mode, test the serial chip
-            //                                // core::ptr::write_volatile(0x14141414141414 as *mut u64, 0);
-            //                                // util::out8(address + 0, 0x69); // Testing
-            util::out8(address + 1, 0x00); // Disable interrupts
-            util::out8(address + 3, 0x80); // Enable DLAB
-            util::out8(address + 0, 0x01); // Set divisor to 3 (low byte) 38400 baud
-            util::out8(address + 1, 0x00); //                  (high byte) 38400 baud
-            util::out8(address + 3, 0x03); // 8 bits, no parity, one stop bit
-                                           // util::out8(address + 2, 0xC7); // Enable FIFO, clear them, with 14-byte threshold
-            util::out8(address + 2, 0xC7); // IRQs enabled, RTS/DSR set
-            util::out8(address + 4, 0x0F); // IRQs enabled, RTS/DSR set
-                                           // util::out8(address + 4, 0x1E); // Set in loopback mode, test the serial chip
-                                           // core::ptr::write_volatile(0x14141414141414 as *mut u64, 0);
-                                           // util::out8(address + 0, 0x69); // Testing
-            util::out8(address + 1, 0x01); // Disable interrupts
-            SerialPort {
-                address,
-                enabled: true,
-            }
-            // if util::in8(address) != 0x69 {
-            //     SerialPort {
-            //         address,
-            //         enabled: false,
-            //     }
-            // } else {
-            //     util::out8(address + 4, 0x0F); // disable loopback, IRQs, OUT 1 and 2
-            //     SerialPort {
-            //         address,
-            //         enabled: true,
-            //     }
-            // }
+            util::out8(address + INTERRUPT_ENABLE, 0x00);
+            util::out8(address + LINE_CONTROL, LCR_DLAB);
+            util::out8(address, divisor as u8); // Divisor latch low
+            util::out8(address + 1, (divisor >> 8) as u8); // Divisor latch high
+            util::out8(address + LINE_CONTROL, LCR_8N1);
+            util::out8(address + FIFO_CONTROL, 0xC7); // Enable and clear, 14 byte threshold
+            util::out8(address + MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_OUT2);
         }
+        SerialPort::from(address)
+    }
+
+    /// Divisor latch value for `baud`, rounded to the nearest rate and clamped to what the latch
+    /// holds. Zero is treated as the fastest rate.
+    pub fn divisor(baud: u32) -> u16 {
+        let baud = baud.max(1);
+        ((UART_CLOCK + baud / 2) / baud).clamp(1, u16::MAX as u32) as u16
+    }
+
+    /// The rate the UART actually runs at when asked for `baud`
+    pub fn actual_baud(baud: u32) -> u32 {
+        UART_CLOCK / SerialPort::divisor(baud) as u32
     }
 
     pub fn from(address: u16) -> Self {
         SerialPort {
             address,
-            enabled: true,
+            enabled: is_present(address),
+        }
+    }
+
+    /// Raises the UART's irq whenever a byte is received. OUT2 has to be set for the irq to reach
+    /// the interrupt controller, so it's set here too.
+    pub fn enable_interrupts(&self) {
+        unsafe {
+            let mcr = util::in8(self.address + MODEM_CONTROL);
+            util::out8(self.address + MODEM_CONTROL, mcr | MCR_OUT2);
+            let ier = util::in8(self.address + INTERRUPT_ENABLE);
+            util::out8(self.address + INTERRUPT_ENABLE, ier | IER_RECEIVED_DATA);
+        }
+    }
+
+    /// Enables RTS/CTS flow control. RTS and DTR are asserted to tell the other end we are ready
+    /// to receive and transmitting waits for the other end to assert CTS.
+    pub fn set_flow_control(&self, enabled: bool) {
+        unsafe {
+            let mcr = util::in8(self.address + MODEM_CONTROL);
+            util::out8(self.address + MODEM_CONTROL, mcr | MCR_DTR | MCR_RTS);
         }
+        FLOW_CONTROL.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn flow_control(&self) -> bool {
+        FLOW_CONTROL.load(Ordering::SeqCst)
+    }
+
+    pub fn clear_to_send(&self) -> bool {
+        unsafe { util::in8(self.address + MODEM_STATUS) & MSR_CTS != 0 }
     }
 
-    fn has_data(&self) -> bool {
-        unsafe { (util::in8(self.address + 5) & 1) == 0 }
+    pub fn carrier_detect(&self) -> bool {
+        unsafe { util::in8(self.address + MODEM_STATUS) & MSR_DCD != 0 }
     }
 
-    fn can_write(&self) -> bool {
-        unsafe { (util::in8(self.address + 5) & 0x20) == 0 }
+    /// A received byte is waiting in the receive buffer
+    pub fn data_ready(&self) -> bool {
+        unsafe { util::in8(self.address + LINE_STATUS) & LSR_DATA_READY != 0 }
     }
 
+    /// Loops the UART's output back into its input and checks a test pattern comes back. False
+    /// means the UART is broken or missing rather than the other end not listening. Received
+    /// data waiting in the buffer is discarded and nothing is sent on the wire while testing.
+    pub fn self_test(&self) -> bool {
+        unsafe {
+            // Let queued output finish first or it gets looped back instead of sent
+            (0..LOOPBACK_TIMEOUT)
+                .any(|_| util::in8(self.address + LINE_STATUS) & LSR_TRANSMITTER_EMPTY != 0);
+
+            let mcr = util::in8(self.address + MODEM_CONTROL);
+            util::out8(self.address + MODEM_CONTROL, mcr | MCR_LOOPBACK);
+
+            // Drain the receive fifo, bounded in case the port isn't there and reads as 0xFF
+            for _ in 0..16 {
+                if !self.data_ready() {
+                    break;
+                }
+                util::in8(self.address);
+            }
+
+            let passed = LOOPBACK_PATTERN.iter().all(|&b| {
+                util::out8(self.address, b);
+                (0..LOOPBACK_TIMEOUT).any(|_| self.data_ready()) && util::in8(self.address) == b
+            });
+
+            util::out8(self.address + MODEM_CONTROL, mcr);
+            passed
+        }
+    }
+
+    fn transmit_empty(&self) -> bool {
+        unsafe { util::in8(self.address + LINE_STATUS) & LSR_TRANSMIT_EMPTY != 0 }
+    }
+
+    fn transmit(&self, value: u8) -> Result<(), WriteError> {
+        if !(0..TRANSMIT_TIMEOUT).any(|_| self.transmit_empty()) {
+            // Don't spin on every following byte too
+            if let Some(flag) = present_flag(self.address) {
+                flag.store(false, Ordering::SeqCst);
+            }
+            return Err(WriteError::Timeout);
+        }
+        unsafe { util::out8(self.address, value) };
+        Ok(())
+    }
+
+    /// The next received byte, None when nothing is waiting
     pub fn read_byte(&self) -> Option<u8> {
         unsafe {
-            if self.has_data() && self.enabled {
+            if self.enabled && self.data_ready() {
                 Some(util::in8(self.address))
             } else {
                 None
@@ -74,29 +230,43 @@ impl SerialPort {
         }
     }
 
-    pub fn write_byte(&self, value: u8) {
-        unsafe {
-            while self.can_write() {}
-            match value {
-                b'\n' => util::out8(self.address, b'\r'),
-                _ => (),
+    /// Spins until a byte arrives. Returns None straight away on a port that isn't present rather
+    /// than spinning forever.
+    pub fn read_byte_blocking(&self) -> Option<u8> {
+        if !self.enabled {
+            return None;
+        }
+        loop {
+            if let Some(b) = self.read_byte() {
+                return Some(b);
             }
-            util::out8(self.address, value);
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Waits a bounded time for the UART instead of hanging when it's wedged
+    pub fn write_byte(&self, value: u8) -> Result<(), WriteError> {
+        if self.flow_control() && !(0..TRANSMIT_TIMEOUT).any(|_| self.clear_to_send()) {
+            return Err(WriteError::NotClearToSend);
+        }
+        if value == b'\n' {
+            self.transmit(b'\r')?;
         }
+        self.transmit(value)
     }
 
-    pub fn write(&self, value: &[u8]) {
+    pub fn write(&self, value: &[u8]) -> Result<(), WriteError> {
         if self.enabled {
             for &b in value {
-                self.write_byte(b);
+                self.write_byte(b)?;
             }
         }
+        Ok(())
     }
 }
 
 impl core::fmt::Write for SerialPort {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        self.write(s.as_bytes());
-        Ok(())
+        self.write(s.as_bytes()).map_err(|_| core::fmt::Error)
     }
 }
@@ -0,0 +1,108 @@
+use core::{alloc::Layout, ptr::NonNull};
+
+use crate::linked_list_allocator;
+
+// Each size is also the alignment of its blocks. Anything bigger goes straight to the fallback.
+const BLOCK_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Fixed size block allocator. Small allocations are rounded up to a block size and freed blocks
+/// go on a list for that size, so they're reused in O(1) without fragmenting the heap. Blocks are
+/// carved from a linked list heap, which also serves anything bigger than the largest block.
+#[derive(Clone)]
+pub struct Heap {
+    // Address of the first free block of each size, 0 when empty. Free blocks hold the address of
+    // the next one.
+    free_lists: [usize; BLOCK_SIZES.len()],
+    fallback: linked_list_allocator::Heap,
+    used: usize,
+}
+
+impl Heap {
+    pub const fn empty() -> Heap {
+        Heap {
+            free_lists: [0; BLOCK_SIZES.len()],
+            fallback: linked_list_allocator::Heap::empty(),
+            used: 0,
+        }
+    }
+
+    pub fn update(&mut self, other: &Heap) {
+        *self = other.clone();
+    }
+
+    /// # Unsafety
+    ///
+    /// The memory in `[heap_bottom, heap_bottom + heap_size)` must be unused
+    pub unsafe fn init(&mut self, heap_bottom: usize, heap_size: usize) {
+        self.free_lists = [0; BLOCK_SIZES.len()];
+        self.fallback.init(heap_bottom, heap_size);
+        self.used = 0;
+    }
+
+    fn block_index(layout: &Layout) -> Option<usize> {
+        let required = layout.size().max(layout.align());
+        BLOCK_SIZES.iter().position(|&size| size >= required)
+    }
+
+    pub fn allocate_first_fit(&mut self, layout: Layout) -> Result<NonNull<u8>, ()> {
+        let index = match Heap::block_index(&layout) {
+            Some(index) => index,
+            None => {
+                let ptr = self.fallback.allocate_first_fit(layout)?;
+                self.used += layout.size();
+                return Ok(ptr);
+            }
+        };
+
+        let size = BLOCK_SIZES[index];
+        let ptr = match self.free_lists[index] {
+            0 => {
+                let block = Layout::from_size_align(size, size).map_err(|_| ())?;
+                self.fallback.allocate_first_fit(block)?
+            }
+            head => unsafe {
+                self.free_lists[index] = *(head as *const usize);
+                NonNull::new_unchecked(head as *mut u8)
+            },
+        };
+        self.used += size;
+        Ok(ptr)
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        match Heap::block_index(&layout) {
+            Some(index) => {
+                let block = ptr.as_ptr() as *mut usize;
+                *block = self.free_lists[index];
+                self.free_lists[index] = block as usize;
+                self.used -= BLOCK_SIZES[index];
+            }
+            None => {
+                self.fallback.deallocate(ptr, layout);
+                self.used -= layout.size();
+            }
+        }
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.fallback.bottom()
+    }
+
+    pub fn size(&self) -> usize {
+        self.fallback.size()
+    }
+
+    pub fn top(&self) -> usize {
+        self.fallback.top()
+    }
+
+    /// Bytes in live allocations, rounded up to their block size
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Counts blocks on the free lists, which can only be reused for their own size
+    pub fn free(&self) -> usize {
+        self.size() - self.used
+    }
+}
@@ -0,0 +1,115 @@
+use core::{alloc::Layout, marker::PhantomData};
+
+use alloc::{
+    alloc::{alloc, dealloc},
+    vec::Vec,
+};
+
+const SLAB_SIZE: usize = 4096;
+
+/// Cache of slots for one type, carved from page sized slabs taken from the heap. Freed slots go
+/// on a list and are handed out again before anything new, so alloc and free are O(1) and objects
+/// that come and go often don't fragment the heap or take its lock. Another slab is added when
+/// every slot is in use. Slabs are only given back to the heap when the cache is dropped.
+pub struct SlabCache<T> {
+    // Address of the first free slot, 0 when empty. Free slots hold the address of the next one.
+    free_list: usize,
+    slabs: Vec<usize>,
+    allocated: usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    pub const fn new() -> SlabCache<T> {
+        SlabCache {
+            free_list: 0,
+            slabs: Vec::new(),
+            allocated: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    // A free slot has to fit the address of the next one
+    fn slot_layout() -> Layout {
+        let layout = Layout::new::<T>();
+        Layout::from_size_align(
+            layout.size().max(core::mem::size_of::<usize>()),
+            layout.align().max(core::mem::align_of::<usize>()),
+        )
+        .unwrap()
+        .pad_to_align()
+    }
+
+    // A page, or as many as it takes for one slot of a big T
+    fn slab_layout() -> Layout {
+        let slot = SlabCache::<T>::slot_layout();
+        let size = (slot.size() + SLAB_SIZE - 1) / SLAB_SIZE * SLAB_SIZE;
+        Layout::from_size_align(size, slot.align().max(SLAB_SIZE)).unwrap()
+    }
+
+    pub fn slots_per_slab() -> usize {
+        SlabCache::<T>::slab_layout().size() / SlabCache::<T>::slot_layout().size()
+    }
+
+    /// An uninitialized slot for a `T`, null if the heap has no room for another slab
+    pub fn alloc(&mut self) -> *mut T {
+        if self.free_list == 0 && !self.grow() {
+            return core::ptr::null_mut();
+        }
+
+        let slot = self.free_list;
+        self.free_list = unsafe { *(slot as *const usize) };
+        self.allocated += 1;
+        slot as *mut T
+    }
+
+    /// Puts the slot back on the free list. The `T` in it isn't dropped.
+    ///
+    /// # Unsafety
+    ///
+    /// `ptr` must have come from `alloc` on this cache and not have been freed since
+    pub unsafe fn free(&mut self, ptr: *mut T) {
+        *(ptr as *mut usize) = self.free_list;
+        self.free_list = ptr as usize;
+        self.allocated -= 1;
+    }
+
+    fn grow(&mut self) -> bool {
+        let slab = unsafe { alloc(SlabCache::<T>::slab_layout()) } as usize;
+        if slab == 0 {
+            return false;
+        }
+
+        // Pushed in reverse so the slab is handed out from its start
+        let slot_size = SlabCache::<T>::slot_layout().size();
+        for i in (0..SlabCache::<T>::slots_per_slab()).rev() {
+            let slot = slab + i * slot_size;
+            unsafe {
+                *(slot as *mut usize) = self.free_list;
+            }
+            self.free_list = slot;
+        }
+        self.slabs.push(slab);
+        true
+    }
+
+    /// Slots handed out and not freed
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// Slots in every slab, in use or not
+    pub fn capacity(&self) -> usize {
+        self.slabs.len() * SlabCache::<T>::slots_per_slab()
+    }
+}
+
+impl<T> Drop for SlabCache<T> {
+    fn drop(&mut self) {
+        for &slab in &self.slabs {
+            unsafe { dealloc(slab as *mut u8, SlabCache::<T>::slab_layout()) }
+        }
+    }
+}
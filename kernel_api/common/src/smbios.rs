@@ -0,0 +1,196 @@
+use crate::efi::{self, guid};
+
+const SYSTEM_INFORMATION: u8 = 1;
+const MEMORY_ARRAY_MAPPED_ADDRESS: u8 = 19;
+const END_OF_TABLE: u8 = 127;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+}
+
+/// The SMBIOS structure table. The entry point and table are read in place so the physical
+/// addresses from the configuration table have to be identity mapped.
+pub struct Smbios {
+    pub version: Version,
+    table: &'static [u8],
+}
+
+pub struct Structure<'a> {
+    pub kind: u8,
+    pub handle: u16,
+    // Formatted area, including the 4 byte header
+    pub data: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Structure<'a> {
+    /// Strings are referenced by their 1 based index in the string set after the structure
+    pub fn string(&self, index: u8) -> Option<&'a str> {
+        if index == 0 {
+            return None;
+        }
+        let string = self
+            .strings
+            .split(|&b| b == 0)
+            .nth(index as usize - 1)?;
+        core::str::from_utf8(string).ok()
+    }
+
+    fn byte(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).copied()
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn u64(&self, offset: usize) -> Option<u64> {
+        let bytes = self.data.get(offset..offset + 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    }
+}
+
+pub struct StructureIterator<'a> {
+    table: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for StructureIterator<'a> {
+    type Item = Structure<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.table.get(self.offset..self.offset + 4)?;
+        let kind = header[0];
+        let length = header[1] as usize;
+        let handle = u16::from_le_bytes([header[2], header[3]]);
+        if length < 4 {
+            return None;
+        }
+
+        let data = self.table.get(self.offset..self.offset + length)?;
+
+        // The string set ends with two nulls (just two nulls if there are no strings)
+        let strings_start = self.offset + length;
+        let mut end = strings_start;
+        loop {
+            match self.table.get(end..end + 2) {
+                Some([0, 0]) => break,
+                Some(_) => end += 1,
+                None => return None,
+            }
+        }
+
+        self.offset = end + 2;
+        if kind == END_OF_TABLE {
+            self.offset = self.table.len();
+        }
+
+        Some(Structure {
+            kind,
+            handle,
+            data,
+            strings: &self.table[strings_start..end],
+        })
+    }
+}
+
+fn checksum(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+impl Smbios {
+    pub fn find() -> Option<Smbios> {
+        let table = efi::get_system_table();
+
+        // Prefer the 64 bit entry point when firmware provides both
+        let entry = table
+            .config_tables()
+            .find(|(guid, _)| *guid == guid::SMBIOS3)
+            .or_else(|| table.config_tables().find(|(guid, _)| *guid == guid::SMBIOS))?;
+
+        unsafe { Smbios::from_entry_point(entry.1 as *const u8) }
+    }
+
+    pub unsafe fn from_entry_point(ptr: *const u8) -> Option<Smbios> {
+        let anchor = core::slice::from_raw_parts(ptr, 5);
+        if anchor == b"_SM3_" {
+            let length = *ptr.offset(6) as usize;
+            let entry = core::slice::from_raw_parts(ptr, length);
+            if !checksum(entry) {
+                return None;
+            }
+
+            let size = u32::from_le_bytes(entry[0x0C..0x10].try_into().ok()?) as usize;
+            let address = u64::from_le_bytes(entry[0x10..0x18].try_into().ok()?);
+            Some(Smbios {
+                version: Version {
+                    major: entry[0x07],
+                    minor: entry[0x08],
+                },
+                table: core::slice::from_raw_parts(address as *const u8, size),
+            })
+        } else if &anchor[..4] == b"_SM_" {
+            let length = *ptr.offset(5) as usize;
+            let entry = core::slice::from_raw_parts(ptr, length);
+            if !checksum(entry) {
+                return None;
+            }
+
+            let size = u16::from_le_bytes(entry[0x16..0x18].try_into().ok()?) as usize;
+            let address = u32::from_le_bytes(entry[0x18..0x1C].try_into().ok()?);
+            Some(Smbios {
+                version: Version {
+                    major: entry[0x06],
+                    minor: entry[0x07],
+                },
+                table: core::slice::from_raw_parts(address as *const u8, size),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn structures(&self) -> StructureIterator<'static> {
+        StructureIterator {
+            table: self.table,
+            offset: 0,
+        }
+    }
+
+    fn system_information(&self) -> Option<Structure<'static>> {
+        self.structures().find(|s| s.kind == SYSTEM_INFORMATION)
+    }
+
+    pub fn manufacturer(&self) -> Option<&'static str> {
+        let info = self.system_information()?;
+        info.string(info.byte(0x04)?)
+    }
+
+    pub fn product_name(&self) -> Option<&'static str> {
+        let info = self.system_information()?;
+        info.string(info.byte(0x05)?)
+    }
+
+    /// Total installed memory in bytes from the memory array mapped address structures
+    pub fn installed_memory(&self) -> u64 {
+        self.structures()
+            .filter(|s| s.kind == MEMORY_ARRAY_MAPPED_ADDRESS)
+            .filter_map(|s| {
+                let start = s.u32(0x04)?;
+                let end = s.u32(0x08)?;
+                if start == 0xFFFFFFFF {
+                    // Extended addresses are in bytes
+                    let start = s.u64(0x0F)?;
+                    let end = s.u64(0x17)?;
+                    Some(end - start + 1)
+                } else {
+                    // In kilobytes
+                    Some((end as u64 - start as u64 + 1) * 1024)
+                }
+            })
+            .sum()
+    }
+}
@@ -1,13 +1,16 @@
 #![allow(dead_code)]
 
-use core::arch::asm;
+use core::{
+    arch::{asm, global_asm},
+    marker::PhantomData,
+};
+
+pub mod rng;
 
 #[macro_export]
 macro_rules! kprint {
     ($($arg:tt)*) => ({
-        use core::fmt;
-        let mut serial = $crate::serial::SerialPort::from(0x3F8);
-        fmt::write(&mut serial, format_args!($($arg)*)).expect("Unable to print!");
+        $crate::output::print(format_args!($($arg)*));
     })
 }
 
@@ -15,13 +18,80 @@ macro_rules! kprint {
 macro_rules! kprintln {
     () => ($crate::kprint!("\r\n"));
     ($($arg:tt)*) => ({
-        use core::fmt;
-        let mut serial = $crate::serial::SerialPort::from(0x3F8);
-        fmt::write(&mut serial, format_args!($($arg)*)).expect("Unable to print!");
-        fmt::write(&mut serial, format_args!("\r\n")).expect("Unable to print!");
+        $crate::output::print(format_args!($($arg)*));
+        $crate::output::print(format_args!("\r\n"));
+    })
+}
+
+/// `kprintln!` that only goes to serial, which works even if the framebuffer doesn't
+#[macro_export]
+macro_rules! serial_println {
+    ($($arg:tt)*) => ({
+        $crate::output::print_serial(format_args!($($arg)*));
+        $crate::output::print_serial(format_args!("\r\n"));
+    })
+}
+
+/// Like `kprint!` but always to the UART at `$port`, whatever the output target is. The port
+/// should have been set up with `SerialPort::new` first, same as COM1.
+///
+/// ```ignore
+/// use common::output::{self, COM1, COM2};
+///
+/// SerialPort::new(COM2);
+/// output::set_panic_port(COM2);
+///
+/// kprint_to!(COM1, "normal output ");
+/// kprintln_to!(COM1, "on COM1");
+/// // The panic handler prints here now, away from the logs above
+/// kprintln_to!(output::panic_port(), "crash output on COM2");
+/// ```
+#[macro_export]
+macro_rules! kprint_to {
+    ($port:expr, $($arg:tt)*) => ({
+        $crate::output::print_to($port, format_args!($($arg)*));
+    })
+}
+
+#[macro_export]
+macro_rules! kprintln_to {
+    ($port:expr) => ($crate::kprint_to!($port, "\r\n"));
+    ($port:expr, $($arg:tt)*) => ({
+        let port = $port;
+        $crate::output::print_to(port, format_args!($($arg)*));
+        $crate::output::print_to(port, format_args!("\r\n"));
+    })
+}
+
+pub const ASSERTIONS_ENABLED: bool = cfg!(not(feature = "release"));
+
+/// Like `assert!` but dumps the control registers before panicking. Compiled out (the condition
+/// is still type checked) when the `release` feature is enabled.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => (
+        $crate::kassert!($cond, "{}", stringify!($cond))
+    );
+    ($cond:expr, $($arg:tt)+) => ({
+        if $crate::util::ASSERTIONS_ENABLED && !$cond {
+            $crate::util::assert_failed(format_args!($($arg)+));
+        }
     })
 }
 
+#[cold]
+#[inline(never)]
+pub fn assert_failed(args: core::fmt::Arguments) -> ! {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+    kprintln!("Kernel assertion failed: {}", args);
+    kprintln!("  CR0: {:?}", Cr0::read());
+    kprintln!("  CR2: {:?}", Cr2::read());
+    kprintln!("  CR3: {:?}", Cr3::read());
+    kprintln!("  CR4: {:?}", Cr4::read());
+    panic!("{}", args);
+}
+
 #[no_mangle]
 #[inline(always)]
 pub unsafe fn memcpy(dst: *mut u8, src: *const u8, n: usize) -> *mut u8 {
@@ -159,22 +229,250 @@ pub unsafe fn in32(port: u16) -> u32 {
     ret
 }
 
-#[derive(Debug, Default)]
+// isa-debug-exit device, qemu exits with a status of (code << 1) | 1
+const QEMU_EXIT_PORT: u16 = 0xF4;
+pub const QEMU_EXIT_SUCCESS: u32 = 0x10;
+pub const QEMU_EXIT_FAILURE: u32 = 0x11;
+
+/// Exits qemu when it was started with `-device isa-debug-exit,iobase=0xf4,iosize=0x04`. Returns
+/// if the device isn't there (real hardware) so callers need a fallback.
+pub fn qemu_exit(code: u32) {
+    unsafe { out32(QEMU_EXIT_PORT, code) }
+}
+
+pub trait PortValue: Copy {
+    unsafe fn read_port(port: u16) -> Self;
+    unsafe fn write_port(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+    #[inline(always)]
+    unsafe fn read_port(port: u16) -> u8 {
+        in8(port)
+    }
+
+    #[inline(always)]
+    unsafe fn write_port(port: u16, value: u8) {
+        out8(port, value)
+    }
+}
+
+impl PortValue for u16 {
+    #[inline(always)]
+    unsafe fn read_port(port: u16) -> u16 {
+        in16(port)
+    }
+
+    #[inline(always)]
+    unsafe fn write_port(port: u16, value: u16) {
+        out16(port, value)
+    }
+}
+
+impl PortValue for u32 {
+    #[inline(always)]
+    unsafe fn read_port(port: u16) -> u32 {
+        in32(port)
+    }
+
+    #[inline(always)]
+    unsafe fn write_port(port: u16, value: u32) {
+        out32(port, value)
+    }
+}
+
+/// An I/O port that is always accessed with the width of `T`
+#[derive(Debug, Clone, Copy)]
+pub struct Port<T: PortValue> {
+    port: u16,
+    phantom: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    pub const fn new(port: u16) -> Port<T> {
+        Port {
+            port,
+            phantom: PhantomData,
+        }
+    }
+
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Port at an offset from this one, for registers relative to a base
+    pub const fn offset<U: PortValue>(&self, offset: u16) -> Port<U> {
+        Port::new(self.port + offset)
+    }
+
+    #[inline(always)]
+    pub unsafe fn read(&self) -> T {
+        T::read_port(self.port)
+    }
+
+    #[inline(always)]
+    pub unsafe fn write(&self, value: T) {
+        T::write_port(self.port, value)
+    }
+}
+
+// Only the reserved bit, a new context starts with interrupts disabled
+const INITIAL_FLAGS: u64 = 0x2;
+
+/// Everything `switch_context` needs to resume a context. The layout is fixed, the assembly
+/// addresses the fields by offset (noted next to each).
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
 pub struct CpuState {
-    rax: u64,
-    rbx: u64,
-    rcx: u64,
-    rdx: u64,
-    rsp: u64,
-    rbp: u64,
-    rsi: u64,
-    rdi: u64,
+    rax: u64, // 0x00
+    rbx: u64, // 0x08
+    rcx: u64, // 0x10
+    rdx: u64, // 0x18
+    rsp: u64, // 0x20
+    rbp: u64, // 0x28
+    rsi: u64, // 0x30
+    rdi: u64, // 0x38
+
+    rip: u64, // 0x40
+
+    flags: u64, // 0x48
+
+    r8: u64,  // 0x50
+    r9: u64,  // 0x58
+    r10: u64, // 0x60
+    r11: u64, // 0x68
+    r12: u64, // 0x70
+    r13: u64, // 0x78
+    r14: u64, // 0x80
+    r15: u64, // 0x88
+}
 
-    rip: u64,
+impl CpuState {
+    /// A context that starts running `entry` on the stack ending at `stack_top`, which needs to
+    /// be mapped and writable. `entry` is entered by a return rather than a call so it must never
+    /// return itself.
+    pub fn new(entry: extern "C" fn() -> !, stack_top: u64) -> CpuState {
+        CpuState {
+            // Aligned like right after a call, as if `entry` had a return address
+            rsp: (stack_top & !0xF) - 8,
+            rip: entry as u64,
+            flags: INITIAL_FLAGS,
+            ..CpuState::empty()
+        }
+    }
 
-    flags: u64,
+    /// Somewhere to save a context into, it can't be resumed until `switch_context` has
+    pub const fn empty() -> CpuState {
+        CpuState {
+            rax: 0,
+            rbx: 0,
+            rcx: 0,
+            rdx: 0,
+            rsp: 0,
+            rbp: 0,
+            rsi: 0,
+            rdi: 0,
+            rip: 0,
+            flags: 0,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r11: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+        }
+    }
+
+    pub fn stack_pointer(&self) -> u64 {
+        self.rsp
+    }
+
+    pub fn instruction_pointer(&self) -> u64 {
+        self.rip
+    }
 }
 
+extern "C" {
+    /// Saves the running context into `old` and resumes `new`. Switching back to `old` later
+    /// returns from this call like nothing happened.
+    ///
+    /// Stack layout, on entry `[rsp]` is the return address into the caller:
+    ///
+    /// ```text
+    ///   old.rsp -> | caller's frame    |
+    ///              | return address    | <- rsp on entry, saved as old.rip
+    /// ```
+    ///
+    /// So `old.rip` is the return address and `old.rsp` is rsp as it will be once the call has
+    /// returned. rbp and every other general purpose register are saved as they were on entry,
+    /// and the flags through pushfq.
+    ///
+    /// To resume, rsp is loaded with `new.rsp`, `new.rip` is pushed just below it and the flags
+    /// are restored through the stack with popfq. The registers are loaded last (rsi, which
+    /// points at `new`, after everything else) and a ret pops `new.rip`, leaving rsp at exactly
+    /// `new.rsp`. The 16 bytes below `new.rsp` are used as scratch so they must be writable.
+    ///
+    /// # Safety
+    ///
+    /// `new` has to be a context saved by this function or made with `CpuState::new`, with its
+    /// stack and address space still valid.
+    pub fn switch_context(old: *mut CpuState, new: *const CpuState);
+}
+
+global_asm!(
+    "
+    .global switch_context
+switch_context:
+    mov [rdi + 0x00], rax
+    mov [rdi + 0x08], rbx
+    mov [rdi + 0x10], rcx
+    mov [rdi + 0x18], rdx
+    mov [rdi + 0x28], rbp
+    mov [rdi + 0x30], rsi
+    mov [rdi + 0x38], rdi
+    mov [rdi + 0x50], r8
+    mov [rdi + 0x58], r9
+    mov [rdi + 0x60], r10
+    mov [rdi + 0x68], r11
+    mov [rdi + 0x70], r12
+    mov [rdi + 0x78], r13
+    mov [rdi + 0x80], r14
+    mov [rdi + 0x88], r15
+
+    // rax is saved so it's free to use. Resume at the return address with it popped.
+    mov rax, [rsp]
+    mov [rdi + 0x40], rax
+    lea rax, [rsp + 8]
+    mov [rdi + 0x20], rax
+    pushfq
+    pop qword ptr [rdi + 0x48]
+
+    mov rsp, [rsi + 0x20]
+    push qword ptr [rsi + 0x40]
+    push qword ptr [rsi + 0x48]
+    popfq
+
+    mov rax, [rsi + 0x00]
+    mov rbx, [rsi + 0x08]
+    mov rcx, [rsi + 0x10]
+    mov rdx, [rsi + 0x18]
+    mov rbp, [rsi + 0x28]
+    mov rdi, [rsi + 0x38]
+    mov r8, [rsi + 0x50]
+    mov r9, [rsi + 0x58]
+    mov r10, [rsi + 0x60]
+    mov r11, [rsi + 0x68]
+    mov r12, [rsi + 0x70]
+    mov r13, [rsi + 0x78]
+    mov r14, [rsi + 0x80]
+    mov r15, [rsi + 0x88]
+    mov rsi, [rsi + 0x30]
+    ret
+    "
+);
+
 // macro_rules! byte_size {
 //     // ($e:expr(KB)) => {
 //     //     ($e) * 1024
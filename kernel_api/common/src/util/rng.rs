@@ -0,0 +1,138 @@
+use core::{
+    arch::{
+        asm,
+        x86_64::{__cpuid, __cpuid_count, _rdtsc},
+    },
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// Intel recommends giving up on RDRAND after 10 failed attempts in a row
+const RDRAND_RETRIES: usize = 10;
+// RDSEED can legitimately run dry for longer
+const RDSEED_RETRIES: usize = 100;
+
+// State of the fallback generator, 0 until it has been seeded
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+pub fn rdrand_supported() -> bool {
+    unsafe { __cpuid(1).ecx & (1 << 30) != 0 }
+}
+
+pub fn rdseed_supported() -> bool {
+    unsafe { __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & (1 << 18) != 0 }
+}
+
+fn rdrand() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    unsafe {
+        asm!(
+            "rdrand {}",
+            "setc {}",
+            out(reg) value,
+            out(reg_byte) ok,
+            options(nomem, nostack)
+        );
+    }
+    if ok != 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn rdseed() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    unsafe {
+        asm!(
+            "rdseed {}",
+            "setc {}",
+            out(reg) value,
+            out(reg_byte) ok,
+            options(nomem, nostack)
+        );
+    }
+    if ok != 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Random value from RDRAND. None if the cpu doesn't have it or it kept failing (CF=0).
+pub fn rand_u64() -> Option<u64> {
+    if !rdrand_supported() {
+        return None;
+    }
+    (0..RDRAND_RETRIES).find_map(|_| rdrand())
+}
+
+/// Value straight from the entropy source with RDSEED, for seeding other generators
+pub fn rand_seed_u64() -> Option<u64> {
+    if !rdseed_supported() {
+        return None;
+    }
+    (0..RDSEED_RETRIES).find_map(|_| {
+        let value = rdseed();
+        if value.is_none() {
+            unsafe { asm!("pause", options(nomem, nostack)) }
+        }
+        value
+    })
+}
+
+/// xorshift64. Deterministic for a given seed.
+#[derive(Debug, Clone)]
+pub struct Xorshift {
+    state: u64,
+}
+
+impl Xorshift {
+    pub const fn new(seed: u64) -> Xorshift {
+        // An all zero state only ever produces zero
+        Xorshift {
+            state: if seed == 0 { 0x9E3779B97F4A7C15 } else { seed },
+        }
+    }
+
+    pub fn next(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+}
+
+/// Next value of the global xorshift generator, seeded from the tsc on first use
+pub fn pseudo_u64() -> u64 {
+    let mut state = STATE.load(Ordering::SeqCst);
+    loop {
+        let current = if state == 0 {
+            unsafe { _rdtsc() }
+        } else {
+            state
+        };
+        let next = Xorshift::new(current).next();
+        match STATE.compare_exchange(state, next, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return next,
+            Err(s) => state = s,
+        }
+    }
+}
+
+/// RDRAND when available, otherwise the xorshift fallback. The fallback is predictable so this
+/// isn't fit for anything cryptographic on cpus without RDRAND.
+pub fn random() -> u64 {
+    rand_u64().unwrap_or_else(pseudo_u64)
+}
+
+/// Random value in `0..bound`
+pub fn random_below(bound: u64) -> u64 {
+    if bound == 0 {
+        return 0;
+    }
+    random() % bound
+}
@@ -160,7 +160,7 @@ pub fn generate_isrs(_: TokenStream) -> TokenStream {
                     asm!("mov rsp, rax", in("rax") SYSCALL_USP, options(nostack));
                 }}
 
-                APIC.lock().send_eoi();
+                lapic::eoi();
                 interrupt_end!(sti);
             }}
             "#,
@@ -175,7 +175,7 @@ pub fn set_isrs(tokens: TokenStream) -> TokenStream {
     let ident = tokens.to_string();
     let mut st = String::new();
     for i in 0x20u8..0xFF {
-        st.push_str(format!("{}[{1}].set_handler_fn(_isr_{1}).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);\n", ident, i).as_str());
+        st.push_str(format!("{}[{1}].set_handler_fn(_isr_{1}).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX).disable_interrupts(gate_type({1}) == GateType::Interrupt);\n", ident, i).as_str());
     }
     TokenStream::from_str(st.as_str()).unwrap()
 }
\ No newline at end of file
@@ -15,22 +15,25 @@ use core::{alloc::Layout, arch::asm};
 use alloc::rc::Rc;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use common::boot::{self, Phase};
+use common::cmdline::CommandLine;
 use common::efi::{MemoryDescriptor, GLOBAL_SYSTEM_TABLE};
 use common::mem::PageTableFrameAllocator;
 use common::util::{Align2MB, Align4096};
 use common::x86_64::structures::paging::page::PageRangeInclusive;
-use common::{include_bytes_align_as, kprint, util};
+use common::{include_bytes_align_as, kassert, kprint, serial, util};
 use macros::wchar;
 
 use common::{
     allocator,
     efi::{
-        self, get_system_table, guid, FileHandle, FileInfo, FileProtocol, FILE_HIDDEN,
-        FILE_MODE_READ, FILE_READ_ONLY, FILE_SYSTEM,
+        self, get_system_table, FileHandle, FileProtocol, FILE_HIDDEN, FILE_MODE_READ,
+        FILE_READ_ONLY, FILE_SYSTEM,
     },
     elf, gdt, kprintln, mem,
+    memory_regions::RUNTIME_SERVICES_OFFSET,
     process::Process,
-    KernelParameters,
+    size_mb, KernelParameters,
 };
 
 use common::x86_64::registers::control::{Cr3, Cr3Flags};
@@ -70,10 +73,35 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
     unsafe {
         asm!("mov {}, rsp", out(reg) STACK_END);
     }
+    // Machines without COM1 drop output instead of hanging on the first print
+    serial::probe(0x3F8);
     unsafe {
         // Set the static system table reference
         efi::register_global_system_table(system_table).unwrap();
     }
+    boot::phase(Phase::Loader);
+    efi::print_config_tables();
+
+    let command_line = efi::command_line(image_handle).unwrap_or("");
+    kprintln!("Command line: {}", command_line);
+
+    let boot_time = efi::read_boot_time();
+    if let Some(time) = boot_time {
+        kprintln!("Boot time: {:?}", time);
+    }
+
+    // The framebuffer outlives boot services but finding it doesn't
+    let framebuffer = efi::get_gop();
+    match &framebuffer {
+        Some(fb) => kprintln!(
+            "Framebuffer: {}x{} {:?} at {:p}",
+            fb.width(),
+            fb.height(),
+            fb.format(),
+            fb.base()
+        ),
+        None => kprintln!("No GOP framebuffer"),
+    }
 
     //let base = efi::get_image_base(image_handle);
     //kprintln!("Entry: {:x}", base);
@@ -81,8 +109,8 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
         let volume = unsafe { &*efi::io_volume(image_handle) };
         let mut fileio: *const FileProtocol = core::ptr::null();
         let res = (volume.open_volume)(volume as _, &mut fileio);
-        if res != 0 {
-            kprintln!("An error occured! {:x} OpenVolume(SFSP)", res);
+        if res.is_error() {
+            kprintln!("An error occured! {:?} OpenVolume(SFSP)", res);
         }
         let fileio = unsafe { &*fileio };
         let mut newfileio: *const FileProtocol = core::ptr::null();
@@ -94,19 +122,13 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
             FILE_MODE_READ,
             FILE_READ_ONLY,
         );
-        if res != 0 {
-            kprintln!("An error occured! {:x} OPEN(SFSP)", res);
+        if res.is_error() {
+            kprintln!("An error occured! {:?} OPEN(SFSP)", res);
         }
 
-        let mut file_info: FileInfo = unsafe { core::mem::zeroed() };
-        let buffer: *mut FileInfo = &mut file_info;
-        let mut size = core::mem::size_of::<FileInfo>();
-
-        let res = (fileio.get_info)(newfileio, &guid::FILE_INFO, &mut size, buffer);
-
-        if res != 0 {
-            kprintln!("An error occured! {:x} GETINFO(SFSP)", res);
-        }
+        let mut info = Vec::new();
+        let file_info = efi::get_file_info(unsafe { &*newfileio }, &mut info)
+            .expect("Unable to get file info!");
 
         let mut file_data: *mut u8 = core::ptr::null_mut();
         let efi_table = get_system_table();
@@ -114,16 +136,16 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
             .boot_services()
             .allocate_pool(file_info.file_size, &mut file_data);
 
-        if res != 0 {
-            kprintln!("An error occured! {:x} ALLOCATEPOOL(SFSP)", res);
+        if res.is_error() {
+            kprintln!("An error occured! {:?} ALLOCATEPOOL(SFSP)", res);
         }
 
         let copy_file_data =
             unsafe { core::slice::from_raw_parts_mut(file_data, file_info.file_size) };
 
         let res = efi_table.boot_services().set_watchdog_timer(0, 0);
-        if res != 0 {
-            kprintln!("An error occured! {:x} Watchdog timer (SFSP)", res);
+        if res.is_error() {
+            kprintln!("An error occured! {:?} Watchdog timer (SFSP)", res);
         }
 
         kprintln!("{:?}", file_info);
@@ -134,8 +156,8 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
             copy_file_data,
         );
 
-        if res != 0 {
-            kprintln!("An error occured! {:x} READ (SFSP)", res);
+        if res.is_error() {
+            kprintln!("An error occured! {:?} READ (SFSP)", res);
         }
     };
 
@@ -154,29 +176,46 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
         asm!("mov {}, rsp", out(reg) copy_top);
     }
     // Iterate memorymap and exit boot services
-    let (memory_map, version) = efi::get_memory_map(image_handle);
+    let (descriptors, version) =
+        efi::get_memory_map(image_handle).expect("Unable to exit boot services!");
+    let count = descriptors.len();
+    let memory_map = unsafe { efi::pack_memory_map(descriptors) };
+    boot::phase(Phase::BootServicesExited);
 
     // Setup global descriptor table :P
+    boot::phase(Phase::Gdt);
     gdt::init();
 
+    boot::phase(Phase::Paging);
     let mut frame_allocator = mem::PageTableFrameAllocator::new(memory_map);
     let mut mapper = unsafe { mem::init(frame_allocator, 0) };
 
     let mut npt = mapper.level_4_table().clone();
     let mut mapper = unsafe { OffsetPageTable::new(&mut npt, VirtAddr::new(0)) };
     let table: *mut PageTable = mapper.level_4_table();
+    let table = mapper
+        .translate_addr(VirtAddr::from_ptr(table))
+        .expect("Unable to translate page table!");
 
-    unsafe {
-        Cr3::write(
-            PhysFrame::from_start_address(PhysAddr::new(table as u64))
-                .expect("Unable to switch page table!"),
-            Cr3Flags::empty(),
-        );
-        mem::KERNEL_MAP = table as u64;
-    }
+    mem::switch_address_space(
+        PhysFrame::from_start_address(table).expect("Unable to switch page table!"),
+    );
+    kassert!(
+        Cr3::read().0 == mem::kernel_map(),
+        "Kernel map doesn't match CR3!"
+    );
 
-    allocator::init_heap_new(&mut mapper, mem::allocator().get_mut(), 0, false)
+    boot::phase(Phase::LoaderHeap);
+    let heap_size = allocator::heap_size_for(efi::get_usable_mem_size(memory_map));
+    allocator::init_heap_sized(&mut mapper, mem::allocator().get_mut(), heap_size)
         .expect("Unable to create heap!");
+    kprintln!("Heap: {} MiB", allocator::heap_size() / size_mb!(1));
+
+    // The bitmap lives on the heap, so it can only take over from here
+    if CommandLine::new(command_line).flag("framebitmap") {
+        mem::allocator().get_mut().use_bitmap();
+        kprintln!("Using the bitmap frame allocator");
+    }
 
     efi::print_memory_map(memory_map);
 
@@ -213,19 +252,17 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
     //     asm!("mov {}, rsp", out(reg) copy_bottom);
     // }
 
-    let mem = efi::get_mem_size(memory_map);
-
     let mut process = Process::kernel_from_elf(
+        "kernel",
         &image.expect("Unable to find kernel image!"),
         unsafe { STACK_START },
         unsafe { STACK_END },
-        mem,
         &mut mapper,
         mem::allocator().get_mut(),
     );
 
     let ptr = memory_map.as_ptr();
-    kprintln!("Memmap ptr: {:p}", ptr);
+    kprintln!("Memmap ptr: {:p}, {} descriptors", ptr, count);
 
     // let kernel_parameters = KernelParameters {
     //     memory_map: unsafe {
@@ -277,45 +314,14 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
         }
     };
 
-    let iters = |mapper: &mut OffsetPageTable, fa: &mut PageTableFrameAllocator| {
-        memory_map
-            .iter()
-            .map(|desc| {
-                if desc.is_runtime() {
-                    for i in 0..desc.size {
-                        unsafe {
-                            mapper.identity_map(
-                                PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(
-                                    (desc.physical_address + i * 4096) as u64,
-                                )),
-                                PageTableFlags::PRESENT,
-                                fa,
-                            );
-                            // .expect("Uanble to map in kernel process for memory descriptors!")
-                            // .flush()
-                        }
-                    }
-
-                    MemoryDescriptor {
-                        virtual_address: desc.physical_address,
-                        ..*desc
-                    }
-                } else {
-                    desc.clone()
-                }
-            })
-            .collect::<Vec<MemoryDescriptor>>()
-    };
-    let value = (iters)(&mut process.get_pt(), mem::allocator().get_mut());
-
-    // mem::map_arr_table(&mut process.get_pt(), <Vec<MemoryDescriptor> as AsRef<[MemoryDescriptor]>>::as_ref(&value));
-
-    let res = get_system_table()
-        .runtime_services()
-        .set_virtual_address_map(value.as_ref(), version);
-    if res != 0 {
-        kprintln!("An error occured! {:x} Set ADdress map(SFSP)", res);
-    }
+    let value = efi::setup_runtime_virtual_map(
+        memory_map,
+        version,
+        RUNTIME_SERVICES_OFFSET,
+        &mut process.get_pt(),
+        mem::allocator().get_mut(),
+    )
+    .expect("Unable to set runtime virtual address map!");
 
     // heap_top: heap_top(),
     // let heap_range = allocator::heap_range(0);
@@ -362,23 +368,23 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
 
     // kprintln!("Boot range: {:x} - {:x}", first, last);
 
-    let heap_range = allocator::heap_range(0);
+    let heap_range = allocator::heap_range(allocator::heap_slide());
     let mut process_pt = process.get_pt();
     for page in heap_range {
-        // mapper.translate
-        match mapper.translate_page(page) {
-            Ok(addr) => unsafe {
+        // The heap may be on 2MiB pages here, which translate_page doesn't look through
+        match mapper.translate_addr(page.start_address()) {
+            Some(addr) => unsafe {
                 process_pt
                     .map_to(
                         page,
-                        addr,
+                        PhysFrame::containing_address(addr),
                         PageTableFlags::WRITABLE | PageTableFlags::PRESENT,
                         mem::allocator().get_mut(),
                     )
                     .expect("unable to map heap!")
                     .flush();
             },
-            Err(_) => (),
+            None => (),
         }
     }
 
@@ -452,12 +458,17 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
         // boot_image: (first, last),
         boot_image: (boot_image.virtual_address(), boot_image.len() as _),
         frame_allocator: mem::allocator().lock().clone(),
-        system_table: GLOBAL_SYSTEM_TABLE.load(core::sync::atomic::Ordering::SeqCst),
+        system_table: (GLOBAL_SYSTEM_TABLE.load(core::sync::atomic::Ordering::SeqCst) as u64
+            + RUNTIME_SERVICES_OFFSET) as *mut _,
         heap: allocator::heap(),
+        command_line,
+        framebuffer,
+        boot_time,
         // page_table: npt.clone()
     };
     let val = frame.start_address().as_u64();
     kprintln!("Parameters {:p}", &kernel_parameters);
+    boot::phase(Phase::KernelJump);
 
     unsafe {
         asm!("", in("r13") process.stack_base, in("r14") process.entry, in("r15") &kernel_parameters);
@@ -477,6 +488,7 @@ fn run(image_handle: efi::Handle, system_table: *mut efi::SystemTable) -> ! {
 
 #[panic_handler]
 fn panic_handler(_info: &PanicInfo) -> ! {
-    kprintln!("LOADER PANIC! {}\n", _info);
+    common::serial_println!("LOADER PANIC! {}\n", _info);
+    common::serial_println!("Last boot phase: {:?}", boot::current_phase());
     loop {}
 }